@@ -0,0 +1,371 @@
+//! An async API, for use with Embassy and similar executors. Enabled with the `async` feature.
+//!
+//! This is split into two parts: A `Control`, used by the application to send requests, and a `Runner`,
+//! which owns the transport, and must be run as a background task. The runner writes frames sent by `Control`,
+//! reads frames from the ESP, and demultiplexes them into RPC responses, RPC events, and HCI (BLE) frames. These
+//! are each queued in `State`, and can be awaited from any task. Errors the runner encounters, e.g. reading from the
+//! transport, or a checksum mismatch, are queued too; await them with `State::error`.
+//!
+//! ```ignore
+//! static STATE: StaticCell<State<NoopRawMutex>> = StaticCell::new();
+//!
+//! let state = STATE.init(State::new());
+//! let (mut control, runner) = asynch::new(state, uart);
+//! spawner.spawn(esp_task(runner)).unwrap();
+//!
+//! control.init(0, &InitConfig::default()).await?;
+//! control.start(1).await?;
+//!
+//! let resp = state.response().await;
+//! if let Ok(MsgParsed::Wifi(msg)) = resp.parse() {
+//!     // ...
+//! }
+//! ```
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::RawMutex, channel::Channel};
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+use crate::{
+    EspError, FrameDecoder, MsgParsed,
+    ble::{HciPkt, make_hci_cmd},
+    cfg_heartbeat,
+    header::build_frame_ble,
+    parse_frame,
+    proto_data::{RpcReqConfigHeartbeat, RpcReqWifiScanStart, WifiConfig},
+    rpc::RpcType,
+    transport::{ESP_TRANSPORT_UART_MAX_BUF_SIZE, Transport},
+    wifi::{
+        self, BandBandwidths, BandProtocols, InitConfig, InterfaceType, MacAddr,
+        PromiscuousCtrlFilter, PromiscuousFilter, Protocols, WifiBand, WifiBandMode, WifiBandwidth,
+        WifiCountry, WifiMode, WifiPsType, WifiSecondChan,
+    },
+};
+
+/// The largest frame we send or receive.
+pub const FRAME_SIZE: usize = ESP_TRANSPORT_UART_MAX_BUF_SIZE as usize;
+
+/// The number of received frames of each type we queue, before dropping new ones.
+const RESPONSE_QUEUE_LEN: usize = 2;
+const EVENT_QUEUE_LEN: usize = 4;
+const HCI_QUEUE_LEN: usize = 2;
+const ERROR_QUEUE_LEN: usize = 4;
+
+/// Our async counterpart to `Transport`. This is implemented for all types that implement `embedded-io-async`'s
+/// `Read` and `Write`, e.g. an async UART.
+///
+/// The `Runner` cancels pending reads when it has a frame to write, so `read` must be cancel-safe; this is the
+/// case for buffered and ring-buffered UARTs.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport {
+    /// Write a whole frame.
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EspError>;
+
+    /// Wait until bytes are available, then read as many as fit in `buf`. Returns the number read.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError>;
+}
+
+impl<T: Read + Write> AsyncTransport for T {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.write_all(buf).await.map_err(|_| EspError::Comms)?;
+        self.flush().await.map_err(|_| EspError::Comms)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        Read::read(self, buf).await.map_err(|_| EspError::Comms)
+    }
+}
+
+/// A frame received from the ESP, starting with the payload header. Its checksum has already been verified,
+/// if checksum verification is enabled.
+pub struct Frame {
+    data: Vec<u8, FRAME_SIZE>,
+}
+
+impl Frame {
+    pub fn parse(&self) -> Result<MsgParsed<'_>, EspError> {
+        parse_frame(&self.data, false)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Collects the frames written by this library's blocking functions, so `Control` can pass them to the
+/// `Runner`. Some functions, e.g. `wifi::set_promiscuous`, write more than one frame; these are stored
+/// back to back.
+pub struct TxFrames {
+    data: Vec<u8, FRAME_SIZE>,
+}
+
+impl Transport for TxFrames {
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.data
+            .extend_from_slice(buf)
+            .map_err(|_| EspError::Capacity)
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EspError> {
+        Ok(0)
+    }
+}
+
+/// Channels shared between `Control`, `Runner`, and the application. Store this in a `static`, or
+/// otherwise somewhere that outlives both.
+pub struct State<M: RawMutex> {
+    tx: Channel<M, TxFrames, 1>,
+    responses: Channel<M, Frame, RESPONSE_QUEUE_LEN>,
+    events: Channel<M, Frame, EVENT_QUEUE_LEN>,
+    hci: Channel<M, Frame, HCI_QUEUE_LEN>,
+    errors: Channel<M, EspError, ERROR_QUEUE_LEN>,
+}
+
+impl<M: RawMutex> Default for State<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex> State<M> {
+    pub const fn new() -> Self {
+        Self {
+            tx: Channel::new(),
+            responses: Channel::new(),
+            events: Channel::new(),
+            hci: Channel::new(),
+            errors: Channel::new(),
+        }
+    }
+
+    /// Wait for the next RPC response.
+    pub async fn response(&self) -> Frame {
+        self.responses.receive().await
+    }
+
+    /// Wait for the next RPC event, e.g. `EventStaConnected`, or `EventHeartbeat`.
+    pub async fn event(&self) -> Frame {
+        self.events.receive().await
+    }
+
+    /// Wait for the next HCI (BLE) frame. Parse its payload with `ble::parse_hci_events`.
+    pub async fn hci(&self) -> Frame {
+        self.hci.receive().await
+    }
+
+    /// Wait for the next error encountered by the `Runner`: Reading from, or writing to the transport, or
+    /// decoding, or parsing a received frame. The runner continues after these.
+    pub async fn error(&self) -> EspError {
+        self.errors.receive().await
+    }
+}
+
+/// Create the `Control` and `Runner` for a transport.
+pub fn new<M: RawMutex, T: AsyncTransport>(
+    state: &State<M>,
+    transport: T,
+) -> (Control<'_, M>, Runner<'_, M, T>) {
+    let control = Control {
+        state,
+        buf: [0; FRAME_SIZE],
+    };
+
+    let runner = Runner {
+        state,
+        transport,
+        decoder: FrameDecoder::new(),
+        rx_dropped: 0,
+        errors_dropped: 0,
+    };
+
+    (control, runner)
+}
+
+/// Owns the transport. Call `run` from a dedicated task.
+pub struct Runner<'a, M: RawMutex, T> {
+    state: &'a State<M>,
+    transport: T,
+    decoder: FrameDecoder<FRAME_SIZE>,
+    /// The number of received frames dropped due to a full queue.
+    pub rx_dropped: u32,
+    /// The number of errors dropped due to a full queue; see `State::error`.
+    pub errors_dropped: u32,
+}
+
+impl<M: RawMutex, T: AsyncTransport> Runner<'_, M, T> {
+    pub async fn run(mut self) -> ! {
+        loop {
+            let rx = self.decoder.read_from_async(&mut self.transport);
+
+            match select(rx, self.state.tx.receive()).await {
+                Either::First(Ok(_)) => self.dispatch(),
+                Either::First(Err(e)) => self.report(e),
+                Either::Second(frames) => {
+                    if let Err(e) = self.transport.write(&frames.data).await {
+                        self.report(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue each complete frame, based on its type.
+    fn dispatch(&mut self) {
+        while let Some(frame) = self.decoder.next_frame_bytes() {
+            let frame = match frame {
+                Ok(f) => f,
+                Err(e) => {
+                    self.report(e);
+                    continue;
+                }
+            };
+
+            let queue = match parse_frame(frame, false) {
+                Ok(MsgParsed::Wifi(msg)) => match msg.rpc.msg_type {
+                    RpcType::Resp => self.state.responses.dyn_sender(),
+                    RpcType::Event => self.state.events.dyn_sender(),
+                    _ => continue,
+                },
+                Ok(MsgParsed::Hci(_)) => self.state.hci.dyn_sender(),
+                Err(e) => {
+                    self.report(e);
+                    continue;
+                }
+            };
+
+            let mut data = Vec::new();
+            // `FrameDecoder` only returns frames that fit in its buffer, which is the same size.
+            data.extend_from_slice(frame).ok();
+
+            if queue.try_send(Frame { data }).is_err() {
+                self.rx_dropped += 1;
+            }
+        }
+    }
+
+    /// Queue an error for `State::error`.
+    fn report(&mut self, e: EspError) {
+        if self.state.errors.try_send(e).is_err() {
+            self.errors_dropped += 1;
+        }
+    }
+}
+
+/// Generates async versions of functions in the `wifi` module. These have the same signature, without
+/// the buffer and transport.
+macro_rules! async_wifi_fns {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            #[doc = concat!("Async version of `wifi::", stringify!($name), "`.")]
+            pub async fn $name(&mut self, uid: u32, $($arg: $ty),*) -> Result<(), EspError> {
+                self.send(|buf, tx| wifi::$name(buf, tx, uid, $($arg),*)).await
+            }
+        )*
+    };
+}
+
+/// Sends requests to the ESP, via the `Runner`. Responses are received with `State::response`.
+pub struct Control<'a, M: RawMutex> {
+    state: &'a State<M>,
+    /// Used to build frames, in the same way as the `buf` passed to blocking functions.
+    buf: [u8; FRAME_SIZE],
+}
+
+impl<'a, M: RawMutex> Control<'a, M> {
+    pub fn state(&self) -> &'a State<M> {
+        self.state
+    }
+
+    /// Build frames using one of this library's blocking functions, then pass them to the `Runner`. For example:
+    /// `control.send(|buf, tx| wifi::set_mode(buf, tx, 0, WifiMode::Station)).await?`.
+    pub async fn send<F>(&mut self, build: F) -> Result<(), EspError>
+    where
+        F: FnOnce(&mut [u8], &mut TxFrames) -> Result<(), EspError>,
+    {
+        let mut frames = TxFrames { data: Vec::new() };
+        build(&mut self.buf, &mut frames)?;
+
+        self.state.tx.send(frames).await;
+        Ok(())
+    }
+
+    /// Wait for the next RPC response.
+    pub async fn response(&self) -> Frame {
+        self.state.response().await
+    }
+
+    /// Async version of `cfg_heartbeat`.
+    pub async fn cfg_heartbeat(
+        &mut self,
+        uid: u32,
+        cfg: &RpcReqConfigHeartbeat,
+    ) -> Result<(), EspError> {
+        self.send(|buf, tx| cfg_heartbeat(buf, tx, uid, cfg)).await
+    }
+
+    /// Send an HCI command. Construct the opcode with `ble::make_hci_opcode`.
+    pub async fn hci_cmd(&mut self, opcode: u16, params: &[u8]) -> Result<(), EspError> {
+        let (payload, len) = make_hci_cmd(opcode, params);
+        self.send_hci(HciPkt::Cmd, &payload[..len]).await
+    }
+
+    /// Send an HCI packet of any type.
+    pub async fn send_hci(&mut self, pkt_type: HciPkt, payload: &[u8]) -> Result<(), EspError> {
+        self.send(|buf, tx| {
+            let frame_len = build_frame_ble(buf, pkt_type, payload);
+            tx.write(&buf[..frame_len])
+        })
+        .await
+    }
+
+    async_wifi_fns! {
+        start();
+        stop();
+        scan_get_ap_num();
+        scan_get_ap_record();
+        clear_ap_list();
+        scan_get_ap_records(max_number: u8);
+        ap_get_sta_list();
+        get_mode();
+        set_mode(mode: WifiMode);
+        init(cfg: &InitConfig);
+        deinit();
+        set_promiscuous(
+            enabled: bool,
+            filter: &PromiscuousFilter,
+            ctrl_filter: Option<&PromiscuousCtrlFilter>
+        );
+        get_promiscuous();
+        get_promiscuous_filter();
+        get_promiscuous_ctrl_filter();
+        scan_start(scan_start: &RpcReqWifiScanStart);
+        scan_stop();
+        set_protocol(ifx: InterfaceType, protocols: &Protocols);
+        get_protocol();
+        set_ps(ps_type: WifiPsType);
+        get_ps();
+        set_channel(primary: u8, second: WifiSecondChan, country: Option<&WifiCountry>);
+        get_channel();
+        set_bandwidth(ifx: InterfaceType, bw: WifiBandwidth);
+        get_bandwidth(ifx: InterfaceType);
+        set_band(band: WifiBand);
+        get_band();
+        set_band_mode(mode: WifiBandMode);
+        get_band_mode();
+        set_protocols(ifx: InterfaceType, protocols: &BandProtocols);
+        get_protocols(ifx: InterfaceType);
+        set_bandwidths(ifx: InterfaceType, bandwidths: &BandBandwidths);
+        get_bandwidths(ifx: InterfaceType);
+        set_config(cfg: &WifiConfig);
+        connect();
+        disconnect();
+        get_mac(ifx: InterfaceType);
+        set_mac(ifx: InterfaceType, mac: &MacAddr);
+        set_country(country: &WifiCountry);
+        get_country();
+        set_country_code(cc: &[u8], ieee80211d_enabled: bool);
+        set_max_tx_power(dbm: f32, country: Option<&WifiCountry>);
+        get_max_tx_power();
+    }
+}
@@ -0,0 +1,361 @@
+//! An incremental frame decoder for byte-oriented transports, e.g. UART. Bytes are pushed in as
+//! they arrive, one at a time or in chunks of any size; complete frames are yielded once all of their
+//! bytes are present. Partial and concatenated frames are both handled.
+//!
+//! The decoder aligns itself using the fields of the payload header: a candidate header must have
+//! a valid interface type, an offset of 12, and a length that fits in the buffer. Once the full frame
+//! is buffered, its checksum is verified, unless disabled with `set_checksum_verification`. If either
+//! check fails, the candidate's first byte is dropped, and the search continues from the next one.
+
+#[cfg(feature = "async")]
+use crate::asynch::AsyncTransport;
+use crate::{
+    EspError, MsgParsed,
+    header::{InterfaceType, PL_HEADER_SIZE, PayloadHeader},
+    parse_frame,
+    transport::{
+        ESP_TRANSPORT_UART_MAX_BUF_SIZE, Transport, checksum_verification_enabled, verify_checksum,
+    },
+};
+
+/// Buffers incoming bytes, and yields complete, checksum-verified frames. `N` is the buffer size
+/// in bytes; it must be at least the size of the largest frame expected. 1600 is a good default.
+pub struct FrameDecoder<const N: usize> {
+    buf: [u8; N],
+    /// The number of bytes currently held in `buf`.
+    len: usize,
+    /// The size of the frame most recently returned by `next_frame`. It's removed from the buffer
+    /// at the start of the next call to `push` or `next_frame`, so the returned message can
+    /// borrow from the buffer.
+    consumed: usize,
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Add a single byte, e.g. from a UART RX interrupt. Returns `EspError::Capacity` if the buffer
+    /// is full; call `next_frame` to free up space.
+    pub fn push_byte(&mut self, byte: u8) -> Result<(), EspError> {
+        self.discard_consumed();
+
+        if self.len >= N {
+            return Err(EspError::Capacity);
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Add a chunk of bytes, e.g. from a DMA transfer. Returns the number of bytes accepted; this
+    /// is less than `bytes.len()` if the buffer fills up.
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        self.discard_consumed();
+
+        let n = bytes.len().min(N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+
+        n
+    }
+
+    /// Read whatever bytes are available from the transport into the buffer. Returns the number
+    /// of bytes read. Follow this with calls to `next_frame`.
+    pub fn read_from<T: Transport>(&mut self, transport: &mut T) -> Result<usize, EspError> {
+        self.discard_consumed();
+
+        if self.len >= N || !transport.data_ready() {
+            return Ok(0);
+        }
+
+        let n = transport.read(&mut self.buf[self.len..])?;
+        self.len += n.min(N - self.len);
+
+        Ok(n)
+    }
+
+    /// Async version of `read_from`. Waits until the transport returns some bytes.
+    #[cfg(feature = "async")]
+    pub async fn read_from_async<T: AsyncTransport>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<usize, EspError> {
+        self.discard_consumed();
+
+        if self.len >= N {
+            return Ok(0);
+        }
+
+        let n = transport.read(&mut self.buf[self.len..]).await?;
+        self.len += n.min(N - self.len);
+
+        Ok(n)
+    }
+
+    /// Discard all buffered bytes.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+    }
+
+    /// The number of bytes buffered, and not yet returned as part of a frame.
+    pub fn buffered(&self) -> usize {
+        self.len - self.consumed
+    }
+
+    /// Returns the next complete frame, if one is available. Returns `None` if more bytes are
+    /// required. Returns `Some(Err(EspError::CrcMismatch))` when a candidate frame fails its checksum; the
+    /// decoder resynchronizes on the following call. Call this repeatedly until it returns `None`,
+    /// as a single push may complete several frames.
+    pub fn next_frame(&mut self) -> Option<Result<MsgParsed<'_>, EspError>> {
+        Some(match self.next_frame_bytes()? {
+            // The checksum is already verified, if required.
+            Ok(frame) => parse_frame(frame, false),
+            Err(e) => Err(e),
+        })
+    }
+
+    /// Like `next_frame`, but returns the frame's bytes, starting with the payload header, without
+    /// parsing them. Useful for passing frames to another task.
+    pub fn next_frame_bytes(&mut self) -> Option<Result<&[u8], EspError>> {
+        self.discard_consumed();
+
+        loop {
+            if self.len < PL_HEADER_SIZE {
+                return None;
+            }
+
+            let Some(frame_len) = frame_len(&self.buf[..PL_HEADER_SIZE], N) else {
+                self.resync();
+                continue;
+            };
+
+            if self.len < frame_len {
+                return None;
+            }
+
+            if checksum_verification_enabled() && !verify_checksum(&self.buf[..frame_len]) {
+                self.drop_front(1);
+                return Some(Err(EspError::CrcMismatch));
+            }
+
+            self.consumed = frame_len;
+            return Some(Ok(&self.buf[..frame_len]));
+        }
+    }
+
+    /// Drop bytes from the front of the buffer until a plausible header start is found, or until
+    /// fewer than a header's worth of bytes remain.
+    fn resync(&mut self) {
+        let mut start = 1;
+
+        while start + PL_HEADER_SIZE <= self.len {
+            if frame_len(&self.buf[start..start + PL_HEADER_SIZE], N).is_some() {
+                break;
+            }
+            start += 1;
+        }
+
+        self.drop_front(start.min(self.len));
+    }
+
+    fn discard_consumed(&mut self) {
+        if self.consumed > 0 {
+            self.drop_front(self.consumed);
+            self.consumed = 0;
+        }
+    }
+
+    fn drop_front(&mut self, count: usize) {
+        self.buf.copy_within(count..self.len, 0);
+        self.len -= count;
+    }
+}
+
+/// If `header` looks like the start of a valid frame, return the total frame length, including
+/// the header. `capacity` is the largest frame we can hold.
+pub(crate) fn frame_len(header: &[u8], capacity: usize) -> Option<usize> {
+    let header = PayloadHeader::from_bytes(header).ok()?;
+
+    if matches!(header.if_type, InterfaceType::Invalid | InterfaceType::Max) {
+        return None;
+    }
+
+    if header.offset as usize != PL_HEADER_SIZE || header.len == 0 {
+        return None;
+    }
+
+    let frame_len = PL_HEADER_SIZE + header.len as usize;
+    if frame_len > capacity || frame_len > ESP_TRANSPORT_UART_MAX_BUF_SIZE as usize {
+        return None;
+    }
+
+    Some(frame_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ble::HciPkt,
+        header::{HEADER_SIZE, build_frame_ble},
+        proto_data::RpcId,
+        rpc::{Rpc, setup_rpc},
+    };
+
+    const HCI_PAYLOAD: [u8; 4] = [0x0E, 0x02, 0x01, 0x00];
+
+    fn hci_frame(buf: &mut [u8]) -> usize {
+        build_frame_ble(buf, HciPkt::Evt, &HCI_PAYLOAD)
+    }
+
+    fn wifi_frame(buf: &mut [u8]) -> usize {
+        let rpc = Rpc::new_req(RpcId::ReqGetWifiMode, 7);
+        setup_rpc(buf, &rpc, &[])
+    }
+
+    /// Returns the payload of the next frame, which must be HCI.
+    fn next_hci<const N: usize>(decoder: &mut FrameDecoder<N>) -> Option<&[u8]> {
+        match decoder.next_frame()? {
+            Ok(MsgParsed::Hci(msg)) => Some(msg.data),
+            _ => panic!("expected an HCI frame"),
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut frame = [0; 32];
+        let len = hci_frame(&mut frame);
+
+        let mut decoder = FrameDecoder::<64>::new();
+        for (i, b) in frame[..len].iter().enumerate() {
+            assert!(decoder.next_frame().is_none(), "frame yielded early at {i}");
+            assert!(decoder.push_byte(*b).is_ok());
+        }
+
+        assert_eq!(next_hci(&mut decoder), Some(&HCI_PAYLOAD[..]));
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn concatenated_and_split() {
+        let mut bytes = [0; 128];
+        let a = hci_frame(&mut bytes);
+        let b = wifi_frame(&mut bytes[a..]);
+        let c = hci_frame(&mut bytes[a + b..]);
+        let total = a + b + c;
+
+        let mut decoder = FrameDecoder::<256>::new();
+        // The first two frames, and part of the third.
+        let split = a + b + 3;
+        assert_eq!(decoder.push(&bytes[..split]), split);
+
+        assert_eq!(next_hci(&mut decoder), Some(&HCI_PAYLOAD[..]));
+
+        match decoder.next_frame() {
+            Some(Ok(MsgParsed::Wifi(msg))) => {
+                assert!(msg.rpc.msg_id == RpcId::ReqGetWifiMode);
+                assert_eq!(msg.rpc.uid, 7);
+                assert!(msg.data.is_empty());
+            }
+            _ => panic!("expected a Wi-Fi frame"),
+        }
+
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.buffered(), 3);
+
+        decoder.push(&bytes[split..total]);
+        assert_eq!(next_hci(&mut decoder), Some(&HCI_PAYLOAD[..]));
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn next_frame_bytes_round_trip() {
+        let mut frame = [0; 64];
+        let len = wifi_frame(&mut frame);
+        assert!(len > HEADER_SIZE);
+
+        let mut decoder = FrameDecoder::<64>::new();
+        decoder.push(&frame[..len]);
+
+        match decoder.next_frame_bytes() {
+            Some(Ok(bytes)) => assert_eq!(bytes, &frame[..len]),
+            _ => panic!("expected a frame"),
+        }
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut bytes = [0xFF; 64];
+        let len = hci_frame(&mut bytes[5..]);
+
+        let mut decoder = FrameDecoder::<64>::new();
+        decoder.push(&bytes[..5 + len]);
+
+        assert_eq!(next_hci(&mut decoder), Some(&HCI_PAYLOAD[..]));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut bytes = [0; 64];
+        let a = hci_frame(&mut bytes);
+        let b = hci_frame(&mut bytes[a..]);
+        // Corrupt the first frame's payload.
+        bytes[PL_HEADER_SIZE] ^= 0x01;
+
+        let mut decoder = FrameDecoder::<64>::new();
+        decoder.push(&bytes[..a + b]);
+
+        assert!(matches!(
+            decoder.next_frame(),
+            Some(Err(EspError::CrcMismatch))
+        ));
+        // The decoder drops the bad frame's bytes, and recovers the next one.
+        assert_eq!(next_hci(&mut decoder), Some(&HCI_PAYLOAD[..]));
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut decoder = FrameDecoder::<16>::new();
+        assert_eq!(decoder.push(&[0; 20]), 16);
+        assert!(matches!(decoder.push_byte(0), Err(EspError::Capacity)));
+
+        decoder.clear();
+        assert_eq!(decoder.buffered(), 0);
+        assert!(decoder.push_byte(0).is_ok());
+    }
+
+    #[test]
+    fn frame_len_rejects_invalid_headers() {
+        let mut frame = [0; 32];
+        let len = hci_frame(&mut frame);
+        assert_eq!(frame_len(&frame[..PL_HEADER_SIZE], 64), Some(len));
+
+        // Larger than the buffer.
+        assert_eq!(frame_len(&frame[..PL_HEADER_SIZE], len - 1), None);
+        // An all-zero header, as sent when the ESP has nothing for us.
+        assert_eq!(frame_len(&[0; PL_HEADER_SIZE], 64), None);
+
+        // An offset other than 12.
+        let mut header = [0; PL_HEADER_SIZE];
+        header.copy_from_slice(&frame[..PL_HEADER_SIZE]);
+        header[4] = 13;
+        assert_eq!(frame_len(&header, 64), None);
+    }
+}
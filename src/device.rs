@@ -0,0 +1,819 @@
+//! A blocking, call-style API. Unlike the functions in the `wifi` module, which only send a request,
+//! these send the request, then read from the transport until the matching response arrives, and
+//! decode it. A non-zero `resp` code in the response is returned as `EspError::Esp`.
+//!
+//! ```ignore
+//! let mut dev = EspHosted::new(transport, now_ms);
+//!
+//! device::init(&mut dev, &InitConfig::default())?;
+//! device::set_mode(&mut dev, WifiMode::Station)?;
+//! device::start(&mut dev)?;
+//!
+//! let mode = device::get_mode(&mut dev)?;
+//! ```
+//!
+//! This requires a `Transport` that implements `read`, and `data_ready` if applicable.
+//!
+//! Events that arrive while waiting for a response, or during `poll`, are queued. Drain them with `next_event`,
+//! and pass them to e.g. a `ScanManager`, or `StaSupervisor`:
+//!
+//! ```ignore
+//! dev.poll()?;
+//! while let Some(event) = dev.next_event() {
+//!     scanner.on_event(&event);
+//! }
+//! ```
+
+use heapless::{Deque, Vec};
+
+use crate::{
+    EspError, FrameDecoder, MsgParsed, PendingRequests,
+    event::Event,
+    pending::DEFAULT_TIMEOUT_MS,
+    proto_data::{RpcId, RpcReqConfigHeartbeat, RpcReqWifiScanStart, WifiConfig, WifiStaList},
+    regulatory,
+    rpc::{FieldVal, Fields, check_resp},
+    transport::{ESP_TRANSPORT_UART_MAX_BUF_SIZE, Transport},
+    wifi::{
+        self, ApRecords, BandBandwidths, BandProtocols, InitConfig, InterfaceType, MAX_AP_RECORDS,
+        MacAddr, Protocols, WifiApRecord, WifiBand, WifiBandMode, WifiBandwidth, WifiCountry,
+        WifiMode, WifiPsType, WifiSecondChan,
+    },
+};
+
+const BUF_SIZE: usize = ESP_TRANSPORT_UART_MAX_BUF_SIZE as usize;
+
+/// The number of events held until drained with `next_event`.
+pub const EVENT_QUEUE_LEN: usize = 8;
+
+/// Owns the transport, and the buffers used to send requests, and receive responses.
+pub struct EspHosted<T> {
+    pub transport: T,
+    buf: [u8; BUF_SIZE],
+    decoder: FrameDecoder<BUF_SIZE>,
+    /// We only have one request outstanding at a time.
+    pending: PendingRequests<1>,
+    /// Returns the current time, in milliseconds. Used for timeouts.
+    now_ms: fn() -> u32,
+    /// Events received, but not yet returned by `next_event`.
+    events: Deque<Event, EVENT_QUEUE_LEN>,
+    /// The number of events dropped due to a full queue. The oldest event is dropped first.
+    pub events_dropped: u32,
+    /// Called with each message received that isn't a response we're waiting for, or an event, e.g.
+    /// HCI frames, and events that fail to parse.
+    pub on_unsolicited: Option<fn(MsgParsed<'_>)>,
+    /// The last country set with `set_country`, or `set_country_code`. Used to check TX power.
+    country: Option<WifiCountry>,
+}
+
+impl<T: Transport> EspHosted<T> {
+    /// `now_ms` returns the current time in milliseconds, e.g. from a SysTick counter. It may wrap.
+    pub fn new(transport: T, now_ms: fn() -> u32) -> Self {
+        Self {
+            transport,
+            buf: [0; BUF_SIZE],
+            decoder: FrameDecoder::new(),
+            pending: PendingRequests::new(DEFAULT_TIMEOUT_MS),
+            now_ms,
+            events: Deque::new(),
+            events_dropped: 0,
+            on_unsolicited: None,
+            country: None,
+        }
+    }
+
+    pub fn free(self) -> T {
+        self.transport
+    }
+
+    /// Set the time to wait for each response, in milliseconds, before returning `EspError::Timeout`.
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.pending.timeout_ms = timeout_ms;
+    }
+
+    /// The time to wait for each response, in milliseconds.
+    pub fn timeout(&self) -> u32 {
+        self.pending.timeout_ms
+    }
+
+    /// The last country set with `set_country`, or `set_country_code`, if it's in the `regulatory` table. Note that
+    /// the ESP reverts to its default country if it restarts; set it again after `EventEspInit`.
+    pub fn country(&self) -> Option<&WifiCountry> {
+        self.country.as_ref()
+    }
+
+    /// The current time, in milliseconds, from the `now_ms` function passed to `new`.
+    pub fn now_ms(&self) -> u32 {
+        (self.now_ms)()
+    }
+
+    /// Read from the transport, queue any events received, and pass other messages to `on_unsolicited`.
+    /// Call this periodically when not making calls, then drain events with `next_event`.
+    pub fn poll(&mut self) -> Result<(), EspError> {
+        self.decoder.read_from(&mut self.transport)?;
+
+        while let Some(frame) = self.decoder.next_frame() {
+            if let Ok(msg) = frame {
+                handle_unsolicited(
+                    msg,
+                    &mut self.events,
+                    &mut self.events_dropped,
+                    self.on_unsolicited,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The oldest event received, and not yet returned. Events are queued by `poll`, and while
+    /// waiting for responses.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Send a request, using one of the `wifi` module's functions, then wait for its response.
+    /// `send` is passed the buffer, transport, and UID. `decode` is passed the response's payload.
+    pub fn call<R>(
+        &mut self,
+        req_id: RpcId,
+        send: impl FnOnce(&mut [u8], &mut T, u32) -> Result<(), EspError>,
+        decode: impl FnOnce(&[u8]) -> Result<R, EspError>,
+    ) -> Result<R, EspError> {
+        let uid = self.pending.begin(req_id, (self.now_ms)())?;
+
+        if let Err(e) = send(&mut self.buf, &mut self.transport, uid) {
+            self.pending.cancel(uid);
+            return Err(e);
+        }
+
+        loop {
+            if let Err(e) = self.decoder.read_from(&mut self.transport) {
+                self.pending.cancel(uid);
+                return Err(e);
+            }
+
+            while let Some(frame) = self.decoder.next_frame() {
+                match frame {
+                    Ok(MsgParsed::Wifi(msg)) if self.pending.on_response(&msg.rpc).is_some() => {
+                        return decode(msg.data);
+                    }
+                    Ok(msg) => handle_unsolicited(
+                        msg,
+                        &mut self.events,
+                        &mut self.events_dropped,
+                        self.on_unsolicited,
+                    ),
+                    // e.g. a checksum mismatch; keep waiting.
+                    Err(_) => (),
+                }
+            }
+
+            self.pending.check(uid, (self.now_ms)())?;
+        }
+    }
+}
+
+/// Queue an event, or pass any other message to `on_unsolicited`. This is a free function, vice a method,
+/// since `msg` borrows from the decoder.
+fn handle_unsolicited(
+    msg: MsgParsed<'_>,
+    events: &mut Deque<Event, EVENT_QUEUE_LEN>,
+    events_dropped: &mut u32,
+    on_unsolicited: Option<fn(MsgParsed<'_>)>,
+) {
+    if let MsgParsed::Wifi(wifi_msg) = &msg
+        && let Ok(event) = Event::from_msg(wifi_msg)
+    {
+        if events.is_full() {
+            events.pop_front();
+            *events_dropped += 1;
+        }
+        events.push_back(event).ok();
+        return;
+    }
+
+    if let Some(f) = on_unsolicited {
+        f(msg);
+    }
+}
+
+/// Read the varint fields of a response payload into an array indexed by field number, then check its
+/// `resp` field. Fields that aren't present are 0, per protobuf's defaults.
+fn resp_fields<const F: usize>(data: &[u8], resp_field: usize) -> Result<[u64; F], EspError> {
+    let mut result = [0; F];
+
+    for field in Fields::new(data) {
+        if let (num, FieldVal::Varint(v)) = field?
+            && (num as usize) < F
+        {
+            result[num as usize] = v;
+        }
+    }
+
+    check_resp(result[resp_field])?;
+    Ok(result)
+}
+
+/// For responses that only contain the `resp` field.
+fn resp_empty(data: &[u8]) -> Result<(), EspError> {
+    resp_fields::<2>(data, 1).map(|_| ())
+}
+
+/// See the crate-level `cfg_heartbeat`.
+pub fn cfg_heartbeat<T: Transport>(
+    dev: &mut EspHosted<T>,
+    cfg: &RpcReqConfigHeartbeat,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqConfigHeartbeat,
+        |buf, t, uid| crate::cfg_heartbeat(buf, t, uid, cfg),
+        resp_empty,
+    )
+}
+
+/// See `wifi::init`.
+pub fn init<T: Transport>(dev: &mut EspHosted<T>, cfg: &InitConfig) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiInit,
+        |buf, t, uid| wifi::init(buf, t, uid, cfg),
+        resp_empty,
+    )
+}
+
+/// See `wifi::deinit`.
+pub fn deinit<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiDeinit, wifi::deinit, resp_empty)
+}
+
+/// See `wifi::start`.
+pub fn start<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiStart, wifi::start, resp_empty)
+}
+
+/// See `wifi::stop`.
+pub fn stop<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiStop, wifi::stop, resp_empty)
+}
+
+/// See `wifi::set_mode`.
+pub fn set_mode<T: Transport>(dev: &mut EspHosted<T>, mode: WifiMode) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqSetWifiMode,
+        |buf, t, uid| wifi::set_mode(buf, t, uid, mode),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_mode`.
+pub fn get_mode<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiMode, EspError> {
+    dev.call(RpcId::ReqGetWifiMode, wifi::get_mode, |data| {
+        // Fields: mode = 1, resp = 2.
+        let fields = resp_fields::<3>(data, 2)?;
+        (fields[1] as u8)
+            .try_into()
+            .map_err(|_| EspError::InvalidData)
+    })
+}
+
+/// See `wifi::get_mac`.
+pub fn get_mac<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+) -> Result<MacAddr, EspError> {
+    dev.call(
+        RpcId::ReqGetMacAddress,
+        |buf, t, uid| wifi::get_mac(buf, t, uid, ifx),
+        wifi::parse_mac,
+    )
+}
+
+/// See `wifi::set_mac`.
+pub fn set_mac<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+    mac: &MacAddr,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqSetMacAddress,
+        |buf, t, uid| wifi::set_mac(buf, t, uid, ifx, mac),
+        resp_empty,
+    )
+}
+
+/// See `wifi::set_country`. The country is stored, and used by `set_max_tx_power`.
+pub fn set_country<T: Transport>(
+    dev: &mut EspHosted<T>,
+    country: &WifiCountry,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetCountry,
+        |buf, t, uid| wifi::set_country(buf, t, uid, country),
+        resp_empty,
+    )?;
+
+    dev.country = Some(country.clone());
+    Ok(())
+}
+
+/// See `wifi::get_country`.
+pub fn get_country<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiCountry, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetCountry,
+        wifi::get_country,
+        wifi::parse_country,
+    )
+}
+
+/// See `wifi::set_country_code`. If the country is in the `regulatory` table, it's stored, and used by
+/// `set_max_tx_power`; otherwise, the stored country is cleared.
+pub fn set_country_code<T: Transport>(
+    dev: &mut EspHosted<T>,
+    cc: &[u8],
+    ieee80211d_enabled: bool,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetCountryCode,
+        |buf, t, uid| wifi::set_country_code(buf, t, uid, cc, ieee80211d_enabled),
+        resp_empty,
+    )?;
+
+    dev.country = regulatory::find(cc).map(|d| d.country());
+    Ok(())
+}
+
+/// See `wifi::set_max_tx_power`. `dbm` is checked against `country`'s limit. If `None`, the country last set
+/// with `set_country`, or `set_country_code` is used, if any.
+pub fn set_max_tx_power<T: Transport>(
+    dev: &mut EspHosted<T>,
+    dbm: f32,
+    country: Option<&WifiCountry>,
+) -> Result<(), EspError> {
+    let stored = dev.country.clone();
+    let country = country.or(stored.as_ref());
+
+    dev.call(
+        RpcId::ReqWifiSetMaxTxPower,
+        |buf, t, uid| wifi::set_max_tx_power(buf, t, uid, dbm, country),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_max_tx_power`. Returns the maximum TX power, in dBm.
+pub fn get_max_tx_power<T: Transport>(dev: &mut EspHosted<T>) -> Result<f32, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetMaxTxPower,
+        wifi::get_max_tx_power,
+        wifi::parse_max_tx_power,
+    )
+}
+
+/// See `wifi::set_protocol`.
+pub fn set_protocol<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+    protocols: &Protocols,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetProtocol,
+        |buf, t, uid| wifi::set_protocol(buf, t, uid, ifx, protocols),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_protocol`.
+pub fn get_protocol<T: Transport>(dev: &mut EspHosted<T>) -> Result<Protocols, EspError> {
+    dev.call(RpcId::ReqWifiGetProtocol, wifi::get_protocol, |data| {
+        // Fields: resp = 1, ifx = 2, protocol_bitmap = 3.
+        let fields = resp_fields::<4>(data, 1)?;
+        Ok(Protocols::from_byte(fields[3] as u8))
+    })
+}
+
+/// See `wifi::set_ps`.
+pub fn set_ps<T: Transport>(dev: &mut EspHosted<T>, ps_type: WifiPsType) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetPs,
+        |buf, t, uid| wifi::set_ps(buf, t, uid, ps_type),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_ps`.
+pub fn get_ps<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiPsType, EspError> {
+    dev.call(RpcId::ReqWifiGetPs, wifi::get_ps, wifi::parse_ps)
+}
+
+/// See `wifi::set_channel`. The combination is checked against `country`'s channels, if passed.
+pub fn set_channel<T: Transport>(
+    dev: &mut EspHosted<T>,
+    primary: u8,
+    second: WifiSecondChan,
+    country: Option<&WifiCountry>,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetChannel,
+        |buf, t, uid| wifi::set_channel(buf, t, uid, primary, second, country),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_channel`. Returns the primary channel, and the secondary channel's position.
+pub fn get_channel<T: Transport>(dev: &mut EspHosted<T>) -> Result<(u8, WifiSecondChan), EspError> {
+    dev.call(
+        RpcId::ReqWifiGetChannel,
+        wifi::get_channel,
+        wifi::parse_channel,
+    )
+}
+
+/// See `wifi::set_bandwidth`.
+pub fn set_bandwidth<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+    bw: WifiBandwidth,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetBandwidth,
+        |buf, t, uid| wifi::set_bandwidth(buf, t, uid, ifx, bw),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_bandwidth`.
+pub fn get_bandwidth<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+) -> Result<WifiBandwidth, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetBandwidth,
+        |buf, t, uid| wifi::get_bandwidth(buf, t, uid, ifx),
+        wifi::parse_bandwidth,
+    )
+}
+
+/// See `wifi::set_band`.
+pub fn set_band<T: Transport>(dev: &mut EspHosted<T>, band: WifiBand) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetBand,
+        |buf, t, uid| wifi::set_band(buf, t, uid, band),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_band`.
+pub fn get_band<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiBand, EspError> {
+    dev.call(RpcId::ReqWifiGetBand, wifi::get_band, wifi::parse_band)
+}
+
+/// See `wifi::set_band_mode`.
+pub fn set_band_mode<T: Transport>(
+    dev: &mut EspHosted<T>,
+    mode: WifiBandMode,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetBandMode,
+        |buf, t, uid| wifi::set_band_mode(buf, t, uid, mode),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_band_mode`.
+pub fn get_band_mode<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiBandMode, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetBandMode,
+        wifi::get_band_mode,
+        wifi::parse_band_mode,
+    )
+}
+
+/// See `wifi::set_protocols`.
+pub fn set_protocols<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+    protocols: &BandProtocols,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetProtocols,
+        |buf, t, uid| wifi::set_protocols(buf, t, uid, ifx, protocols),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_protocols`.
+pub fn get_protocols<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+) -> Result<BandProtocols, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetProtocols,
+        |buf, t, uid| wifi::get_protocols(buf, t, uid, ifx),
+        wifi::parse_protocols,
+    )
+}
+
+/// See `wifi::set_bandwidths`.
+pub fn set_bandwidths<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+    bandwidths: &BandBandwidths,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetBandwidths,
+        |buf, t, uid| wifi::set_bandwidths(buf, t, uid, ifx, bandwidths),
+        resp_empty,
+    )
+}
+
+/// See `wifi::get_bandwidths`.
+pub fn get_bandwidths<T: Transport>(
+    dev: &mut EspHosted<T>,
+    ifx: InterfaceType,
+) -> Result<BandBandwidths, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetBandwidths,
+        |buf, t, uid| wifi::get_bandwidths(buf, t, uid, ifx),
+        wifi::parse_bandwidths,
+    )
+}
+
+/// See `wifi::set_config`.
+pub fn set_config<T: Transport>(dev: &mut EspHosted<T>, cfg: &WifiConfig) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiSetConfig,
+        |buf, t, uid| wifi::set_config(buf, t, uid, cfg),
+        resp_empty,
+    )
+}
+
+/// See `wifi::connect`. This returns once the connection attempt has started; `EventStaConnected`
+/// is sent when it completes.
+pub fn connect<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiConnect, wifi::connect, resp_empty)
+}
+
+/// See `wifi::disconnect`.
+pub fn disconnect<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiDisconnect, wifi::disconnect, resp_empty)
+}
+
+/// See `wifi::scan_start`. Note that with `block = false`, this returns once the scan has started;
+/// `EventStaScanDone` is sent when it completes.
+pub fn scan_start<T: Transport>(
+    dev: &mut EspHosted<T>,
+    scan_start: &RpcReqWifiScanStart,
+) -> Result<(), EspError> {
+    dev.call(
+        RpcId::ReqWifiScanStart,
+        |buf, t, uid| wifi::scan_start(buf, t, uid, scan_start),
+        resp_empty,
+    )
+}
+
+/// See `wifi::scan_stop`.
+pub fn scan_stop<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiScanStop, wifi::scan_stop, resp_empty)
+}
+
+/// See `wifi::scan_get_ap_num`. Returns the number of APs found in the last scan.
+pub fn scan_get_ap_num<T: Transport>(dev: &mut EspHosted<T>) -> Result<u16, EspError> {
+    dev.call(RpcId::ReqWifiScanGetApNum, wifi::scan_get_ap_num, |data| {
+        // Fields: resp = 1, number = 2.
+        let fields = resp_fields::<3>(data, 1)?;
+        Ok(fields[2] as u16)
+    })
+}
+
+/// See `wifi::scan_get_ap_record`. Returns one AP record from the last scan, and removes it from the ESP's list.
+pub fn scan_get_ap_record<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiApRecord, EspError> {
+    dev.call(
+        RpcId::ReqWifiScanGetApRecord,
+        wifi::scan_get_ap_record,
+        |data| {
+            ApRecords::new(data)
+                .next()
+                .unwrap_or(Err(EspError::InvalidData))
+        },
+    )
+}
+
+/// See `wifi::scan_get_ap_records`. Returns up to `max_number` records from the last scan, sorted by RSSI.
+pub fn scan_get_ap_records<T: Transport>(
+    dev: &mut EspHosted<T>,
+    max_number: u8,
+) -> Result<Vec<WifiApRecord, MAX_AP_RECORDS>, EspError> {
+    dev.call(
+        RpcId::ReqWifiScanGetApRecords,
+        |buf, t, uid| wifi::scan_get_ap_records(buf, t, uid, max_number),
+        wifi::parse_ap_records,
+    )
+}
+
+/// See `wifi::clear_ap_list`.
+pub fn clear_ap_list<T: Transport>(dev: &mut EspHosted<T>) -> Result<(), EspError> {
+    dev.call(RpcId::ReqWifiClearApList, wifi::clear_ap_list, resp_empty)
+}
+
+/// See `wifi::ap_get_sta_list`. Returns the stations connected to our soft-AP.
+pub fn ap_get_sta_list<T: Transport>(dev: &mut EspHosted<T>) -> Result<WifiStaList, EspError> {
+    dev.call(
+        RpcId::ReqWifiApGetStaList,
+        wifi::ap_get_sta_list,
+        wifi::parse_sta_list,
+    )
+}
+
+/// See `wifi::get_promiscuous`. Returns whether promiscuous mode is enabled.
+pub fn get_promiscuous<T: Transport>(dev: &mut EspHosted<T>) -> Result<bool, EspError> {
+    dev.call(
+        RpcId::ReqWifiGetPromiscuous,
+        wifi::get_promiscuous,
+        |data| {
+            // Fields: resp = 1, enable = 2.
+            let fields = resp_fields::<3>(data, 1)?;
+            Ok(fields[2] != 0)
+        },
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::{cell::Cell, vec::Vec};
+
+    use super::*;
+    use crate::{
+        header::HEADER_SIZE,
+        rpc::{Rpc, RpcType, WireType, setup_rpc, write_rpc, write_rpc_len},
+    };
+
+    std::thread_local! {
+        static NOW_MS: Cell<u32> = const { Cell::new(0) };
+    }
+
+    /// A clock to pass to `EspHosted::new`. It's thread-local, so parallel tests don't share it; tests that depend
+    /// on it should set it first, since the harness may run tests on one thread.
+    pub(crate) fn now_ms() -> u32 {
+        NOW_MS.with(|t| t.get())
+    }
+
+    pub(crate) fn set_now_ms(ms: u32) {
+        NOW_MS.with(|t| t.set(ms))
+    }
+
+    /// Encode a message of varint fields, e.g. a response payload.
+    pub(crate) fn varints(fields: &[(u16, u64)]) -> Vec<u8> {
+        let mut buf = [0; 128];
+        let mut i = 0;
+        for &(field, val) in fields {
+            write_rpc(&mut buf, field, WireType::Varint, val, &mut i);
+        }
+        buf[..i].to_vec()
+    }
+
+    /// The payload of an `EventStaScanDone`.
+    pub(crate) fn scan_done(scan_id: u8) -> Vec<u8> {
+        let mut buf = [0; 32];
+        let mut i = 0;
+        write_rpc(&mut buf, 1, WireType::Varint, 0, &mut i);
+        write_rpc_len(&mut buf, 2, &mut i, |b| {
+            let mut j = 0;
+            write_rpc(b, 2, WireType::Varint, 3, &mut j);
+            write_rpc(b, 3, WireType::Varint, scan_id as u64, &mut j);
+            j
+        });
+        buf[..i].to_vec()
+    }
+
+    fn push_frame(out: &mut Vec<u8>, msg_type: RpcType, msg_id: RpcId, uid: u32, data: &[u8]) {
+        let rpc = Rpc {
+            msg_type,
+            msg_id,
+            uid,
+            payload: None,
+        };
+
+        let mut buf = [0; 256];
+        let len = setup_rpc(&mut buf, &rpc, data);
+        out.extend_from_slice(&buf[..len]);
+    }
+
+    /// Emulates the ESP. Replies to each request with a response, whose payload is set per request ID with
+    /// `respond`. By default, it's empty: Success, with all fields 0. Events in `events_per_call` are sent ahead of
+    /// each response.
+    #[derive(Default)]
+    pub(crate) struct MockEsp {
+        pub rx: Vec<u8>,
+        /// The IDs of requests received, in order.
+        pub requests: Vec<RpcId>,
+        responses: Vec<(RpcId, Vec<u8>)>,
+        /// (Event ID, payload)
+        pub events_per_call: Vec<(RpcId, Vec<u8>)>,
+    }
+
+    impl MockEsp {
+        /// Set the payload of the response to requests of type `req`.
+        pub fn respond(&mut self, req: RpcId, payload: Vec<u8>) {
+            self.responses.retain(|(id, _)| *id != req);
+            self.responses.push((req, payload));
+        }
+
+        /// Queue an event for the host.
+        pub fn push_event(&mut self, msg_id: RpcId, data: &[u8]) {
+            push_frame(&mut self.rx, RpcType::Event, msg_id, 0, data);
+        }
+
+        /// The number of requests of type `req` received.
+        pub fn count(&self, req: RpcId) -> usize {
+            self.requests.iter().filter(|r| **r == req).count()
+        }
+    }
+
+    impl Transport for MockEsp {
+        fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+            let (req, _, _) = Rpc::from_bytes(&buf[HEADER_SIZE..])?;
+            self.requests.push(req.msg_id);
+
+            for (msg_id, data) in &self.events_per_call {
+                push_frame(&mut self.rx, RpcType::Event, *msg_id, 0, data);
+            }
+
+            let resp_id = RpcId::try_from(req.msg_id as u16 + 256)
+                .unwrap_or_else(|_| panic!("no response ID"));
+            let payload = self
+                .responses
+                .iter()
+                .find(|(id, _)| *id == req.msg_id)
+                .map(|(_, p)| p.as_slice())
+                .unwrap_or(&[]);
+
+            push_frame(&mut self.rx, RpcType::Resp, resp_id, req.uid, payload);
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+            let n = self.rx.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.rx[..n]);
+            self.rx.drain(..n);
+            Ok(n)
+        }
+    }
+
+    fn scan_id(event: Option<Event>) -> u8 {
+        match event {
+            Some(Event::StaScanDone(ev)) => ev.scan_id,
+            _ => panic!("expected a scan done event"),
+        }
+    }
+
+    #[test]
+    fn events_during_call_are_queued() {
+        let mut esp = MockEsp::default();
+        esp.respond(
+            RpcId::ReqGetWifiMode,
+            varints(&[(1, WifiMode::Station as u64)]),
+        );
+        esp.events_per_call = (0..2)
+            .map(|i| (RpcId::EventStaScanDone, scan_done(i)))
+            .collect();
+        let mut dev = EspHosted::new(esp, now_ms);
+
+        assert!(matches!(get_mode(&mut dev), Ok(WifiMode::Station)));
+
+        assert_eq!(scan_id(dev.next_event()), 0);
+        assert_eq!(scan_id(dev.next_event()), 1);
+        assert!(dev.next_event().is_none());
+        assert_eq!(dev.events_dropped, 0);
+    }
+
+    #[test]
+    fn event_queue_full_drops_oldest() {
+        let esp = MockEsp {
+            events_per_call: (0..EVENT_QUEUE_LEN as u8 + 2)
+                .map(|i| (RpcId::EventStaScanDone, scan_done(i)))
+                .collect(),
+            ..Default::default()
+        };
+        let mut dev = EspHosted::new(esp, now_ms);
+
+        assert!(get_mode(&mut dev).is_ok());
+        assert_eq!(dev.events_dropped, 2);
+
+        for i in 0..EVENT_QUEUE_LEN as u8 {
+            assert_eq!(scan_id(dev.next_event()), i + 2);
+        }
+        assert!(dev.next_event().is_none());
+    }
+
+    #[test]
+    fn poll_queues_events() {
+        let mut dev = EspHosted::new(MockEsp::default(), now_ms);
+        dev.transport
+            .push_event(RpcId::EventStaScanDone, &scan_done(5));
+
+        assert!(dev.poll().is_ok());
+        assert_eq!(scan_id(dev.next_event()), 5);
+        assert!(dev.next_event().is_none());
+    }
+}
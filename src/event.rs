@@ -0,0 +1,373 @@
+//! Typed RPC events, and a dispatcher that passes them to handlers registered per event ID.
+//! Events arrive mixed in with responses; pass each `WifiMsg` received to `EventDispatcher::dispatch`,
+//! and it's ignored unless it's an event with a subscriber.
+//!
+//! ```ignore
+//! static HEARTBEAT_CHANNEL: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+//! static DISCONNECTS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+//!
+//! fn on_disconnected(event: &Event) {
+//!     if let Event::StaDisconnected(ev) = event {
+//!         println!("Disconnected. Reason: {}", ev.reason_code);
+//!         critical_section::with(|cs| {
+//!             let count = DISCONNECTS.borrow(cs);
+//!             count.set(count.get() + 1);
+//!         });
+//!     }
+//! }
+//!
+//! let mut events = EventDispatcher::<4>::new();
+//! events.subscribe_fn(RpcId::EventStaDisconnected, on_disconnected)?;
+//! // Or, with the `async` feature, an Embassy channel:
+//! events.subscribe(RpcId::EventHeartbeat, &HEARTBEAT_CHANNEL)?;
+//!
+//! // On reception:
+//! if let MsgParsed::Wifi(msg) = msg {
+//!     events.dispatch(&msg)?;
+//! }
+//! ```
+
+use heapless::Vec;
+
+use crate::{
+    EspError, WifiMsg,
+    proto_data::{EventHeartbeat, RpcId},
+    rpc::{FieldVal, Fields, RpcType, check_resp, decode_int32},
+    wifi::{DisconnectReason, WifiAuthMode},
+};
+
+pub const SSID_MAX_LEN: usize = 32;
+
+/// `WIFI_EVENT_STA_CONNECTED`
+#[derive(Clone, Default)]
+pub struct StaConnected {
+    pub ssid: Vec<u8, SSID_MAX_LEN>,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub authmode: WifiAuthMode,
+    pub aid: u16,
+}
+
+/// `WIFI_EVENT_STA_DISCONNECTED`
+#[derive(Clone, Default)]
+pub struct StaDisconnected {
+    pub ssid: Vec<u8, SSID_MAX_LEN>,
+    pub bssid: [u8; 6],
+    /// `DisconnectReason::Unknown` if the reason isn't one we know of; see `reason_code`.
+    pub reason: DisconnectReason,
+    /// The raw `wifi_err_reason_t` value.
+    pub reason_code: u16,
+    pub rssi: i8,
+}
+
+/// `WIFI_EVENT_SCAN_DONE`
+#[derive(Clone, Copy, Default)]
+pub struct StaScanDone {
+    /// 0 if the scan succeeded.
+    pub status: u32,
+    /// The number of APs found.
+    pub number: u8,
+    pub scan_id: u8,
+}
+
+/// `WIFI_EVENT_AP_STACONNECTED`, and `WIFI_EVENT_AP_STADISCONNECTED`: A station connected to, or
+/// disconnected from our soft-AP.
+#[derive(Clone, Copy, Default)]
+pub struct ApStaEvent {
+    pub mac: [u8; 6],
+    /// Association ID.
+    pub aid: u8,
+    pub is_mesh_child: bool,
+    /// Why the station disconnected. `None` for connections. `DisconnectReason::Unknown` if the reason
+    /// isn't one we know of; see `reason_code`.
+    pub reason: Option<DisconnectReason>,
+    /// The raw `wifi_err_reason_t` value. 0 for connections.
+    pub reason_code: u16,
+}
+
+/// An event sent by the ESP. These correspond to the `RpcId::Event*` variants.
+#[derive(Clone)]
+pub enum Event {
+    EspInit,
+    Heartbeat(EventHeartbeat),
+    ApStaConnected(ApStaEvent),
+    ApStaDisconnected(ApStaEvent),
+    /// A Wi-Fi event without data, e.g. `WIFI_EVENT_STA_START`. This is the `wifi_event_t` value.
+    WifiEventNoArgs(u32),
+    StaScanDone(StaScanDone),
+    StaConnected(StaConnected),
+    StaDisconnected(StaDisconnected),
+}
+
+impl Event {
+    /// Parse an event from a received message. Returns `EspError::InvalidData` if the message isn't an
+    /// event, and `EspError::Esp` if the event contains a non-zero `resp` code.
+    pub fn from_msg(msg: &WifiMsg) -> Result<Self, EspError> {
+        if msg.rpc.msg_type != RpcType::Event {
+            return Err(EspError::InvalidData);
+        }
+
+        let data = msg.data;
+
+        Ok(match msg.rpc.msg_id {
+            RpcId::EventEspInit => Self::EspInit,
+            RpcId::EventHeartbeat => {
+                let mut result = EventHeartbeat { number: 0 };
+                for field in Fields::new(data) {
+                    if let (1, FieldVal::Varint(v)) = field? {
+                        result.number = v as u32;
+                    }
+                }
+                Self::Heartbeat(result)
+            }
+            RpcId::EventApStaConnected => Self::ApStaConnected(parse_ap_sta_event(data)?),
+            RpcId::EventApStaDisconnected => Self::ApStaDisconnected(parse_ap_sta_event(data)?),
+            RpcId::EventWifiEventNoArgs => {
+                let mut event_id = 0;
+                for field in Fields::new(data) {
+                    match field? {
+                        (1, FieldVal::Varint(v)) => check_resp(v)?,
+                        (2, FieldVal::Varint(v)) => event_id = v as u32,
+                        _ => (),
+                    }
+                }
+                Self::WifiEventNoArgs(event_id)
+            }
+            RpcId::EventStaScanDone => {
+                let mut result = StaScanDone::default();
+                for field in Fields::new(parse_event_wrapper(data)?) {
+                    match field? {
+                        (1, FieldVal::Varint(v)) => result.status = v as u32,
+                        (2, FieldVal::Varint(v)) => result.number = v as u8,
+                        (3, FieldVal::Varint(v)) => result.scan_id = v as u8,
+                        _ => (),
+                    }
+                }
+                Self::StaScanDone(result)
+            }
+            RpcId::EventStaConnected => {
+                let mut result = StaConnected::default();
+                for field in Fields::new(parse_event_wrapper(data)?) {
+                    match field? {
+                        (1, FieldVal::Len(v)) => result.ssid = parse_ssid(v)?,
+                        (3, FieldVal::Len(v)) => result.bssid = parse_mac(v)?,
+                        (4, FieldVal::Varint(v)) => result.channel = v as u8,
+                        (5, FieldVal::Varint(v)) => {
+                            result.authmode = (v as u8).try_into().unwrap_or_default()
+                        }
+                        (6, FieldVal::Varint(v)) => result.aid = v as u16,
+                        _ => (),
+                    }
+                }
+                Self::StaConnected(result)
+            }
+            RpcId::EventStaDisconnected => {
+                let mut result = StaDisconnected::default();
+                for field in Fields::new(parse_event_wrapper(data)?) {
+                    match field? {
+                        (1, FieldVal::Len(v)) => result.ssid = parse_ssid(v)?,
+                        (3, FieldVal::Len(v)) => result.bssid = parse_mac(v)?,
+                        (4, FieldVal::Varint(v)) => {
+                            result.reason_code = v as u16;
+                            result.reason = DisconnectReason::from_code(v as u16);
+                        }
+                        (5, FieldVal::Varint(v)) => result.rssi = decode_int32(v) as i8,
+                        _ => (),
+                    }
+                }
+                Self::StaDisconnected(result)
+            }
+            _ => return Err(EspError::InvalidData),
+        })
+    }
+}
+
+/// Several events wrap their data in a sub-message at field 2, following the `resp` field at field 1. Check
+/// `resp`, and return the sub-message.
+fn parse_event_wrapper(data: &[u8]) -> Result<&[u8], EspError> {
+    let mut result: &[u8] = &[];
+
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Len(v)) => result = v,
+            _ => (),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Fields: resp = 1, mac = 2, aid = 3, is_mesh_child = 4, reason = 5 (Disconnections only).
+fn parse_ap_sta_event(data: &[u8]) -> Result<ApStaEvent, EspError> {
+    let mut result = ApStaEvent::default();
+
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Len(v)) => result.mac = parse_mac(v)?,
+            (3, FieldVal::Varint(v)) => result.aid = v as u8,
+            (4, FieldVal::Varint(v)) => result.is_mesh_child = v != 0,
+            (5, FieldVal::Varint(v)) => {
+                result.reason_code = v as u16;
+                result.reason = Some(DisconnectReason::from_code(v as u16));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_mac(buf: &[u8]) -> Result<[u8; 6], EspError> {
+    buf.try_into().map_err(|_| EspError::InvalidData)
+}
+
+fn parse_ssid(buf: &[u8]) -> Result<Vec<u8, SSID_MAX_LEN>, EspError> {
+    Vec::from_slice(buf).map_err(|_| EspError::Capacity)
+}
+
+/// Receives events from an `EventDispatcher`. Implement this to pass events to a queue, or
+/// similar. For plain functions, use `EventDispatcher::subscribe_fn`.
+pub trait EventHandler {
+    fn handle(&self, event: &Event);
+}
+
+#[cfg(feature = "async")]
+impl<M, const N: usize> EventHandler for embassy_sync::channel::Channel<M, Event, N>
+where
+    M: embassy_sync::blocking_mutex::raw::RawMutex,
+{
+    /// Queues the event. If the channel is full, the event is dropped.
+    fn handle(&self, event: &Event) {
+        self.try_send(event.clone()).ok();
+    }
+}
+
+enum Subscriber<'a> {
+    Fn(fn(&Event)),
+    Handler(&'a dyn EventHandler),
+}
+
+/// Passes events to handlers registered for their `RpcId`. Holds up to `N` subscriptions.
+pub struct EventDispatcher<'a, const N: usize> {
+    subscribers: Vec<(RpcId, Subscriber<'a>), N>,
+}
+
+impl<const N: usize> Default for EventDispatcher<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> EventDispatcher<'a, N> {
+    pub const fn new() -> Self {
+        Self {
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Call `handler` with each event of type `id`, e.g. `RpcId::EventStaConnected`.
+    pub fn subscribe_fn(&mut self, id: RpcId, handler: fn(&Event)) -> Result<(), EspError> {
+        self.add(id, Subscriber::Fn(handler))
+    }
+
+    /// Pass each event of type `id` to `handler`, e.g. a static channel.
+    pub fn subscribe(&mut self, id: RpcId, handler: &'a dyn EventHandler) -> Result<(), EspError> {
+        self.add(id, Subscriber::Handler(handler))
+    }
+
+    /// Remove all subscriptions for an event ID.
+    pub fn unsubscribe(&mut self, id: RpcId) {
+        self.subscribers.retain(|(sub_id, _)| *sub_id != id);
+    }
+
+    fn add(&mut self, id: RpcId, subscriber: Subscriber<'a>) -> Result<(), EspError> {
+        let id_val = id as u16;
+        if id_val <= RpcId::EventBase as u16 || id_val >= RpcId::EventMax as u16 {
+            return Err(EspError::InvalidData);
+        }
+
+        self.subscribers
+            .push((id, subscriber))
+            .map_err(|_| EspError::Capacity)
+    }
+
+    /// Pass a received message to the handlers subscribed to it. Returns `Ok(false)` if it isn't an event,
+    /// or there are no subscribers for it. The event is only parsed if there's a subscriber.
+    pub fn dispatch(&self, msg: &WifiMsg) -> Result<bool, EspError> {
+        if msg.rpc.msg_type != RpcType::Event
+            || !self.subscribers.iter().any(|(id, _)| *id == msg.rpc.msg_id)
+        {
+            return Ok(false);
+        }
+
+        let event = Event::from_msg(msg)?;
+
+        for (id, subscriber) in &self.subscribers {
+            if *id != msg.rpc.msg_id {
+                continue;
+            }
+
+            match subscriber {
+                Subscriber::Fn(f) => f(&event),
+                Subscriber::Handler(h) => h.handle(&event),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rpc::{WireType, write_rpc, write_rpc_bytes},
+        wifi::DisconnectCategory,
+    };
+
+    fn ap_sta_event(reason: Option<u64>) -> ApStaEvent {
+        let mut buf = [0; 32];
+        let mut i = 0;
+        write_rpc(&mut buf, 1, WireType::Varint, 0, &mut i);
+        write_rpc_bytes(&mut buf, 2, &[2, 0, 0, 0, 0, 1], &mut i);
+        write_rpc(&mut buf, 3, WireType::Varint, 4, &mut i);
+        if let Some(r) = reason {
+            write_rpc(&mut buf, 5, WireType::Varint, r, &mut i);
+        }
+
+        parse_ap_sta_event(&buf[..i]).unwrap_or_else(|_| panic!("failed to parse"))
+    }
+
+    #[test]
+    fn ap_sta_connected() {
+        let ev = ap_sta_event(None);
+        assert_eq!(ev.mac, [2, 0, 0, 0, 0, 1]);
+        assert_eq!(ev.aid, 4);
+        assert!(ev.reason.is_none());
+        assert_eq!(ev.reason_code, 0);
+    }
+
+    #[test]
+    fn ap_sta_disconnect_reasons() {
+        let ev = ap_sta_event(Some(15));
+        assert!(ev.reason == Some(DisconnectReason::FourWayHandshakeTimeout));
+        assert_eq!(ev.reason_code, 15);
+
+        // Unknown codes are kept, and aren't mistaken for `Unspecified`.
+        let ev = ap_sta_event(Some(999));
+        assert!(ev.reason == Some(DisconnectReason::Unknown));
+        assert_eq!(ev.reason_code, 999);
+    }
+
+    #[test]
+    fn reason_categories() {
+        assert!(DisconnectReason::from_code(1) == DisconnectReason::Unspecified);
+        assert!(DisconnectReason::from_code(1).category() == DisconnectCategory::Other);
+        assert!(DisconnectReason::from_code(202).category() == DisconnectCategory::AuthFailure);
+        assert!(DisconnectReason::from_code(200).category() == DisconnectCategory::LinkLost);
+        assert!(DisconnectReason::from_code(999).category() == DisconnectCategory::Unknown);
+        assert!(DisconnectReason::from_code(0).category() == DisconnectCategory::Unknown);
+    }
+}
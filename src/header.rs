@@ -161,11 +161,11 @@ impl PayloadHeader {
 }
 
 /// Builds the entire frame sent and received over the wire protocol. See `esp_hosted_protocol.md`
-/// for details on how this is constructed.
+/// for details on how this is constructed. The payload, of `payload_len` bytes, must already be written to
+/// `out`, starting at `HEADER_SIZE`; this fills in the payload header and TLV ahead of it, and the checksum.
 /// Outputs total bytes in the frame.
-pub(crate) fn build_frame_wifi(out: &mut [u8], payload: &[u8]) -> usize {
+pub(crate) fn build_frame_wifi(out: &mut [u8], payload_len: usize) -> usize {
     // `payload` here is all remaining bytes, including RPC metadata.
-    let payload_len = payload.len();
 
     // From `serial_if.c`: Always Resp for compose. Either Resp or Event from parse. (host-side)
     let endpoint_value = RpcEndpoint::CtrlResp.as_bytes();
@@ -197,7 +197,7 @@ pub(crate) fn build_frame_wifi(out: &mut [u8], payload: &[u8]) -> usize {
     copy_le!(out, payload_len as u16, i..i + 2);
     i += 2;
 
-    out[i..i + payload_len].copy_from_slice(payload);
+    // The payload is already in place.
     i += payload_len;
 
     // system_design...: "**Checksum Coverage**: The checksum covers the **entire frame** including:
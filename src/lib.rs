@@ -19,12 +19,28 @@
 //! This library does not use an allocator. This makes integrating it simple, but it uses a significant amount of flash
 //! for static buffers. These are configured in the `build_proto/src/main.rs` script on a field-by-field basis.
 //!
-//! It's transport agnostic; compatible with SPI, SDIO, and UART. It does this by allowing the application firmware to pass
-//! a generic `write` function, and reads are performed as functions that act on buffers passed by the firmware.
+//! It's transport agnostic; compatible with SPI, SDIO, and UART. It does this by having the application firmware
+//! implement the `Transport` trait for its hardware, which handles writes and reads. A plain `write` closure may be passed
+//! instead, in which case reads are performed as functions that act on buffers passed by the firmware.
+//!
+//! With the `async` feature, the `asynch` module provides an async API, built on `embedded-io-async` and `embassy-sync`.
 
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod decoder;
+pub mod device;
+pub mod event;
 pub mod header;
+pub mod pending;
 pub mod proto_data;
+pub mod regulatory;
 mod rpc;
+pub mod scan;
+pub mod sdio;
+pub mod softap;
+pub mod spi;
+pub mod spi_hd;
+pub mod station;
 mod transport;
 pub mod wifi;
 
@@ -34,18 +50,26 @@ pub mod proto;
 mod util;
 
 // pub use ble::*;
+pub use decoder::FrameDecoder;
+pub use device::EspHosted;
+pub use event::{Event, EventDispatcher};
 use defmt::{Format, println};
 pub use esp_errors::EspCode;
 pub use header::{PayloadHeader, build_frame_ble};
+pub use pending::PendingRequests;
 use micropb::{MessageDecode, PbDecoder};
 pub use proto::{Rpc as RpcP, RpcId as RpcIdP, RpcType as RpcTypeP};
 pub use proto_data::RpcId;
-pub use transport::PacketType;
+pub use scan::ScanManager;
+pub use softap::StationTable;
+pub use station::{LinkState, StaSupervisor};
+pub use transport::{PacketType, Transport, set_checksum_verification};
 
 pub use crate::rpc::*;
 use crate::{
-    header::{HEADER_SIZE, InterfaceType, PL_HEADER_SIZE},
+    header::{HEADER_SIZE, InterfaceType, PL_HEADER_SIZE, TLV_SIZE},
     proto_data::RpcReqConfigHeartbeat,
+    transport::{checksum_verification_enabled, verify_checksum, verify_checksum_split},
 };
 
 #[macro_export]
@@ -88,24 +112,17 @@ pub enum EspError {
 // }
 
 /// Minimum of 10s.
-pub fn cfg_heartbeat<W>(
+pub fn cfg_heartbeat<T: Transport>(
     buf: &mut [u8],
-    mut write: W,
+    transport: &mut T,
     uid: u32,
     cfg: &RpcReqConfigHeartbeat,
-) -> Result<(), EspError>
-// todo: Typedef this if able. (Unstable feature)
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqConfigHeartbeat, uid);
 
-    let mut data = [0; 5]; // Seems to be 4 in for small duration values.
-    let data_size = cfg.to_bytes(&mut data);
-
     // unsafe {
-    let frame_len = setup_rpc(buf, &rpc, &data[..data_size]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| cfg.to_bytes(data));
+    transport.write(&buf[..frame_len])?;
     // }
 
     Ok(())
@@ -145,12 +162,8 @@ pub fn parse_msg_header_not_read(buf: &[u8]) -> Result<MsgParsed, EspError> {
         // each index, effectively. It covers both forward, and reverse shifts by a few bytes.
         for offset in 1..MAX_SHIFT {
             if buf[offset..offset + 2] == [12, 0] && buf[offset + 6..offset + 9] == [0, 0, 4] {
-                // Align the shift with the [12, 0] we matched.
-                let shift = 4 - offset;
-
-                return Ok(MsgParsed::Hci(HciMsg {
-                    data: &buf[PL_HEADER_SIZE - shift..],
-                }));
+                // Align the shift with the [12, 0] we matched. (The header starts at `offset - 4`.)
+                return parse_shifted_hci(buf, PL_HEADER_SIZE + offset - 4);
             }
         }
 
@@ -164,17 +177,8 @@ pub fn parse_msg_header_not_read(buf: &[u8]) -> Result<MsgParsed, EspError> {
             }
 
             if buf[offset..offset + 3] == [0, 4, 62] {
-                // Align the shift with the [12, 0] we matched.
-                let shift = 9 - offset;
-                // println!(
-                //     "Shifted BLE packet Type 2. Shift: {}. Offset: {}",
-                //     shift, offset
-                // );
-
-                // println!("Corrected buf T2: {:?}",&buf[PL_HEADER_SIZE - shift..30]); // todo tmep
-                return Ok(MsgParsed::Hci(HciMsg {
-                    data: &buf[PL_HEADER_SIZE - shift..],
-                }));
+                // Align the shift with the [0, 4, 62] we matched. (The header starts at `offset - 9`.)
+                return parse_shifted_hci(buf, PL_HEADER_SIZE + offset - 9);
             }
         }
 
@@ -188,16 +192,48 @@ pub fn parse_msg_header_not_read(buf: &[u8]) -> Result<MsgParsed, EspError> {
         // header.if_type = InterfaceType::Hci;
     }
 
-    let mut header = PayloadHeader::from_bytes(&buf[..HEADER_SIZE])?;
-    let mut total_size = header.len as usize + PL_HEADER_SIZE;
+    parse_frame(buf, checksum_verification_enabled())
+}
+
+/// Parse an HCI frame found shifted in `buf`, whose payload starts at `data_start`. If checksum verification is
+/// enabled, the frame is only accepted if its whole header is in `buf`, and its checksum matches; a late reception
+/// start cuts off the start of the header, so it can't be verified.
+fn parse_shifted_hci(buf: &[u8], data_start: usize) -> Result<MsgParsed<'_>, EspError> {
+    if !checksum_verification_enabled() {
+        return Ok(MsgParsed::Hci(HciMsg {
+            data: &buf[data_start..],
+        }));
+    }
+
+    let Some(header_start) = data_start.checked_sub(PL_HEADER_SIZE) else {
+        return Err(EspError::CrcMismatch);
+    };
+
+    parse_frame(&buf[header_start..], true)
+}
+
+/// Parse a frame that starts with the payload header. Unlike `parse_msg_header_not_read`, this
+/// doesn't attempt to correct for shifted packets; it's used when the frame boundaries are already known,
+/// e.g. by `FrameDecoder`.
+pub(crate) fn parse_frame(buf: &[u8], verify: bool) -> Result<MsgParsed<'_>, EspError> {
+    if buf.len() < PL_HEADER_SIZE {
+        return Err(EspError::InvalidData);
+    }
+
+    let header = PayloadHeader::from_bytes(&buf[..PL_HEADER_SIZE])?;
+    let total_size = header.len as usize + PL_HEADER_SIZE;
 
     if total_size > buf.len() {
         return Err(EspError::Capacity);
     }
 
+    if verify && !verify_checksum(&buf[..total_size]) {
+        return Err(EspError::CrcMismatch);
+    }
+
     if header.if_type == InterfaceType::Hci {
         return Ok(MsgParsed::Hci(HciMsg {
-            data: &buf[PL_HEADER_SIZE..],
+            data: &buf[PL_HEADER_SIZE..total_size],
         }));
     }
 
@@ -206,27 +242,23 @@ pub fn parse_msg_header_not_read(buf: &[u8]) -> Result<MsgParsed, EspError> {
         println!(
             "Error: Invalid RPC packet. packet size: {}, buf: {:?}",
             total_size,
-            buf[0..24]
+            buf[0..PL_HEADER_SIZE]
         );
         return Err(EspError::InvalidData);
     }
 
     let rpc_buf = &buf[HEADER_SIZE..total_size];
-    let (rpc, data_start_i, _data_len_rpc) = Rpc::from_bytes(rpc_buf)?;
-    let data = &rpc_buf[data_start_i..];
+    let (rpc, data_start_i, data_len) = Rpc::from_bytes(rpc_buf)?;
+    let data = &rpc_buf[data_start_i..data_start_i + data_len];
 
     // Parsing the proto data from the generated mod.
-    // let mut decoder = PbDecoder::new(&rpc_buf[0..100]);
     let mut decoder = PbDecoder::new(rpc_buf);
     let mut rpc_parsed = RpcP::default();
 
     let rpc_parsed = match rpc_parsed.decode(&mut decoder, rpc_buf.len()) {
-        Ok(r) => Ok(rpc_parsed),
-        Err(e) => Err(EspError::Proto),
+        Ok(_) => Ok(rpc_parsed),
+        Err(_) => Err(EspError::Proto),
     };
-    // rpc_parsed
-    //     .decode(&mut decoder, rpc_buf.len())
-    //     .map_err(|_| EspError::Proto)?;
 
     Ok(MsgParsed::Wifi(WifiMsg {
         header,
@@ -236,24 +268,41 @@ pub fn parse_msg_header_not_read(buf: &[u8]) -> Result<MsgParsed, EspError> {
     }))
 }
 
-/// Use this for SPI, after parsing the header in the first 12-byte transaction.
-/// Assumes the header has been read already, and passed as a param.
-pub fn parse_msg_header_read(buf: &[u8], header: PayloadHeader) -> Result<MsgParsed, EspError> {
+/// Use this for SPI, after reading the header in the first 12-byte transaction. `header_buf` is the
+/// header's bytes, as received, and `buf` is the rest of the frame.
+pub fn parse_msg_header_read<'a>(
+    buf: &'a [u8],
+    header_buf: &[u8],
+) -> Result<MsgParsed<'a>, EspError> {
+    if header_buf.len() < PL_HEADER_SIZE {
+        return Err(EspError::InvalidData);
+    }
+
+    let header = PayloadHeader::from_bytes(&header_buf[..PL_HEADER_SIZE])?;
     let mut total_size = header.len as usize;
 
     if total_size > buf.len() {
         return Err(EspError::Capacity);
     }
 
+    if checksum_verification_enabled()
+        && !verify_checksum_split(&header_buf[..PL_HEADER_SIZE], &buf[..total_size])
+    {
+        return Err(EspError::CrcMismatch);
+    }
+
     if header.if_type == InterfaceType::Hci {
         return Ok(MsgParsed::Hci(HciMsg { data: &buf[..] }));
     }
 
-    let rpc_buf = &buf[..total_size];
-    let (rpc, data_start_i, _data_len_rpc) = Rpc::from_bytes(rpc_buf)?;
-    let data = &rpc_buf[data_start_i..];
+    if TLV_SIZE >= total_size {
+        return Err(EspError::InvalidData);
+    }
 
-    println!("RPC read: {:?}", rpc); // todo temp
+    // Skip the TLV, as `parse_frame` does.
+    let rpc_buf = &buf[TLV_SIZE..total_size];
+    let (rpc, data_start_i, data_len) = Rpc::from_bytes(rpc_buf)?;
+    let data = &rpc_buf[data_start_i..data_start_i + data_len];
 
     // Parsing the proto data from the generated mod.
     // let mut decoder = PbDecoder::new(&rpc_buf[0..100]);
@@ -275,3 +324,66 @@ pub fn parse_msg_header_read(buf: &[u8], header: PayloadHeader) -> Result<MsgPar
         rpc_parsed,
     }))
 }
+
+/// `defmt` requires a global logger to link. Tests run on the host, so discard its output.
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ble::HciPkt, transport::compute_checksum};
+
+    /// An HCI frame, as the ESP sends it when matched by the shifted-packet checks: The packet type is in the
+    /// payload, and the header's is 0.
+    fn hci_frame(out: &mut [u8]) -> usize {
+        let len = build_frame_ble(out, HciPkt::Evt, &[4, 0x0E, 0x04, 0x01, 0x03, 0x0C, 0x00]);
+        out[11] = 0;
+        out[6..8].fill(0);
+        let checksum = compute_checksum(&out[..len]);
+        out[6..8].copy_from_slice(&checksum.to_le_bytes());
+        len
+    }
+
+    #[test]
+    fn shifted_hci_verified() {
+        // A byte of noise ahead of the frame.
+        let mut buf = [0xFF; 64];
+        let len = hci_frame(&mut buf[1..]);
+
+        match parse_msg_header_not_read(&buf) {
+            Ok(MsgParsed::Hci(msg)) => assert_eq!(msg.data, &buf[1 + PL_HEADER_SIZE..1 + len]),
+            _ => panic!("expected an HCI frame"),
+        }
+
+        buf[1 + len - 1] ^= 1;
+        assert!(matches!(
+            parse_msg_header_not_read(&buf),
+            Err(EspError::CrcMismatch)
+        ));
+    }
+
+    #[test]
+    fn shifted_hci_truncated_header() {
+        // A late reception start cuts off the first 3 header bytes, so the checksum can't be verified.
+        let mut frame = [0; 64];
+        hci_frame(&mut frame);
+
+        assert!(matches!(
+            parse_msg_header_not_read(&frame[3..]),
+            Err(EspError::CrcMismatch)
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+//! Tracks outstanding RPC requests, so responses can be matched to them. This allocates UIDs,
+//! matches each response's UID (and RPC ID) to the request that caused it, and expires requests
+//! that haven't received a response after a timeout.
+//!
+//! This library doesn't have access to a clock; pass the current time, in milliseconds, from your
+//! firmware's timer, e.g. a SysTick counter. It may wrap.
+//!
+//! ```ignore
+//! let mut pending = PendingRequests::<4>::new(DEFAULT_TIMEOUT_MS);
+//!
+//! let uid = pending.begin(RpcId::ReqGetWifiMode, now_ms())?;
+//! wifi::get_mode(&mut buf, &mut transport, uid)?;
+//!
+//! // When a frame is received:
+//! if let MsgParsed::Wifi(msg) = msg {
+//!     if let Some(req) = pending.on_response(&msg.rpc) {
+//!         // `msg` is the response to `req`.
+//!     }
+//! }
+//!
+//! // Periodically:
+//! while let Some(req) = pending.expire(now_ms()) {
+//!     // `req` timed out.
+//! }
+//! ```
+
+use heapless::Vec;
+
+use crate::{
+    EspError,
+    proto_data::RpcId,
+    rpc::{Rpc, RpcType},
+};
+
+/// The default time to wait for a response, in milliseconds. Some requests, e.g. `ReqWifiInit`,
+/// can take several hundred ms.
+pub const DEFAULT_TIMEOUT_MS: u32 = 2_000;
+
+/// Response IDs are request IDs, offset by this amount. e.g. `ReqGetWifiMode` = 259, and
+/// `RespGetWifiMode` = 515.
+const RESP_OFFSET: u16 = RpcId::RespBase as u16 - RpcId::ReqBase as u16;
+
+/// A request we've sent, which hasn't yet received a response.
+#[derive(Clone, Copy)]
+pub struct PendingRequest {
+    pub uid: u32,
+    /// The request's ID, e.g. `RpcId::ReqGetWifiMode`.
+    pub msg_id: RpcId,
+    /// The time the request was sent, in ms.
+    pub sent_ms: u32,
+}
+
+impl PendingRequest {
+    /// If the timeout has elapsed since this request was sent.
+    fn expired(&self, now_ms: u32, timeout_ms: u32) -> bool {
+        now_ms.wrapping_sub(self.sent_ms) >= timeout_ms
+    }
+
+    /// If `rpc` is the response to this request.
+    fn matches(&self, rpc: &Rpc) -> bool {
+        rpc.msg_type == RpcType::Resp
+            && rpc.uid == self.uid
+            && rpc.msg_id as u16 == self.msg_id as u16 + RESP_OFFSET
+    }
+}
+
+/// A table of up to `N` outstanding requests.
+pub struct PendingRequests<const N: usize> {
+    requests: Vec<PendingRequest, N>,
+    next_uid: u32,
+    /// The time to wait for a response, in milliseconds, before a request expires.
+    pub timeout_ms: u32,
+}
+
+impl<const N: usize> Default for PendingRequests<N> {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT_MS)
+    }
+}
+
+impl<const N: usize> PendingRequests<N> {
+    pub const fn new(timeout_ms: u32) -> Self {
+        Self {
+            requests: Vec::new(),
+            // We skip 0, since events are sent with a UID of 0, or none at all.
+            next_uid: 1,
+            timeout_ms,
+        }
+    }
+
+    /// Register a request we're about to send, and return the UID to send it with. Returns
+    /// `EspError::Capacity` if `N` requests are already outstanding; call `expire` to free them.
+    pub fn begin(&mut self, msg_id: RpcId, now_ms: u32) -> Result<u32, EspError> {
+        let uid = self.next_uid;
+
+        self.requests
+            .push(PendingRequest {
+                uid,
+                msg_id,
+                sent_ms: now_ms,
+            })
+            .map_err(|_| EspError::Capacity)?;
+
+        self.next_uid = self.next_uid.wrapping_add(1).max(1);
+
+        Ok(uid)
+    }
+
+    /// Call this with each RPC message received. If it's the response to an outstanding request, that
+    /// request is removed from the table, and returned. Returns `None` for events, and for responses that
+    /// don't match an outstanding request, e.g. ones that arrive after their request expired.
+    pub fn on_response(&mut self, rpc: &Rpc) -> Option<PendingRequest> {
+        let i = self.requests.iter().position(|r| r.matches(rpc))?;
+        Some(self.requests.swap_remove(i))
+    }
+
+    /// Remove, and return a request that has been waiting longer than the timeout, if there is one.
+    /// Call this repeatedly until it returns `None`.
+    pub fn expire(&mut self, now_ms: u32) -> Option<PendingRequest> {
+        let i = self
+            .requests
+            .iter()
+            .position(|r| r.expired(now_ms, self.timeout_ms))?;
+
+        Some(self.requests.swap_remove(i))
+    }
+
+    /// Check on a single request. Returns `EspError::Timeout` if it has expired; it's then removed
+    /// from the table. Returns `Ok(true)` if it's still outstanding, and `Ok(false)` if it isn't in the table,
+    /// e.g. because its response has arrived.
+    pub fn check(&mut self, uid: u32, now_ms: u32) -> Result<bool, EspError> {
+        let Some(i) = self.requests.iter().position(|r| r.uid == uid) else {
+            return Ok(false);
+        };
+
+        if self.requests[i].expired(now_ms, self.timeout_ms) {
+            self.requests.swap_remove(i);
+            return Err(EspError::Timeout);
+        }
+
+        Ok(true)
+    }
+
+    /// Stop tracking a request, e.g. if sending it failed.
+    pub fn cancel(&mut self, uid: u32) -> Option<PendingRequest> {
+        let i = self.requests.iter().position(|r| r.uid == uid)?;
+        Some(self.requests.swap_remove(i))
+    }
+
+    /// The number of outstanding requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resp(msg_id: RpcId, uid: u32) -> Rpc {
+        Rpc {
+            msg_type: RpcType::Resp,
+            msg_id,
+            uid,
+            payload: None,
+        }
+    }
+
+    fn begin<const N: usize>(pending: &mut PendingRequests<N>, msg_id: RpcId, now_ms: u32) -> u32 {
+        pending
+            .begin(msg_id, now_ms)
+            .unwrap_or_else(|_| panic!("table full"))
+    }
+
+    #[test]
+    fn begin_and_match() {
+        let mut pending = PendingRequests::<4>::new(100);
+        let a = begin(&mut pending, RpcId::ReqGetWifiMode, 0);
+        let b = begin(&mut pending, RpcId::ReqGetMacAddress, 0);
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(pending.len(), 2);
+
+        // Wrong UID, wrong response ID, and an event with a matching UID.
+        assert!(
+            pending
+                .on_response(&resp(RpcId::RespGetWifiMode, b))
+                .is_none()
+        );
+        assert!(
+            pending
+                .on_response(&resp(RpcId::RespGetMacAddress, a))
+                .is_none()
+        );
+        let mut event = resp(RpcId::RespGetWifiMode, a);
+        event.msg_type = RpcType::Event;
+        assert!(pending.on_response(&event).is_none());
+
+        let req = pending.on_response(&resp(RpcId::RespGetMacAddress, b));
+        assert!(req.is_some_and(|r| r.uid == b && r.msg_id == RpcId::ReqGetMacAddress));
+
+        // Each request is only matched once.
+        assert!(
+            pending
+                .on_response(&resp(RpcId::RespGetMacAddress, b))
+                .is_none()
+        );
+        assert!(
+            pending
+                .on_response(&resp(RpcId::RespGetWifiMode, a))
+                .is_some()
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn uid_wrap_skips_zero() {
+        let mut pending = PendingRequests::<4>::new(100);
+        pending.next_uid = u32::MAX;
+
+        assert_eq!(begin(&mut pending, RpcId::ReqGetWifiMode, 0), u32::MAX);
+        assert_eq!(begin(&mut pending, RpcId::ReqGetWifiMode, 0), 1);
+    }
+
+    #[test]
+    fn full() {
+        let mut pending = PendingRequests::<2>::new(100);
+        begin(&mut pending, RpcId::ReqGetWifiMode, 0);
+        let uid = begin(&mut pending, RpcId::ReqGetWifiMode, 0);
+
+        assert!(matches!(
+            pending.begin(RpcId::ReqGetWifiMode, 0),
+            Err(EspError::Capacity)
+        ));
+
+        // A UID isn't used up by a failed `begin`.
+        pending.cancel(uid);
+        assert_eq!(begin(&mut pending, RpcId::ReqGetWifiMode, 0), uid + 1);
+    }
+
+    #[test]
+    fn expire() {
+        let mut pending = PendingRequests::<4>::new(100);
+        let a = begin(&mut pending, RpcId::ReqGetWifiMode, 1_000);
+        let b = begin(&mut pending, RpcId::ReqGetWifiMode, 1_050);
+
+        assert!(pending.expire(1_099).is_none());
+        assert!(pending.expire(1_100).is_some_and(|r| r.uid == a));
+        assert!(pending.expire(1_100).is_none());
+        assert!(pending.expire(1_150).is_some_and(|r| r.uid == b));
+        assert!(pending.is_empty());
+
+        // The clock may wrap.
+        let c = begin(&mut pending, RpcId::ReqGetWifiMode, u32::MAX - 10);
+        assert!(pending.expire(50).is_none());
+        assert!(pending.expire(89).is_some_and(|r| r.uid == c));
+    }
+
+    #[test]
+    fn check() {
+        let mut pending = PendingRequests::<4>::new(100);
+        let uid = begin(&mut pending, RpcId::ReqGetWifiMode, 0);
+
+        assert!(matches!(pending.check(uid, 99), Ok(true)));
+        assert!(matches!(pending.check(uid, 100), Err(EspError::Timeout)));
+        // It was removed when it timed out.
+        assert!(matches!(pending.check(uid, 100), Ok(false)));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut pending = PendingRequests::<4>::new(100);
+        let uid = begin(&mut pending, RpcId::ReqGetWifiMode, 0);
+
+        assert!(pending.cancel(uid + 1).is_none());
+        assert!(pending.cancel(uid).is_some_and(|r| r.uid == uid));
+        assert!(pending.is_empty());
+
+        // A response that arrives after cancelling isn't matched.
+        assert!(
+            pending
+                .on_response(&resp(RpcId::RespGetWifiMode, uid))
+                .is_none()
+        );
+    }
+}
@@ -9,16 +9,27 @@ use heapless::Vec;
 use num_enum::TryFromPrimitive;
 
 use crate::{
+    EspError,
+    event::SSID_MAX_LEN,
     rpc::{
-        WireType,
+        FieldVal, Fields, WireType,
         WireType::{Len, Varint},
-        write_rpc,
+        decode_int32, write_rpc, write_rpc_bytes, write_rpc_int32, write_rpc_len,
+    },
+    wifi::{
+        InitConfig, InterfaceType, SaePweMethod, ScanConfig, ScanMethod, SortMethod, WifiAuthMode,
+        WifiCipher,
     },
-    wifi::{InitConfig, ScanConfig},
 };
 
 const MAX_DATA_SIZE: usize = 300; // todo temp
 
+/// WPA passphrases are 8 - 63 characters; a 64-character value is a raw PSK, in hex.
+pub const PASSWORD_MAX_LEN: usize = 64;
+pub const SAE_H2E_IDENTIFIER_MAX_LEN: usize = 32;
+/// The maximum number of stations that can connect to the soft-AP. (`ESP_WIFI_MAX_CONN_NUM`)
+pub const STA_MAX_CONN: usize = 15;
+
 #[derive(Clone, Copy, PartialEq, TryFromPrimitive, Format)]
 #[repr(u16)]
 /// See `esp_hosted_rpc.proto`, enum by this name. This is encoded as a varint.
@@ -245,54 +256,201 @@ pub enum RpcId {
 }
 
 // ---------- WiFi Scan Threshold ----------
-#[derive(Format)]
+/// The minimum signal strength and security of APs to connect to.
+#[derive(Clone, Default, Format)]
 pub struct WifiScanThreshold {
+    /// In dBm. Usually negative. 0 means no minimum.
     pub rssi: i32,
-    pub authmode: i32,
+    pub authmode: WifiAuthMode,
+}
+
+impl WifiScanThreshold {
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc_int32(buf, 1, self.rssi, &mut i);
+        write_rpc_int32(buf, 2, self.authmode as i32, &mut i);
+
+        i
+    }
 }
 
 // ---------- WiFi PMF Config ----------
-#[derive(Format)]
+/// Protected Management Frames (802.11w) settings.
+#[derive(Clone, Default, Format)]
 pub struct WifiPmfConfig {
+    /// Deprecated in ESP-IDF; PMF is always advertised as capable.
     pub capable: bool,
+    /// Only connect to devices that support PMF.
     pub required: bool,
 }
 
+impl WifiPmfConfig {
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc(buf, 1, Varint, self.capable as u64, &mut i);
+        write_rpc(buf, 2, Varint, self.required as u64, &mut i);
+
+        i
+    }
+}
+
 // ---------- WiFi AP Config ----------
-// #[derive(Format)]
+/// Soft-AP configuration. Create with `new`, then adjust other fields as required.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416wifi_ap_config_t)
+#[derive(Clone)]
 pub struct WifiApConfig {
-    pub ssid: Vec<u8, 30>,
-    pub password: Vec<u8, 30>,
-    pub ssid_len: u32,
-    pub channel: u32,
-    pub authmode: i32,
-    pub ssid_hidden: u32,
-    pub max_connection: u32,
-    pub beacon_interval: u32,
-    pub pairwise_cipher: i32,
+    pub ssid: Vec<u8, SSID_MAX_LEN>,
+    /// At least 8 characters, unless `authmode` is `Open`.
+    pub password: Vec<u8, PASSWORD_MAX_LEN>,
+    pub channel: u8,
+    pub authmode: WifiAuthMode,
+    /// Don't broadcast the SSID.
+    pub ssid_hidden: bool,
+    /// The maximum number of stations allowed to connect. Up to `STA_MAX_CONN`.
+    pub max_connection: u8,
+    /// In time units (1.024ms). 100 - 60,000.
+    pub beacon_interval: u16,
+    /// `WifiCipher::None` uses the default for the auth mode.
+    pub pairwise_cipher: WifiCipher,
+    /// Enable the Fine Timing Measurement responder.
     pub ftm_responder: bool,
     pub pmf_cfg: WifiPmfConfig,
-    pub sae_pwe_h2e: i32,
+    pub sae_pwe_h2e: SaePweMethod,
+}
+
+impl Default for WifiApConfig {
+    /// These match ESP-IDF's defaults.
+    fn default() -> Self {
+        Self {
+            ssid: Vec::new(),
+            password: Vec::new(),
+            channel: 1,
+            authmode: WifiAuthMode::Open,
+            ssid_hidden: false,
+            max_connection: 4,
+            beacon_interval: 100,
+            pairwise_cipher: WifiCipher::None,
+            ftm_responder: false,
+            pmf_cfg: Default::default(),
+            sae_pwe_h2e: Default::default(),
+        }
+    }
+}
+
+impl WifiApConfig {
+    /// Uses WPA2-PSK if `password` isn't empty, and an open network otherwise. Returns `EspError::Capacity`
+    /// if the SSID or password is too long, and `EspError::InvalidData` if the password is shorter than 8
+    /// characters.
+    pub fn new(ssid: &[u8], password: &[u8], channel: u8) -> Result<Self, EspError> {
+        if !password.is_empty() && password.len() < 8 {
+            return Err(EspError::InvalidData);
+        }
+
+        let authmode = if password.is_empty() {
+            WifiAuthMode::Open
+        } else {
+            WifiAuthMode::WPA2_PSK
+        };
+
+        Ok(Self {
+            ssid: Vec::from_slice(ssid).map_err(|_| EspError::Capacity)?,
+            password: Vec::from_slice(password).map_err(|_| EspError::Capacity)?,
+            channel,
+            authmode,
+            ..Default::default()
+        })
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc_bytes(buf, 1, &self.ssid, &mut i);
+        write_rpc_bytes(buf, 2, &self.password, &mut i);
+        write_rpc(buf, 3, Varint, self.ssid.len() as u64, &mut i);
+        write_rpc(buf, 4, Varint, self.channel as u64, &mut i);
+        write_rpc_int32(buf, 5, self.authmode as i32, &mut i);
+        write_rpc(buf, 6, Varint, self.ssid_hidden as u64, &mut i);
+        write_rpc(buf, 7, Varint, self.max_connection as u64, &mut i);
+        write_rpc(buf, 8, Varint, self.beacon_interval as u64, &mut i);
+        write_rpc_int32(buf, 9, self.pairwise_cipher as i32, &mut i);
+        write_rpc(buf, 10, Varint, self.ftm_responder as u64, &mut i);
+        write_rpc_len(buf, 11, &mut i, |b| self.pmf_cfg.to_bytes(b));
+        write_rpc_int32(buf, 12, self.sae_pwe_h2e as i32, &mut i);
+
+        i
+    }
 }
 
 // ---------- WiFi STA Config ----------
-// #[derive(Format)]
+/// Station configuration, used to join an AP. Create with `new`, then adjust other fields as required.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv417wifi_sta_config_t)
+#[derive(Clone, Default)]
 pub struct WifiStaConfig {
-    pub ssid: Vec<u8, 30>,
-    pub password: Vec<u8, 30>,
-    pub scan_method: i32,
+    pub ssid: Vec<u8, SSID_MAX_LEN>,
+    /// Empty for open networks.
+    pub password: Vec<u8, PASSWORD_MAX_LEN>,
+    pub scan_method: ScanMethod,
+    /// If true, only connect to the AP with BSSID (MAC address) `bssid`.
     pub bssid_set: bool,
-    pub bssid: Vec<u8, 30>,
-    pub channel: u32,
-    pub listen_interval: u32,
-    pub sort_method: i32,
+    pub bssid: [u8; 6],
+    /// The AP's channel, if known. This speeds up connecting. 0 means unknown.
+    pub channel: u8,
+    /// How often the station wakes to receive beacons, in AP beacon intervals, when power save is
+    /// enabled. 0 means the default of 3.
+    pub listen_interval: u16,
+    /// Used with `ScanMethod::AllChannel`, to pick between several matching APs.
+    pub sort_method: SortMethod,
+    /// APs weaker than, or less secure than this are ignored.
     pub threshold: WifiScanThreshold,
     pub pmf_cfg: WifiPmfConfig,
+    /// Bit 0: Radio measurements. 1: BSS transition management. 2: MBO. 3: Fast BSS transition.
+    /// 4: OWE. 5: Transition disable.
     pub bitmask: u32,
-    pub sae_pwe_h2e: i32,
-    pub failure_retry_cnt: u32,
+    pub sae_pwe_h2e: SaePweMethod,
+    /// The number of connection attempts before moving to the next AP. Requires
+    /// `ScanMethod::AllChannel`.
+    pub failure_retry_cnt: u8,
+    /// 802.11ax settings. See `wifi_sta_config_t`.
     pub he_bitmask: u32,
-    pub sae_h2e_identifier: Vec<u8, 30>,
+    /// The password identifier for SAE H2E. Usually empty.
+    pub sae_h2e_identifier: Vec<u8, SAE_H2E_IDENTIFIER_MAX_LEN>,
+}
+
+impl WifiStaConfig {
+    /// Returns `EspError::Capacity` if the SSID or password is too long.
+    pub fn new(ssid: &[u8], password: &[u8]) -> Result<Self, EspError> {
+        Ok(Self {
+            ssid: Vec::from_slice(ssid).map_err(|_| EspError::Capacity)?,
+            password: Vec::from_slice(password).map_err(|_| EspError::Capacity)?,
+            ..Default::default()
+        })
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc_bytes(buf, 1, &self.ssid, &mut i);
+        write_rpc_bytes(buf, 2, &self.password, &mut i);
+        write_rpc_int32(buf, 3, self.scan_method as i32, &mut i);
+        write_rpc(buf, 4, Varint, self.bssid_set as u64, &mut i);
+        if self.bssid_set {
+            write_rpc_bytes(buf, 5, &self.bssid, &mut i);
+        }
+        write_rpc(buf, 6, Varint, self.channel as u64, &mut i);
+        write_rpc(buf, 7, Varint, self.listen_interval as u64, &mut i);
+        write_rpc_int32(buf, 8, self.sort_method as i32, &mut i);
+        write_rpc_len(buf, 9, &mut i, |b| self.threshold.to_bytes(b));
+        write_rpc_len(buf, 10, &mut i, |b| self.pmf_cfg.to_bytes(b));
+        write_rpc(buf, 11, Varint, self.bitmask as u64, &mut i);
+        write_rpc_int32(buf, 12, self.sae_pwe_h2e as i32, &mut i);
+        write_rpc(buf, 13, Varint, self.failure_retry_cnt as u64, &mut i);
+        write_rpc(buf, 14, Varint, self.he_bitmask as u64, &mut i);
+        write_rpc_bytes(buf, 15, &self.sae_h2e_identifier, &mut i);
+
+        i
+    }
 }
 
 // ---------- WiFi Config (oneof) ----------
@@ -302,19 +460,87 @@ pub enum WifiConfig {
     Sta(WifiStaConfig),
 }
 
+impl WifiConfig {
+    /// The interface this configuration applies to.
+    pub fn interface(&self) -> InterfaceType {
+        match self {
+            Self::Ap(_) => InterfaceType::Ap,
+            Self::Sta(_) => InterfaceType::Station,
+        }
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        match self {
+            Self::Ap(c) => write_rpc_len(buf, 1, &mut i, |b| c.to_bytes(b)),
+            Self::Sta(c) => write_rpc_len(buf, 2, &mut i, |b| c.to_bytes(b)),
+        }
+
+        i
+    }
+}
+
 // ---------- WiFi STA Info ----------
-// #[derive(Format)]
+/// A station connected to our soft-AP.
+#[derive(Clone, Default, Format)]
 pub struct WifiStaInfo {
-    pub mac: Vec<u8, 30>,
-    pub rssi: i32,
+    pub mac: [u8; 6],
+    /// The average RSSI of frames received from this station, in dBm.
+    pub rssi: i8,
+    /// Bit 0: 11b. 1: 11g. 2: 11n. 3: Low rate. 4: 11ax. 5: Mesh child.
     pub bitmask: u32,
 }
 
+impl WifiStaInfo {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Len(v)) => {
+                    result.mac = v.try_into().map_err(|_| EspError::InvalidData)?
+                }
+                (2, FieldVal::Varint(v)) => result.rssi = decode_int32(v) as i8,
+                (3, FieldVal::Varint(v)) => result.bitmask = v as u32,
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 // ---------- WiFi STA List ----------
-// #[derive(Format)]
+/// The stations connected to our soft-AP.
+#[derive(Clone, Default)]
 pub struct WifiStaList {
-    pub sta: Vec<WifiStaInfo, 30>,
-    pub num: i32,
+    pub sta: Vec<WifiStaInfo, STA_MAX_CONN>,
+}
+
+impl WifiStaList {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+        let mut num = None;
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Len(v)) => result
+                    .sta
+                    .push(WifiStaInfo::from_bytes(v)?)
+                    .map_err(|_| EspError::Capacity)?,
+                (2, FieldVal::Varint(v)) => num = Some(decode_int32(v)),
+                _ => (),
+            }
+        }
+
+        // `sta` is a fixed-size array on the ESP; only the first `num` entries are valid.
+        if let Some(n) = num {
+            result.sta.truncate(n.max(0) as usize);
+        }
+
+        Ok(result)
+    }
 }
 
 #[derive(Format)]
@@ -329,13 +555,13 @@ impl RpcReqConfigHeartbeat {
         let mut i = 0;
 
         write_rpc(buf, 1, WireType::Varint, self.enable as u64, &mut i);
-        write_rpc(buf, 2, WireType::Varint, self.duration as u64, &mut i);
+        write_rpc_int32(buf, 2, self.duration, &mut i);
 
         i
     }
 }
 
-#[derive(Format)]
+#[derive(Clone, Copy, Format)]
 pub struct EventHeartbeat {
     /// Number of beats
     pub number: u32,
@@ -349,19 +575,8 @@ pub struct RpcReqWifiInit {
 
 impl RpcReqWifiInit {
     pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
-        let c = &self.cfg;
-        let v = WireType::Varint;
-
-        // Total size came out to 50 for a test case.
-        let mut buf_init_cfg = [0; 80]; // 50 from measurement.
-
-        let cfg_len = self.cfg.to_bytes(&mut buf_init_cfg);
-
         let mut i = 0;
-        write_rpc(buf, 1, WireType::Len, cfg_len as u64, &mut i);
-
-        buf[i..i + cfg_len].copy_from_slice(&buf_init_cfg[..cfg_len]);
-        i += cfg_len;
+        write_rpc_len(buf, 1, &mut i, |b| self.cfg.to_bytes(b));
 
         i
     }
@@ -383,15 +598,10 @@ impl RpcReqWifiScanStart {
     pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
         let mut i = 0;
 
-        let mut cfg_buf = [0; 30]; // Measured 22 with defaults, and 24 with u16s in some fields.
-        let cfg_len = self.config.to_bytes(&mut cfg_buf);
-
-        write_rpc(buf, 1, Len, cfg_len as u64, &mut i);
-        buf[i..i + cfg_len].copy_from_slice(&cfg_buf[..cfg_len]);
-        i += cfg_len;
+        write_rpc_len(buf, 1, &mut i, |b| self.config.to_bytes(b));
 
         write_rpc(buf, 2, Varint, self.block as u64, &mut i);
-        write_rpc(buf, 3, Varint, self.config_set as u64, &mut i);
+        write_rpc_int32(buf, 3, self.config_set, &mut i);
 
         i
     }
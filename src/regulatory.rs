@@ -0,0 +1,200 @@
+//! A table of regulatory domains: The 2.4 and 5 GHz channels, and maximum TX power allowed in each country. Use it to
+//! configure the ESP with `wifi::set_country`, and to validate channels and TX power before sending them, with
+//! `wifi::check_channel` and `wifi::check_tx_power`.
+//!
+//! This covers common markets, and is a convenience, not legal advice; check the rules where you ship. The ESP
+//! supports other country codes directly, with `wifi::set_country_code`.
+//!
+//! ```ignore
+//! let jp = regulatory::find(b"JP").ok_or(EspError::InvalidData)?;
+//! let country = jp.country();
+//! device::set_country(&mut dev, &country)?;
+//!
+//! // Channel 14 is 802.11b-only, and can't be used with HT40.
+//! let protocols = Protocols { p_11g: false, p_11n: false, ..Default::default() };
+//! assert!(jp.allows_protocols(14, &protocols));
+//! device::set_protocol(&mut dev, InterfaceType::Ap, &protocols)?;
+//! device::set_channel(&mut dev, 14, WifiSecondChan::None, Some(&country))?;
+//! device::set_max_tx_power(&mut dev, 18., Some(&country))?;
+//! ```
+
+use defmt::Format;
+
+use crate::wifi::{self, Protocols, WifiBand, WifiCountry, WifiSecondChan};
+
+/// `WifiCountry::policy`: Use the country info from the AP we're connected to, if available.
+pub const POLICY_AUTO: u8 = 0;
+/// `WifiCountry::policy`: Always use the configured country info.
+pub const POLICY_MANUAL: u8 = 1;
+
+/// The channels and TX power allowed in a country, or region.
+#[derive(Clone, Copy, Format)]
+pub struct RegDomain {
+    /// ISO 3166-1 alpha-2 country code, or `01` (world-safe) or `EU`, as used by ESP-IDF.
+    pub cc: [u8; 2],
+    /// The first allowed 2.4 GHz channel.
+    pub schan: u8,
+    /// The number of allowed 2.4 GHz channels.
+    pub nchan: u8,
+    /// The maximum TX power, in dBm.
+    pub max_tx_power: i8,
+    /// The allowed 5 GHz channels, as (first, last) ranges, in steps of 4. This includes DFS channels.
+    pub channels_5g: &'static [(u8, u8)],
+}
+
+impl RegDomain {
+    const fn new(
+        cc: &[u8; 2],
+        nchan: u8,
+        max_tx_power: i8,
+        channels_5g: &'static [(u8, u8)],
+    ) -> Self {
+        Self {
+            cc: *cc,
+            schan: 1,
+            nchan,
+            max_tx_power,
+            channels_5g,
+        }
+    }
+
+    /// The country info to pass to `wifi::set_country`, using `POLICY_MANUAL`.
+    pub fn country(&self) -> WifiCountry {
+        WifiCountry {
+            // The third character is the environment; ' ' means indoor and outdoor.
+            cc: [self.cc[0], self.cc[1], b' '],
+            schan: self.schan,
+            nchan: self.nchan,
+            max_tx_power: self.max_tx_power,
+            policy: POLICY_MANUAL,
+        }
+    }
+
+    /// If a 2.4, or 5 GHz channel is allowed.
+    pub fn allows_channel(&self, channel: u8) -> bool {
+        match wifi::channel_band(channel) {
+            Some(WifiBand::Ghz2) => channel >= self.schan && channel - self.schan < self.nchan,
+            Some(WifiBand::Ghz5) => wifi::in_channel_ranges(channel, self.channels_5g),
+            None => false,
+        }
+    }
+
+    /// If a channel may only be used with 802.11b. This is channel 14, where allowed, e.g. in Japan.
+    pub fn is_11b_only(&self, channel: u8) -> bool {
+        channel == wifi::CHANNEL_11B_ONLY && self.allows_channel(channel)
+    }
+
+    /// If a primary, and secondary channel position are allowed together; see `wifi::check_channel`. HT40 isn't
+    /// allowed on 802.11b-only channels.
+    pub fn allows_ht40(&self, primary: u8, second: WifiSecondChan) -> bool {
+        wifi::check_channel(primary, second, Some(&self.country())).is_ok()
+    }
+
+    /// If `protocols` may be used on a channel. On 802.11b-only channels, only `p_11b` may be set.
+    pub fn allows_protocols(&self, channel: u8, protocols: &Protocols) -> bool {
+        if !self.allows_channel(channel) {
+            return false;
+        }
+
+        // Bit 0 of the bitmap is 802.11b.
+        !self.is_11b_only(channel) || protocols.to_byte() == 0x01
+    }
+}
+
+// 5 GHz channel ranges.
+const UNII_1: (u8, u8) = (36, 48);
+const UNII_1_2: (u8, u8) = (36, 64);
+const UNII_2E: (u8, u8) = (100, 144);
+/// Europe's, and Japan's U-NII-2e stops at 140.
+const UNII_2E_EU: (u8, u8) = (100, 140);
+const UNII_3: (u8, u8) = (149, 165);
+
+const CH_5G_EU: &[(u8, u8)] = &[UNII_1_2, UNII_2E_EU];
+const CH_5G_FULL: &[(u8, u8)] = &[UNII_1_2, UNII_2E, UNII_3];
+/// 120 - 128 are excluded, for weather radar.
+const CH_5G_AU_CA: &[(u8, u8)] = &[UNII_1_2, (100, 116), (132, 144), UNII_3];
+const CH_5G_NO_2E: &[(u8, u8)] = &[UNII_1_2, UNII_3];
+
+/// The world-safe domain: Channels 1 - 11, and 36 - 48. This is the ESP's default.
+pub const WORLD: RegDomain = RegDomain::new(b"01", 11, 20, &[UNII_1]);
+
+/// Known regulatory domains. Channel 14 (Japan) is only allowed with 802.11b.
+pub static DOMAINS: &[RegDomain] = &[
+    WORLD,
+    RegDomain::new(b"AU", 13, 30, CH_5G_AU_CA),
+    RegDomain::new(b"BR", 13, 30, CH_5G_FULL),
+    RegDomain::new(b"CA", 11, 30, CH_5G_AU_CA),
+    RegDomain::new(b"CN", 13, 20, CH_5G_NO_2E),
+    RegDomain::new(b"DE", 13, 20, CH_5G_EU),
+    RegDomain::new(b"EU", 13, 20, CH_5G_EU),
+    RegDomain::new(b"FR", 13, 20, CH_5G_EU),
+    RegDomain::new(b"GB", 13, 20, CH_5G_EU),
+    RegDomain::new(b"IN", 13, 30, CH_5G_NO_2E),
+    RegDomain::new(b"IT", 13, 20, CH_5G_EU),
+    RegDomain::new(b"JP", 14, 20, &[UNII_1_2, UNII_2E_EU]),
+    RegDomain::new(b"KR", 13, 23, CH_5G_FULL),
+    RegDomain::new(b"MX", 11, 30, CH_5G_NO_2E),
+    RegDomain::new(b"NZ", 13, 30, CH_5G_FULL),
+    RegDomain::new(b"TW", 11, 30, CH_5G_FULL),
+    RegDomain::new(b"US", 11, 30, CH_5G_FULL),
+];
+
+/// Find a regulatory domain by country code, e.g. `b"US"`. The code is case-insensitive. A third character,
+/// e.g. from `WifiCountry::cc`, is ignored.
+pub fn find(cc: &[u8]) -> Option<&'static RegDomain> {
+    let cc = cc.get(..2)?;
+    DOMAINS.iter().find(|d| d.cc.eq_ignore_ascii_case(cc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_domain() {
+        assert!(find(b"jp").is_some_and(|d| d.cc == *b"JP"));
+        assert!(find(b"US ").is_some_and(|d| d.cc == *b"US"));
+        assert!(find(b"ZZ").is_none());
+        assert!(find(b"U").is_none());
+    }
+
+    #[test]
+    fn jp_channels() {
+        let jp = find(b"JP").unwrap();
+
+        assert!(jp.allows_channel(1));
+        assert!(jp.allows_channel(14));
+        assert!(!jp.allows_channel(15));
+
+        assert!(jp.allows_channel(36));
+        assert!(jp.allows_channel(140));
+        // Japan's U-NII-2e stops at 140, unlike the US.
+        assert!(!jp.allows_channel(144));
+        assert!(find(b"US").unwrap().allows_channel(144));
+        assert!(!jp.allows_channel(149));
+    }
+
+    #[test]
+    fn channel_14_is_11b_only() {
+        let jp = find(b"JP").unwrap();
+
+        assert!(jp.is_11b_only(14));
+        assert!(!jp.is_11b_only(13));
+        // Channel 14 isn't allowed in the US at all.
+        assert!(!find(b"US").unwrap().is_11b_only(14));
+
+        let b_only = Protocols {
+            p_11g: false,
+            p_11n: false,
+            ..Default::default()
+        };
+        assert!(jp.allows_protocols(14, &b_only));
+        assert!(!jp.allows_protocols(14, &Protocols::default()));
+        assert!(jp.allows_protocols(13, &Protocols::default()));
+
+        assert!(jp.allows_ht40(14, WifiSecondChan::None));
+        assert!(!jp.allows_ht40(14, WifiSecondChan::Below));
+        // The secondary channel would be 14.
+        assert!(!jp.allows_ht40(10, WifiSecondChan::Above));
+    }
+}
@@ -4,21 +4,19 @@
 
 use defmt::{Format, Formatter, println};
 use heapless::Vec;
-use micropb::{MessageEncode, PbEncoder};
+use micropb::{MessageEncode, PbEncoder, PbWrite};
 use num_enum::TryFromPrimitive;
 
 pub use crate::proto_data::RpcId;
 use crate::{
     EspError, RpcP,
     esp_errors::EspCode,
-    header::build_frame_wifi,
+    header::{HEADER_SIZE, build_frame_wifi},
     proto_data::EventHeartbeat,
-    transport::{RPC_EP_NAME_EVT, RPC_EP_NAME_RSP},
-    wifi::WifiApRecord,
+    transport::{RPC_EP_NAME_EVT, RPC_EP_NAME_RSP, Transport},
+    wifi::{MAX_AP_RECORDS, WifiApRecord},
 };
 
-// todo: A/R, or ideally pass in.
-pub(crate) const MAX_RPC_SIZE: usize = 500;
 pub(crate) const RPC_MIN_SIZE: usize = 10;
 
 // #[derive(Format)]
@@ -26,7 +24,7 @@ pub enum RpcPayload {
     EventHeartbeat(EventHeartbeat),
     EventWifiScanGetApRecord(WifiApRecord),
     // todo: Setting up a static buf here may be trouble.
-    EventWifiScanGetApRecords(Vec<WifiApRecord, 30>),
+    EventWifiScanGetApRecords(Vec<WifiApRecord, MAX_AP_RECORDS>),
 }
 
 impl Format for RpcPayload {
@@ -70,9 +68,7 @@ impl Rpc {
         let mut i = 0;
         let data_len = data.len();
 
-        write_rpc(buf, 1, WireType::Varint, self.msg_type as u64, &mut i);
-        write_rpc(buf, 2, WireType::Varint, self.msg_id as u64, &mut i);
-        write_rpc(buf, 3, WireType::Varint, self.uid as u64, &mut i);
+        self.write_fields(buf, &mut i);
 
         // We repeat the message id as the payload's tag field.
         // Note: When using length-determined, we must follow the tag with a varint len.
@@ -91,56 +87,75 @@ impl Rpc {
         i
     }
 
-    /// Returns (Self, data start i, data len expected).
+    /// Write the fields preceding the payload: Message type, ID, and UID.
+    fn write_fields(&self, buf: &mut [u8], i: &mut usize) {
+        write_rpc(buf, 1, WireType::Varint, self.msg_type as u64, i);
+        write_rpc(buf, 2, WireType::Varint, self.msg_id as u64, i);
+        write_rpc(buf, 3, WireType::Varint, self.uid as u64, i);
+    }
+
+    /// Returns (Self, data start i, data len). The data is the payload message, e.g. `Rpc_Resp_GetMode`; it's
+    /// the field with a number matching `msg_id`. Fields may be in any order, and unknown ones are skipped.
+    ///
+    /// Note: Error codes from the ESP are in the payload's `resp` field; they're not checked here.
     pub fn from_bytes(buf: &[u8]) -> Result<(Self, usize, usize), EspError> {
-        // Skipping msg type tag at byte 0.
-        if buf.len() < 2 {
-            return Err(EspError::InvalidData);
+        let mut msg_type = None;
+        let mut msg_id = None;
+        let mut uid = 0; // Default if the UID is missing. We observe this for events.
+        // (field number, start i, len)
+        let mut payload = None;
+
+        let mut fields = Fields::new(buf);
+        while let Some(field) = fields.next() {
+            match field? {
+                (1, FieldVal::Varint(v)) => {
+                    msg_type = Some(
+                        u8::try_from(v)
+                            .ok()
+                            .and_then(|v| RpcType::try_from(v).ok())
+                            .ok_or(EspError::InvalidData)?,
+                    );
+                }
+                (2, FieldVal::Varint(v)) => {
+                    msg_id = Some(
+                        u16::try_from(v)
+                            .ok()
+                            .and_then(|v| RpcId::try_from(v).ok())
+                            .ok_or(EspError::InvalidData)?,
+                    );
+                }
+                (3, FieldVal::Varint(v)) => uid = v as u32,
+                // Payload field numbers are RPC IDs.
+                (field, FieldVal::Len(v)) if field >= RpcId::ReqBase as u16 => {
+                    payload = Some((field, fields.pos() - v.len(), v.len()));
+                }
+                _ => (),
+            }
         }
-        let msg_type = buf[1].try_into().map_err(|_| EspError::InvalidData)?;
-
-        let (rpc_id, rpc_id_size) = decode_varint(&buf[3..])?;
-        let msg_id = (rpc_id as u16)
-            .try_into()
-            .map_err(|_| EspError::InvalidData)?;
 
-        let mut i = 3 + rpc_id_size;
+        let (Some(msg_type), Some(msg_id)) = (msg_type, msg_id) else {
+            return Err(EspError::InvalidData);
+        };
 
-        let mut uid = 0; // Default if the UID is missing. We observe this for events.
-        // This is a bit fragile, but works for now.
-        if buf[3 + rpc_id_size] == 16 {
-            // UID present
-            i += 1; // Skip the tag.
-            let (uid_, uid_size) = decode_varint(&buf[i..])?;
-            uid = uid_;
-            i += uid_size;
-        }
+        let (data_start, data_len) = match payload {
+            Some((field, start, len)) => {
+                if field != msg_id as u16 {
+                    return Err(EspError::InvalidData);
+                }
+                (start, len)
+            }
+            // An empty payload.
+            None => (buf.len(), 0),
+        };
 
         let result = Self {
             msg_type,
             msg_id,
-            uid: uid as u32,
+            uid,
             payload: None, // todo: Update this?
         };
 
-        let (_data_tag, data_tag_size) = decode_varint(&buf[i..])?;
-        i += data_tag_size;
-
-        let (data_len, data_len_size) = decode_varint(&buf[i..])?;
-        i += data_len_size;
-
-        // Check for an ESP error code.
-        if data_len == 3 && buf[i] == 8 {
-            let (err_code, _) = decode_varint(&buf[i + 1..])?;
-            match EspCode::try_from(err_code as u16) {
-                Ok(c) => {
-                    return Err(EspError::Esp(c));
-                }
-                Err(_) => (),
-            }
-        }
-
-        Ok((result, i, data_len as usize))
+        Ok((result, data_start, data_len))
     }
 }
 
@@ -168,15 +183,6 @@ pub(crate) enum Rpc_WifiBw {
     HT40 = 2,
 }
 
-#[derive(Clone, Copy, PartialEq, TryFromPrimitive, Format)]
-#[repr(u8)]
-/// See `esp_hosted_rpc.proto`, enum by this name.
-pub(crate) enum Rpc_WifiPowerSave {
-    PS_Invalid = 0,
-    MIN_MODEM = 1,
-    MAX_MODEM = 2,
-}
-
 #[derive(Clone, Copy, PartialEq, TryFromPrimitive, Format)]
 #[repr(u8)]
 /// See `esp_hosted_rpc.proto`, enum by this name.
@@ -251,33 +257,78 @@ impl RpcEndpoint {
 /// returns the total payload size after setup. (Including PL header, TLV, RPC). This function is mainly
 /// used internally by our higher-level API.
 pub fn setup_rpc(buf: &mut [u8], rpc: &Rpc, data: &[u8]) -> usize {
-    // todo: I don't like how we need to specify another size constraint here, in addition to the frame buf.
-    let mut rpc_buf = [0; MAX_RPC_SIZE];
+    setup_rpc_in_place(buf, rpc, |buf| {
+        buf[..data.len()].copy_from_slice(data);
+        data.len()
+    })
+}
+
+/// Like `setup_rpc`, but `write_data` encodes the RPC payload directly into the frame buffer,
+/// and returns its size. This avoids an intermediate buffer, and copy. The payload header and TLV are
+/// filled in after, in space reserved at the start of the buffer.
+pub fn setup_rpc_in_place(
+    buf: &mut [u8],
+    rpc: &Rpc,
+    write_data: impl FnOnce(&mut [u8]) -> usize,
+) -> usize {
+    let mut i = HEADER_SIZE;
+
+    rpc.write_fields(buf, &mut i);
+    // We repeat the message id as the payload's tag field.
+    write_rpc_len(buf, rpc.msg_id as u16, &mut i, write_data);
+
+    build_frame_wifi(buf, i - HEADER_SIZE)
+}
 
-    let mut i = 0;
-    i += rpc.to_bytes(&mut rpc_buf, data);
+/// Lets micropb encode directly into a frame buffer.
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl PbWrite for SliceWriter<'_> {
+    type Error = EspError;
+
+    fn pb_write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(EspError::Capacity);
+        }
+
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
 
-    build_frame_wifi(buf, &rpc_buf[..i])
+        Ok(())
+    }
 }
 
 /// Sets up an RPC command to write using the automatic protbuf decoding from micropb. This is flexible, and
 /// allows writing arbitrary commands, but its interface isn't as streamlined as our native impl.
 pub fn setup_rpc_proto(buf: &mut [u8], message: RpcP) -> Result<usize, EspError> {
-    let mut rpc_buf = Vec::<u8, MAX_RPC_SIZE>::new();
+    if buf.len() < HEADER_SIZE {
+        return Err(EspError::Capacity);
+    }
+
+    let mut writer = SliceWriter {
+        buf: &mut buf[HEADER_SIZE..],
+        len: 0,
+    };
 
-    let mut encoder = PbEncoder::new(&mut rpc_buf);
+    let mut encoder = PbEncoder::new(&mut writer);
     message.encode(&mut encoder).map_err(|_| EspError::Proto)?;
 
-    Ok(build_frame_wifi(buf, &rpc_buf[..rpc_buf.len()]))
+    let payload_len = writer.len;
+    Ok(build_frame_wifi(buf, payload_len))
 }
 
 /// Write an automatically-decoded protobuf message directly.
-pub fn write_rpc_proto<W>(buf: &mut [u8], mut write: W, msg: RpcP) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+pub fn write_rpc_proto<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    msg: RpcP,
+) -> Result<(), EspError> {
     let frame_len = setup_rpc_proto(buf, msg)?;
-    write(&buf[..frame_len])?;
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
@@ -290,6 +341,71 @@ pub(crate) fn write_rpc(buf: &mut [u8], field: u16, wire_type: WireType, val: u6
     *i += encode_varint(val, &mut buf[*i..]);
 }
 
+/// The space we reserve for a length-delimited field's length, before its contents are written.
+/// This allows lengths up to 2^21.
+const LEN_RESERVED: usize = 3;
+
+/// Writes a length-delimited field, e.g. a sub-message, in place. `write` writes the contents to the buffer
+/// passed, and returns their size. Space is reserved for the length, which is written afterwards; the contents are
+/// shifted back if the length's varint is shorter than the space reserved. Increments the index.
+pub(crate) fn write_rpc_len(
+    buf: &mut [u8],
+    field: u16,
+    i: &mut usize,
+    write: impl FnOnce(&mut [u8]) -> usize,
+) {
+    let tag = encode_tag(field, WireType::Len);
+    *i += encode_varint(tag as u64, &mut buf[*i..]);
+
+    let len_start = *i;
+    let contents_start = len_start + LEN_RESERVED;
+
+    let len = write(&mut buf[contents_start..]);
+
+    let len_size = encode_varint(len as u64, &mut buf[len_start..contents_start]);
+    buf.copy_within(contents_start..contents_start + len, len_start + len_size);
+
+    *i = len_start + len_size + len;
+}
+
+/// Writes a `bytes` or `string` field. Increments the index.
+pub(crate) fn write_rpc_bytes(buf: &mut [u8], field: u16, val: &[u8], i: &mut usize) {
+    write_rpc(buf, field, WireType::Len, val.len() as u64, i);
+    buf[*i..*i + val.len()].copy_from_slice(val);
+    *i += val.len();
+}
+
+/// Writes an `int32` field. Increments the index.
+pub(crate) fn write_rpc_int32(buf: &mut [u8], field: u16, val: i32, i: &mut usize) {
+    write_rpc(buf, field, WireType::Varint, encode_int32(val), i);
+}
+
+/// Writes a `sint32` field. Increments the index.
+pub(crate) fn write_rpc_sint32(buf: &mut [u8], field: u16, val: i32, i: &mut usize) {
+    write_rpc(buf, field, WireType::Varint, encode_sint32(val), i);
+}
+
+/// `int32` values are sign-extended to 64 bits before varint encoding, so negative values
+/// take 10 bytes.
+pub(crate) fn encode_int32(v: i32) -> u64 {
+    v as i64 as u64
+}
+
+/// Decodes an `int32` from its varint value. Negative values were sign-extended, so we truncate.
+pub(crate) fn decode_int32(v: u64) -> i32 {
+    v as i32
+}
+
+/// `sint32` values use ZigZag encoding: 0, -1, 1, -2 etc map to 0, 1, 2, 3.
+pub(crate) fn encode_sint32(v: i32) -> u64 {
+    ((v << 1) ^ (v >> 31)) as u32 as u64
+}
+
+pub(crate) fn decode_sint32(v: u64) -> i32 {
+    let v = v as u32;
+    ((v >> 1) as i32) ^ -((v & 1) as i32)
+}
+
 /// Used in a few places when setting up RPC.
 pub(crate) fn encode_tag(field: u16, wire_type: WireType) -> u16 {
     (field << 3) | (wire_type as u16)
@@ -342,3 +458,419 @@ pub(crate) fn decode_varint(input: &[u8]) -> Result<(u64, usize), EspError> {
 
     Err(EspError::InvalidData)
 }
+
+/// The value of a single protobuf field.
+#[derive(Clone, Copy)]
+pub(crate) enum FieldVal<'a> {
+    Varint(u64),
+    I64(u64),
+    Len(&'a [u8]),
+    I32(u32),
+}
+
+/// Iterates over the fields of a protobuf message, e.g. an RPC payload. Yields (field number, value).
+pub(crate) struct Fields<'a> {
+    buf: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, i: 0 }
+    }
+
+    /// The index following the most recent field.
+    pub fn pos(&self) -> usize {
+        self.i
+    }
+
+    fn next_field(&mut self) -> Result<(u16, FieldVal<'a>), EspError> {
+        let buf = self.buf;
+
+        let (tag, tag_len) = decode_varint(&buf[self.i..])?;
+        self.i += tag_len;
+
+        let field = (tag >> 3) as u16;
+        let wire_type: WireType = ((tag & 0b111) as u8)
+            .try_into()
+            .map_err(|_| EspError::InvalidData)?;
+
+        let val = match wire_type {
+            WireType::Varint => {
+                let (v, len) = decode_varint(&buf[self.i..])?;
+                self.i += len;
+                FieldVal::Varint(v)
+            }
+            WireType::Len => {
+                let (len, len_len) = decode_varint(&buf[self.i..])?;
+                self.i += len_len;
+
+                let end = self.i + len as usize;
+                if end > buf.len() {
+                    return Err(EspError::InvalidData);
+                }
+
+                let v = &buf[self.i..end];
+                self.i = end;
+                FieldVal::Len(v)
+            }
+            WireType::I64 => {
+                if self.i + 8 > buf.len() {
+                    return Err(EspError::InvalidData);
+                }
+                let v = u64::from_le_bytes(buf[self.i..self.i + 8].try_into().unwrap());
+                self.i += 8;
+                FieldVal::I64(v)
+            }
+            WireType::I32 => {
+                if self.i + 4 > buf.len() {
+                    return Err(EspError::InvalidData);
+                }
+                let v = u32::from_le_bytes(buf[self.i..self.i + 4].try_into().unwrap());
+                self.i += 4;
+                FieldVal::I32(v)
+            }
+        };
+
+        Ok((field, val))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u16, FieldVal<'a>), EspError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.i >= self.buf.len() {
+            return None;
+        }
+
+        let result = self.next_field();
+        if result.is_err() {
+            // Don't attempt to continue past malformed data.
+            self.i = self.buf.len();
+        }
+
+        Some(result)
+    }
+}
+
+/// Convert the `resp` field of an RPC response, which is an `esp_err_t`, into a `Result`.
+pub(crate) fn check_resp(resp: u64) -> Result<(), EspError> {
+    // `esp_err_t` is an `int32`; `ESP_FAIL` (-1) is encoded as a 10-byte varint.
+    let code = decode_int32(resp);
+    if code == 0 {
+        return Ok(());
+    }
+
+    Err(EspError::Esp(
+        EspCode::try_from(code as u16).unwrap_or(EspCode::ESP_FAIL),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects the fields of a message, for comparison. `EspError` doesn't implement `Debug`.
+    fn fields(buf: &[u8]) -> heapless::Vec<(u16, FieldVal<'_>), 8> {
+        Fields::new(buf)
+            .map(|f| f.unwrap_or_else(|_| panic!("malformed field")))
+            .collect()
+    }
+
+    impl PartialEq for FieldVal<'_> {
+        fn eq(&self, other: &Self) -> bool {
+            match (self, other) {
+                (Self::Varint(a), Self::Varint(b)) => a == b,
+                (Self::I64(a), Self::I64(b)) => a == b,
+                (Self::Len(a), Self::Len(b)) => a == b,
+                (Self::I32(a), Self::I32(b)) => a == b,
+                _ => false,
+            }
+        }
+    }
+
+    impl core::fmt::Debug for FieldVal<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self {
+                Self::Varint(v) => write!(f, "Varint({v})"),
+                Self::I64(v) => write!(f, "I64({v})"),
+                Self::Len(v) => write!(f, "Len({v:?})"),
+                Self::I32(v) => write!(f, "I32({v})"),
+            }
+        }
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        let mut buf = [0; 64];
+        let mut i = 0;
+
+        write_rpc(&mut buf, 1, WireType::Varint, 300, &mut i);
+        write_rpc_int32(&mut buf, 2, -5, &mut i);
+        write_rpc_bytes(&mut buf, 3, b"ssid", &mut i);
+        // Field numbers above 15 take a 2-byte tag.
+        write_rpc(&mut buf, 300, WireType::Varint, 1, &mut i);
+
+        buf[i] = encode_tag(4, WireType::I32) as u8;
+        buf[i + 1..i + 5].copy_from_slice(&0x1234_5678_u32.to_le_bytes());
+        i += 5;
+
+        buf[i] = encode_tag(5, WireType::I64) as u8;
+        buf[i + 1..i + 9].copy_from_slice(&u64::MAX.to_le_bytes());
+        i += 9;
+
+        let f = fields(&buf[..i]);
+        assert_eq!(
+            f[..],
+            [
+                (1, FieldVal::Varint(300)),
+                (2, FieldVal::Varint(encode_int32(-5))),
+                (3, FieldVal::Len(b"ssid")),
+                (300, FieldVal::Varint(1)),
+                (4, FieldVal::I32(0x1234_5678)),
+                (5, FieldVal::I64(u64::MAX)),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_malformed() {
+        // A length-delimited field that runs past the end of the buffer.
+        let buf = [encode_tag(1, WireType::Len) as u8, 5, 1, 2];
+        let mut f = Fields::new(&buf);
+        assert!(matches!(f.next(), Some(Err(EspError::InvalidData))));
+        assert!(f.next().is_none());
+
+        // An invalid wire type.
+        let buf = [(1 << 3) | 3, 0];
+        assert!(matches!(
+            Fields::new(&buf).next(),
+            Some(Err(EspError::InvalidData))
+        ));
+    }
+
+    #[test]
+    fn rpc_round_trip() {
+        let mut buf = [0; 64];
+        let data = [0x08, 0x01];
+
+        let rpc = Rpc::new_req(RpcId::ReqGetMacAddress, 0x1_0000);
+        let len = rpc.to_bytes(&mut buf, &data);
+
+        let Ok((parsed, start, data_len)) = Rpc::from_bytes(&buf[..len]) else {
+            panic!("failed to parse");
+        };
+        assert!(parsed.msg_type == RpcType::Req);
+        assert!(parsed.msg_id == RpcId::ReqGetMacAddress);
+        assert_eq!(parsed.uid, 0x1_0000);
+        assert_eq!(buf[start..start + data_len], data);
+
+        // An empty payload.
+        let len = rpc.to_bytes(&mut buf, &[]);
+        let Ok((_, start, data_len)) = Rpc::from_bytes(&buf[..len]) else {
+            panic!("failed to parse");
+        };
+        assert_eq!(data_len, 0);
+        assert!(start <= len);
+    }
+
+    #[test]
+    fn rpc_from_bytes_field_order() {
+        // Events have no UID, and fields may be in any order, with unknown ones skipped.
+        let mut buf = [0; 32];
+        let mut i = 0;
+        write_rpc_bytes(
+            &mut buf,
+            RpcId::EventHeartbeat as u16,
+            &[0x08, 0x05],
+            &mut i,
+        );
+        write_rpc(&mut buf, 9, WireType::Varint, 42, &mut i);
+        write_rpc(
+            &mut buf,
+            2,
+            WireType::Varint,
+            RpcId::EventHeartbeat as u64,
+            &mut i,
+        );
+        write_rpc(&mut buf, 1, WireType::Varint, RpcType::Event as u64, &mut i);
+
+        let Ok((parsed, start, data_len)) = Rpc::from_bytes(&buf[..i]) else {
+            panic!("failed to parse");
+        };
+        assert!(parsed.msg_type == RpcType::Event);
+        assert!(parsed.msg_id == RpcId::EventHeartbeat);
+        assert_eq!(parsed.uid, 0);
+        assert_eq!(buf[start..start + data_len], [0x08, 0x05]);
+    }
+
+    #[test]
+    fn rpc_from_bytes_invalid() {
+        let mut buf = [0; 32];
+
+        // The payload's field number doesn't match the message ID.
+        let mut i = 0;
+        write_rpc(&mut buf, 1, WireType::Varint, RpcType::Resp as u64, &mut i);
+        write_rpc(
+            &mut buf,
+            2,
+            WireType::Varint,
+            RpcId::RespGetMacAddress as u64,
+            &mut i,
+        );
+        write_rpc_bytes(&mut buf, RpcId::ReqGetMacAddress as u16, &[1], &mut i);
+        assert!(matches!(
+            Rpc::from_bytes(&buf[..i]),
+            Err(EspError::InvalidData)
+        ));
+
+        // No message ID.
+        let mut i = 0;
+        write_rpc(&mut buf, 1, WireType::Varint, RpcType::Resp as u64, &mut i);
+        assert!(matches!(
+            Rpc::from_bytes(&buf[..i]),
+            Err(EspError::InvalidData)
+        ));
+
+        // An unknown message type.
+        let mut i = 0;
+        write_rpc(&mut buf, 1, WireType::Varint, 99, &mut i);
+        write_rpc(
+            &mut buf,
+            2,
+            WireType::Varint,
+            RpcId::RespGetMacAddress as u64,
+            &mut i,
+        );
+        assert!(matches!(
+            Rpc::from_bytes(&buf[..i]),
+            Err(EspError::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn write_rpc_len_round_trip() {
+        let mut buf = [0; 400];
+
+        // Short and long contents: 1 and 2-byte lengths, both shorter than the space reserved.
+        for len in [0, 5, 127, 128, 300] {
+            let mut i = 0;
+            write_rpc(&mut buf, 1, WireType::Varint, 7, &mut i);
+            write_rpc_len(&mut buf, 2, &mut i, |b| {
+                for (j, v) in b[..len].iter_mut().enumerate() {
+                    *v = j as u8;
+                }
+                len
+            });
+            write_rpc(&mut buf, 3, WireType::Varint, 9, &mut i);
+
+            let f = fields(&buf[..i]);
+            assert_eq!(f.len(), 3);
+            assert_eq!(f[0], (1, FieldVal::Varint(7)));
+            assert_eq!(f[2], (3, FieldVal::Varint(9)));
+
+            let (2, FieldVal::Len(contents)) = f[1] else {
+                panic!("expected field 2");
+            };
+            assert_eq!(contents.len(), len);
+            assert!(contents.iter().enumerate().all(|(j, v)| *v == j as u8));
+        }
+    }
+
+    #[test]
+    fn write_rpc_len_nested() {
+        let mut buf = [0; 32];
+        let mut i = 0;
+
+        write_rpc_len(&mut buf, 1, &mut i, |b| {
+            let mut j = 0;
+            write_rpc_bytes(b, 1, b"abc", &mut j);
+            write_rpc(b, 2, WireType::Varint, 1, &mut j);
+            j
+        });
+
+        let outer = fields(&buf[..i]);
+        let (1, FieldVal::Len(inner)) = outer[0] else {
+            panic!("expected field 1");
+        };
+        assert_eq!(
+            fields(inner)[..],
+            [(1, FieldVal::Len(b"abc")), (2, FieldVal::Varint(1))]
+        );
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut buf = [0; 10];
+
+        for (v, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (300, 2),
+            (16_383, 2),
+            (16_384, 3),
+        ] {
+            assert_eq!(encode_varint(v, &mut buf), len);
+            assert!(matches!(decode_varint(&buf), Ok((d, l)) if d == v && l == len));
+        }
+
+        // `int32` -1 is sign-extended, so takes 10 bytes.
+        assert_eq!(encode_varint(encode_int32(-1), &mut buf), 10);
+        let (v, _) = decode_varint(&buf).unwrap_or_else(|_| panic!());
+        assert_eq!(decode_int32(v), -1);
+
+        // A truncated varint.
+        assert!(decode_varint(&[0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn int32_round_trip() {
+        let mut buf = [0; 32];
+
+        for v in [0, 1, -1, 150, -150, i32::MIN, i32::MAX] {
+            let mut i = 0;
+            write_rpc_int32(&mut buf, 1, v, &mut i);
+            write_rpc_sint32(&mut buf, 2, v, &mut i);
+
+            let f = fields(&buf[..i]);
+            let (1, FieldVal::Varint(a)) = f[0] else {
+                panic!("expected field 1");
+            };
+            let (2, FieldVal::Varint(b)) = f[1] else {
+                panic!("expected field 2");
+            };
+            assert_eq!(decode_int32(a), v);
+            assert_eq!(decode_sint32(b), v);
+        }
+    }
+
+    #[test]
+    fn sint32_zigzag() {
+        for (v, encoded) in [
+            (0, 0),
+            (-1, 1),
+            (1, 2),
+            (-2, 3),
+            (i32::MAX, 0xFFFF_FFFE),
+            (i32::MIN, 0xFFFF_FFFF),
+        ] {
+            assert_eq!(encode_sint32(v), encoded);
+            assert_eq!(decode_sint32(encoded), v);
+        }
+
+        // Unlike `int32`, small negative values stay short.
+        let mut buf = [0; 10];
+        assert_eq!(encode_varint(encode_sint32(-64), &mut buf), 1);
+        assert_eq!(encode_varint(encode_sint32(i32::MIN), &mut buf), 5);
+    }
+    #[test]
+    fn check_resp_codes() {
+        assert!(check_resp(0).is_ok());
+        assert!(matches!(
+            check_resp(encode_int32(-1)),
+            Err(EspError::Esp(EspCode::ESP_FAIL))
+        ));
+    }
+}
@@ -0,0 +1,453 @@
+//! Runs a complete scan cycle, and caches the results. This starts the scan, and when `EventStaScanDone` arrives,
+//! reads the AP records, and frees the ESP's AP list afterwards, even if reading fails. Results are deduplicated by
+//! BSSID, and sorted by RSSI, strongest first.
+//!
+//! The scan doesn't block: Pass events to `on_event`, and call `poll` periodically. This uses the blocking
+//! call-style API in the `device` module for each request, but doesn't wait for the scan itself.
+//!
+//! ```ignore
+//! let mut scanner = ScanManager::<16>::new();
+//! scanner.start(&mut dev, &ScanConfig::default())?;
+//!
+//! // Or, scan in the background, every 30s:
+//! scanner.interval_ms = Some(30_000);
+//!
+//! loop {
+//!     // Pass each event received. `EspHosted` queues events that arrive during calls.
+//!     dev.poll()?;
+//!     while let Some(event) = dev.next_event() {
+//!         scanner.on_event(&event);
+//!     }
+//!
+//!     if scanner.poll(&mut dev, &ScanConfig::default())? {
+//!         for result in scanner.results() {
+//!             println!("{:?}: {} dBm", result.record.ssid, result.record.rssi);
+//!         }
+//!     }
+//! }
+//! ```
+
+use core::cmp::Reverse;
+
+use heapless::Vec;
+
+use crate::{
+    EspError, EspHosted, device,
+    event::Event,
+    proto_data::RpcReqWifiScanStart,
+    transport::Transport,
+    wifi::{ScanConfig, WifiApRecord},
+};
+
+/// If `EventStaScanDone` doesn't arrive this long after starting a scan, in ms, the scan is abandoned. A passive
+/// scan of all channels can take several seconds.
+pub const SCAN_TIMEOUT_MS: u32 = 15_000;
+
+/// Results not seen in a scan for this long are removed, by default.
+pub const DEFAULT_MAX_AGE_MS: u32 = 120_000;
+
+/// If the ESP found at most this many APs, we read them with a single `scan_get_ap_records` call. Otherwise, we
+/// read them one at a time, so each response fits in a frame. (`scan_get_ap_records` frees the whole list, so it
+/// can't be paged.)
+const RECORDS_PER_CALL: u16 = 10;
+
+/// An AP found by a scan.
+#[derive(Clone)]
+pub struct ScanResult {
+    pub record: WifiApRecord,
+    /// The time of the last scan this AP was found in, in ms.
+    pub last_seen_ms: u32,
+}
+
+/// Runs scans, and caches up to `N` results. When the cache is full, the weakest APs are dropped.
+pub struct ScanManager<const N: usize> {
+    results: Vec<ScanResult, N>,
+    /// The time between background scans started by `poll`, in ms. `None` disables background scanning.
+    pub interval_ms: Option<u32>,
+    /// Results not seen in a scan for this long, in ms, are removed.
+    pub max_age_ms: u32,
+    last_scan_ms: Option<u32>,
+    /// When the scan in progress started, in ms.
+    started_ms: Option<u32>,
+    /// Set by `on_event` when the scan in progress completes; its records are read by the next `poll`.
+    done: bool,
+}
+
+impl<const N: usize> Default for ScanManager<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ScanManager<N> {
+    pub const fn new() -> Self {
+        Self {
+            results: Vec::new(),
+            interval_ms: None,
+            max_age_ms: DEFAULT_MAX_AGE_MS,
+            last_scan_ms: None,
+            started_ms: None,
+            done: false,
+        }
+    }
+
+    /// Start a scan, if one isn't in progress. This returns once the ESP has started it; the results are read by
+    /// `poll`, after `EventStaScanDone` is passed to `on_event`. Wi-Fi must be started, in station, or station +
+    /// soft-AP mode.
+    pub fn start<T: Transport>(
+        &mut self,
+        dev: &mut EspHosted<T>,
+        cfg: &ScanConfig,
+    ) -> Result<(), EspError> {
+        if self.is_scanning() {
+            return Ok(());
+        }
+
+        let scan_start = RpcReqWifiScanStart {
+            config: cfg.clone(),
+            block: false,
+            config_set: 1,
+        };
+
+        device::scan_start(dev, &scan_start)?;
+
+        self.started_ms = Some(dev.now_ms());
+        self.done = false;
+        Ok(())
+    }
+
+    /// If a scan is in progress, or its records haven't been read yet.
+    pub fn is_scanning(&self) -> bool {
+        self.started_ms.is_some()
+    }
+
+    /// Update from an event. Returns `true` if a scan completed; call `poll` to read its records. `EspInit`
+    /// abandons a scan in progress, since the ESP has restarted.
+    pub fn on_event(&mut self, event: &Event) -> bool {
+        match event {
+            Event::StaScanDone(_) if self.is_scanning() => {
+                self.done = true;
+                true
+            }
+            Event::EspInit => {
+                self.started_ms = None;
+                self.done = false;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Call this periodically. If a scan has completed, read its records, and add the APs found to the results;
+    /// returns `Ok(true)` if so. If background scanning is enabled, and `interval_ms` has elapsed since the last
+    /// scan, start one. If `EventStaScanDone` doesn't arrive within `SCAN_TIMEOUT_MS`, the scan is abandoned, and
+    /// `EspError::Timeout` returned.
+    pub fn poll<T: Transport>(
+        &mut self,
+        dev: &mut EspHosted<T>,
+        cfg: &ScanConfig,
+    ) -> Result<bool, EspError> {
+        let now_ms = dev.now_ms();
+
+        if let Some(started_ms) = self.started_ms {
+            if self.done {
+                self.finish(dev, now_ms)?;
+                return Ok(true);
+            }
+
+            if now_ms.wrapping_sub(started_ms) >= SCAN_TIMEOUT_MS {
+                self.started_ms = None;
+                // Free anything the ESP found, since we won't read it. The timeout is the error we report.
+                device::clear_ap_list(dev).ok();
+                return Err(EspError::Timeout);
+            }
+
+            return Ok(false);
+        }
+
+        let Some(interval_ms) = self.interval_ms else {
+            return Ok(false);
+        };
+
+        if let Some(last) = self.last_scan_ms
+            && now_ms.wrapping_sub(last) < interval_ms
+        {
+            return Ok(false);
+        }
+
+        self.start(dev, cfg)?;
+        Ok(false)
+    }
+
+    /// Read the completed scan's records, and free the ESP's AP list.
+    fn finish<T: Transport>(
+        &mut self,
+        dev: &mut EspHosted<T>,
+        now_ms: u32,
+    ) -> Result<(), EspError> {
+        self.started_ms = None;
+        self.done = false;
+        self.last_scan_ms = Some(now_ms);
+
+        let read = self.read_records(dev, now_ms);
+        // If we don't clear the list after a failed read, its memory leaks on the ESP. If both fail, we return the
+        // read's error, since it happened first.
+        let cleared = device::clear_ap_list(dev);
+
+        self.expire(now_ms);
+        read.and(cleared)
+    }
+
+    fn read_records<T: Transport>(
+        &mut self,
+        dev: &mut EspHosted<T>,
+        now_ms: u32,
+    ) -> Result<(), EspError> {
+        let num = device::scan_get_ap_num(dev)?;
+
+        if num == 0 {
+            return Ok(());
+        }
+
+        if num <= RECORDS_PER_CALL {
+            for record in device::scan_get_ap_records(dev, num as u8)? {
+                self.insert(record, now_ms);
+            }
+        } else {
+            for _ in 0..num {
+                let record = device::scan_get_ap_record(dev)?;
+                self.insert(record, now_ms);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add or update a result, keeping the results sorted.
+    pub(crate) fn insert(&mut self, record: WifiApRecord, now_ms: u32) {
+        let result = ScanResult {
+            record,
+            last_seen_ms: now_ms,
+        };
+
+        match self
+            .results
+            .iter_mut()
+            .find(|r| r.record.bssid == result.record.bssid)
+        {
+            Some(existing) => *existing = result,
+            None => {
+                if let Err(result) = self.results.push(result) {
+                    // The results are sorted, so the last is the weakest.
+                    match self.results.last_mut() {
+                        Some(weakest) if weakest.record.rssi < result.record.rssi => {
+                            *weakest = result
+                        }
+                        _ => return,
+                    }
+                }
+            }
+        }
+
+        self.results
+            .sort_unstable_by_key(|r| Reverse(r.record.rssi));
+    }
+
+    /// Remove results that haven't been seen for `max_age_ms`.
+    fn expire(&mut self, now_ms: u32) {
+        let max_age_ms = self.max_age_ms;
+        self.results
+            .retain(|r| now_ms.wrapping_sub(r.last_seen_ms) < max_age_ms);
+    }
+
+    /// The cached results, strongest first.
+    pub fn results(&self) -> &[ScanResult] {
+        &self.results
+    }
+
+    /// The strongest AP with a given SSID, if one has been found.
+    pub fn find_ssid(&self, ssid: &[u8]) -> Option<&ScanResult> {
+        self.results.iter().find(|r| r.record.ssid == ssid)
+    }
+
+    /// Clear the results. This doesn't affect a scan in progress.
+    pub fn clear(&mut self) {
+        self.results.clear();
+        self.last_scan_ms = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{
+        device::tests::{MockEsp, now_ms, set_now_ms, varints},
+        esp_errors::EspCode,
+        event::StaScanDone,
+        rpc::RpcId,
+    };
+
+    fn record(bssid: u8, rssi: i8) -> WifiApRecord {
+        WifiApRecord {
+            bssid: [bssid; 6],
+            rssi,
+            ..Default::default()
+        }
+    }
+
+    fn rssis<const N: usize>(mgr: &ScanManager<N>) -> Vec<i8, N> {
+        mgr.results().iter().map(|r| r.record.rssi).collect()
+    }
+
+    /// A manager whose scan has completed, but hasn't been read.
+    fn scan_done(dev: &mut EspHosted<MockEsp>) -> ScanManager<4> {
+        let mut mgr = ScanManager::new();
+        assert!(mgr.start(dev, &ScanConfig::default()).is_ok());
+        assert!(mgr.on_event(&Event::StaScanDone(StaScanDone::default())));
+        mgr
+    }
+
+    fn esp_err(code: EspCode) -> std::vec::Vec<u8> {
+        varints(&[(1, code as u64)])
+    }
+
+    #[test]
+    fn dedup_by_bssid() {
+        let mut mgr = ScanManager::<4>::new();
+        mgr.insert(record(1, -70), 0);
+        mgr.insert(record(2, -60), 0);
+        mgr.insert(record(1, -50), 10);
+
+        assert_eq!(mgr.results().len(), 2);
+        assert_eq!(mgr.results()[0].record.bssid, [1; 6]);
+        assert_eq!(mgr.results()[0].record.rssi, -50);
+        assert_eq!(mgr.results()[0].last_seen_ms, 10);
+    }
+
+    #[test]
+    fn sorted_by_rssi() {
+        let mut mgr = ScanManager::<4>::new();
+        for (bssid, rssi) in [(1, -80), (2, -40), (3, -90), (4, -60)] {
+            mgr.insert(record(bssid, rssi), 0);
+        }
+        assert_eq!(rssis(&mgr), [-40, -60, -80, -90]);
+
+        // An update can change the order.
+        mgr.insert(record(3, -30), 0);
+        assert_eq!(rssis(&mgr), [-30, -40, -60, -80]);
+    }
+
+    #[test]
+    fn full_evicts_weakest() {
+        let mut mgr = ScanManager::<2>::new();
+        mgr.insert(record(1, -60), 0);
+        mgr.insert(record(2, -70), 0);
+
+        mgr.insert(record(3, -50), 0);
+        assert_eq!(rssis(&mgr), [-50, -60]);
+
+        // Weaker than everything we have, so it's ignored.
+        mgr.insert(record(4, -80), 0);
+        assert_eq!(rssis(&mgr), [-50, -60]);
+        assert!(mgr.results().iter().all(|r| r.record.bssid != [4; 6]));
+    }
+
+    #[test]
+    fn expire_max_age() {
+        let mut mgr = ScanManager::<4>::new();
+        mgr.max_age_ms = 1_000;
+        mgr.insert(record(1, -50), 0);
+        mgr.insert(record(2, -60), 500);
+
+        mgr.expire(999);
+        assert_eq!(mgr.results().len(), 2);
+
+        mgr.expire(1_000);
+        assert_eq!(mgr.results().len(), 1);
+        assert_eq!(mgr.results()[0].record.bssid, [2; 6]);
+
+        mgr.expire(1_500);
+        assert!(mgr.results().is_empty());
+    }
+
+    #[test]
+    fn finish_returns_read_error() {
+        let mut dev = EspHosted::new(MockEsp::default(), now_ms);
+        let mut mgr = scan_done(&mut dev);
+
+        dev.transport
+            .respond(RpcId::ReqWifiScanGetApNum, esp_err(EspCode::ESP_ERR_NO_MEM));
+        dev.transport.respond(
+            RpcId::ReqWifiClearApList,
+            esp_err(EspCode::ESP_ERR_INVALID_STATE),
+        );
+
+        let result = mgr.poll(&mut dev, &ScanConfig::default());
+        assert!(matches!(
+            result,
+            Err(EspError::Esp(EspCode::ESP_ERR_NO_MEM))
+        ));
+        // The clear is still attempted.
+        assert_eq!(dev.transport.count(RpcId::ReqWifiClearApList), 1);
+        assert!(!mgr.is_scanning());
+    }
+
+    #[test]
+    fn finish_returns_clear_error() {
+        let mut dev = EspHosted::new(MockEsp::default(), now_ms);
+        let mut mgr = scan_done(&mut dev);
+
+        dev.transport.respond(
+            RpcId::ReqWifiClearApList,
+            esp_err(EspCode::ESP_ERR_INVALID_STATE),
+        );
+
+        let result = mgr.poll(&mut dev, &ScanConfig::default());
+        assert!(matches!(
+            result,
+            Err(EspError::Esp(EspCode::ESP_ERR_INVALID_STATE))
+        ));
+    }
+
+    #[test]
+    fn finish_no_aps() {
+        let mut dev = EspHosted::new(MockEsp::default(), now_ms);
+        let mut mgr = scan_done(&mut dev);
+
+        assert!(matches!(
+            mgr.poll(&mut dev, &ScanConfig::default()),
+            Ok(true)
+        ));
+        assert_eq!(dev.transport.count(RpcId::ReqWifiScanGetApRecords), 0);
+        assert_eq!(dev.transport.count(RpcId::ReqWifiClearApList), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        set_now_ms(0);
+        let mut dev = EspHosted::new(MockEsp::default(), now_ms);
+        let mut mgr = ScanManager::<4>::new();
+        assert!(mgr.start(&mut dev, &ScanConfig::default()).is_ok());
+
+        set_now_ms(SCAN_TIMEOUT_MS - 1);
+        assert!(matches!(
+            mgr.poll(&mut dev, &ScanConfig::default()),
+            Ok(false)
+        ));
+
+        // A failed clear doesn't mask the timeout.
+        dev.transport.respond(
+            RpcId::ReqWifiClearApList,
+            esp_err(EspCode::ESP_ERR_INVALID_STATE),
+        );
+        set_now_ms(SCAN_TIMEOUT_MS);
+        assert!(matches!(
+            mgr.poll(&mut dev, &ScanConfig::default()),
+            Err(EspError::Timeout)
+        ));
+        assert_eq!(dev.transport.count(RpcId::ReqWifiClearApList), 1);
+        assert!(!mgr.is_scanning());
+    }
+}
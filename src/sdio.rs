@@ -0,0 +1,600 @@
+//! Host-side support for the SDIO transport. This handles the ESP's SDIO slave protocol: Reading its
+//! packet-length, token, and interrupt registers, and splitting transfers into whole blocks, sent with a block-mode
+//! CMD53, and a remainder padded to 4 bytes, sent with a byte-mode CMD53.
+//!
+//! This is built on the `SdioBus` trait, which exposes CMD52 and CMD53; implement it for your HAL's SDMMC peripheral.
+//! Card initialization (CMD5, CMD3, CMD7 etc) is left to the HAL.
+//!
+//! A single read may contain several frames back to back. Use a `FrameDecoder` to split them.
+//!
+//! See ESP-IDF's `essl_sdio.c`, and `esp_sdio_api.h` in ESP-Hosted.
+
+use crate::{
+    EspError,
+    transport::{ESP_TRANSPORT_SDIO_MAX_BUF_SIZE, Transport},
+};
+
+const BUF_SIZE: usize = ESP_TRANSPORT_SDIO_MAX_BUF_SIZE as usize;
+
+/// The ESP's SDIO function used for data, and its registers.
+const FUNC_DATA: u8 = 1;
+
+/// CMD53 addresses for data transfers count down from here: Transfers of `n` remaining bytes use address
+/// `CMD53_END_ADDR - n`.
+const CMD53_END_ADDR: u32 = 0x1_F800;
+
+// Slave registers, in function 1's address space. Each is 4 bytes.
+/// Bits 16:28 are a running count of buffers the slave has made available to receive from the host.
+const REG_TOKEN_RDATA: u32 = 0x044;
+const REG_INT_RAW: u32 = 0x050;
+const REG_INT_ST: u32 = 0x058;
+/// Bits 0:20 are a running count of bytes the slave has queued for the host.
+const REG_PKT_LEN: u32 = 0x060;
+const REG_INT_CLR: u32 = 0x0D4;
+
+const TOKEN_MASK: u32 = 0xFFF;
+const TOKEN_SHIFT: u32 = 16;
+const LEN_MASK: u32 = 0xF_FFFF;
+
+/// Set in `REG_INT_ST` when the slave has a new packet for the host.
+pub const INT_NEW_PACKET: u32 = 1 << 23;
+
+// Card common control registers, in function 0's address space.
+const CCCR_IO_ENABLE: u32 = 0x02;
+const CCCR_IO_READY: u32 = 0x03;
+/// Function 1's block size; a `u16`. (Function basic registers)
+const FBR1_BLOCK_SIZE: u32 = 0x110;
+
+pub const BLOCK_SIZE: usize = 512;
+/// Byte-mode CMD53 transfers must be a multiple of this.
+const BYTE_MODE_ALIGN: usize = 4;
+
+/// The number of times we poll the slave's registers waiting for it to be ready, or for a receive buffer,
+/// before returning `EspError::Timeout`.
+pub const POLL_LIMIT: u32 = 10_000;
+
+/// CMD52 (single register) and CMD53 (multi-byte, or multi-block) access to the SDIO card. Implement this for
+/// your HAL's SDMMC peripheral.
+pub trait SdioBus {
+    /// CMD52: Read one byte from function `func`'s register at `addr`.
+    fn cmd52_read(&mut self, func: u8, addr: u32) -> Result<u8, EspError>;
+
+    /// CMD52: Write one byte to function `func`'s register at `addr`.
+    fn cmd52_write(&mut self, func: u8, addr: u32, val: u8) -> Result<(), EspError>;
+
+    /// CMD53: Read `buf.len()` bytes, starting at `addr`, with incrementing addresses. In block mode, `buf.len()`
+    /// is a multiple of the block size.
+    fn cmd53_read(
+        &mut self,
+        func: u8,
+        addr: u32,
+        buf: &mut [u8],
+        block_mode: bool,
+    ) -> Result<(), EspError>;
+
+    /// CMD53: Write `buf`, starting at `addr`, with incrementing addresses. In block mode, `buf.len()`
+    /// is a multiple of the block size.
+    fn cmd53_write(
+        &mut self,
+        func: u8,
+        addr: u32,
+        buf: &[u8],
+        block_mode: bool,
+    ) -> Result<(), EspError>;
+}
+
+pub struct SdioTransport<B> {
+    bus: B,
+    /// Holds frames padded for transfer, in both directions. The extra bytes allow for byte-mode padding.
+    buf: [u8; BUF_SIZE + BYTE_MODE_ALIGN],
+    /// Running count of bytes we've read from the slave. Compared against `REG_PKT_LEN`.
+    rx_bytes: u32,
+    /// Running count of buffers we've written to the slave. Compared against `REG_TOKEN_RDATA`.
+    tx_bufs: u32,
+}
+
+impl<B: SdioBus> SdioTransport<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            buf: [0; BUF_SIZE + BYTE_MODE_ALIGN],
+            rx_bytes: 0,
+            tx_bufs: 0,
+        }
+    }
+
+    pub fn free(self) -> B {
+        self.bus
+    }
+
+    /// Enable function 1, wait for it to be ready, and set its block size. Run this after the HAL has initialized
+    /// the card. Also synchronizes our counters with the slave's.
+    pub fn init(&mut self) -> Result<(), EspError> {
+        let enabled = self.bus.cmd52_read(0, CCCR_IO_ENABLE)?;
+        self.bus
+            .cmd52_write(0, CCCR_IO_ENABLE, enabled | (1 << FUNC_DATA))?;
+
+        let mut ready = false;
+        for _ in 0..POLL_LIMIT {
+            if self.bus.cmd52_read(0, CCCR_IO_READY)? & (1 << FUNC_DATA) != 0 {
+                ready = true;
+                break;
+            }
+        }
+
+        if !ready {
+            return Err(EspError::Timeout);
+        }
+
+        let block_size = (BLOCK_SIZE as u16).to_le_bytes();
+        self.bus.cmd52_write(0, FBR1_BLOCK_SIZE, block_size[0])?;
+        self.bus
+            .cmd52_write(0, FBR1_BLOCK_SIZE + 1, block_size[1])?;
+
+        self.rx_bytes = self.read_reg(REG_PKT_LEN)? & LEN_MASK;
+        self.tx_bufs = (self.read_reg(REG_TOKEN_RDATA)? >> TOKEN_SHIFT) & TOKEN_MASK;
+
+        Ok(())
+    }
+
+    pub fn read_reg(&mut self, reg: u32) -> Result<u32, EspError> {
+        let mut buf = [0; 4];
+        self.bus.cmd53_read(FUNC_DATA, reg, &mut buf, false)?;
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn write_reg(&mut self, reg: u32, val: u32) -> Result<(), EspError> {
+        self.bus
+            .cmd53_write(FUNC_DATA, reg, &val.to_le_bytes(), false)
+    }
+
+    /// Read the slave-to-host interrupt status. Check against `INT_NEW_PACKET` etc.
+    pub fn interrupt_status(&mut self) -> Result<u32, EspError> {
+        self.read_reg(REG_INT_ST)
+    }
+
+    /// Read the slave-to-host interrupt status, without the enable mask applied.
+    pub fn interrupt_raw(&mut self) -> Result<u32, EspError> {
+        self.read_reg(REG_INT_RAW)
+    }
+
+    pub fn clear_interrupts(&mut self, mask: u32) -> Result<(), EspError> {
+        self.write_reg(REG_INT_CLR, mask)
+    }
+
+    /// The number of bytes the slave has queued for us.
+    pub fn rx_available(&mut self) -> Result<u32, EspError> {
+        let pkt_len = self.read_reg(REG_PKT_LEN)? & LEN_MASK;
+        Ok(pkt_len.wrapping_sub(self.rx_bytes) & LEN_MASK)
+    }
+
+    /// The number of buffers the slave has available to receive frames from us.
+    pub fn tx_buffers_available(&mut self) -> Result<u32, EspError> {
+        let tokens = (self.read_reg(REG_TOKEN_RDATA)? >> TOKEN_SHIFT) & TOKEN_MASK;
+        Ok(tokens.wrapping_sub(self.tx_bufs) & TOKEN_MASK)
+    }
+}
+
+/// Split a transfer of `len` bytes into a block-mode part, and a byte-mode remainder. Returns the
+/// (block-mode len, byte-mode len, padded byte-mode len).
+fn split_transfer(len: usize) -> (usize, usize, usize) {
+    let block_len = (len / BLOCK_SIZE) * BLOCK_SIZE;
+    let byte_len = len - block_len;
+    let byte_len_padded = byte_len.div_ceil(BYTE_MODE_ALIGN) * BYTE_MODE_ALIGN;
+
+    (block_len, byte_len, byte_len_padded)
+}
+
+impl<B: SdioBus> Transport for SdioTransport<B> {
+    /// Waits for the slave to have a receive buffer available, then writes the frame.
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        let len = buf.len();
+        if len > BUF_SIZE {
+            return Err(EspError::Capacity);
+        }
+
+        let mut available = false;
+        for _ in 0..POLL_LIMIT {
+            if self.tx_buffers_available()? > 0 {
+                available = true;
+                break;
+            }
+        }
+
+        if !available {
+            return Err(EspError::Timeout);
+        }
+
+        let (block_len, byte_len, byte_len_padded) = split_transfer(len);
+
+        self.buf[..len].copy_from_slice(buf);
+        self.buf[len..block_len + byte_len_padded].fill(0);
+
+        if block_len > 0 {
+            let addr = CMD53_END_ADDR - len as u32;
+            self.bus
+                .cmd53_write(FUNC_DATA, addr, &self.buf[..block_len], true)?;
+        }
+
+        if byte_len > 0 {
+            let addr = CMD53_END_ADDR - byte_len as u32;
+            self.bus.cmd53_write(
+                FUNC_DATA,
+                addr,
+                &self.buf[block_len..block_len + byte_len_padded],
+                false,
+            )?;
+        }
+
+        self.tx_bufs = self.tx_bufs.wrapping_add(1) & TOKEN_MASK;
+
+        Ok(())
+    }
+
+    /// Reads as many queued bytes as fit in `buf`. This may include several frames.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        let available = self.rx_available()? as usize;
+        if available == 0 {
+            return Ok(0);
+        }
+
+        let len = available.min(buf.len()).min(BUF_SIZE);
+        let (block_len, byte_len, byte_len_padded) = split_transfer(len);
+
+        if block_len > 0 {
+            let addr = CMD53_END_ADDR - len as u32;
+            self.bus
+                .cmd53_read(FUNC_DATA, addr, &mut self.buf[..block_len], true)?;
+        }
+
+        if byte_len > 0 {
+            let addr = CMD53_END_ADDR - byte_len as u32;
+            self.bus.cmd53_read(
+                FUNC_DATA,
+                addr,
+                &mut self.buf[block_len..block_len + byte_len_padded],
+                false,
+            )?;
+        }
+
+        buf[..len].copy_from_slice(&self.buf[..len]);
+        self.rx_bytes = self.rx_bytes.wrapping_add(len as u32) & LEN_MASK;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// A CMD53 data transfer, as recorded by `MockBus`. Register accesses aren't recorded.
+    #[derive(Clone, PartialEq, Debug)]
+    struct Xfer {
+        addr: u32,
+        block_mode: bool,
+        /// The bytes written, or the number read.
+        data: Vec<u8>,
+        len: usize,
+    }
+
+    /// Emulates the slave's function 0 and 1 registers, and its data buffers.
+    struct MockBus {
+        /// Function 0 registers, by address.
+        cccr: [u8; 0x120],
+        /// Reads of `CCCR_IO_READY` before function 1 is ready.
+        ready_after: u32,
+        /// Function 1 registers 0x00 - 0xD4, indexed by address / 4.
+        regs: [u32; 0x36],
+        /// Bytes the slave has queued for the host.
+        to_host: Vec<u8>,
+        log: Vec<Xfer>,
+    }
+
+    impl Default for MockBus {
+        fn default() -> Self {
+            Self {
+                cccr: [0; 0x120],
+                ready_after: 0,
+                regs: [0; 0x36],
+                to_host: Vec::new(),
+                log: Vec::new(),
+            }
+        }
+    }
+
+    impl MockBus {
+        fn reg(&mut self, addr: u32) -> &mut u32 {
+            &mut self.regs[addr as usize / 4]
+        }
+
+        /// Queue bytes for the host, and advance the slave's packet length count.
+        fn queue(&mut self, data: &[u8]) {
+            self.to_host.extend_from_slice(data);
+            *self.reg(REG_PKT_LEN) += data.len() as u32;
+        }
+
+        /// Make `n` more receive buffers available.
+        fn add_tokens(&mut self, n: u32) {
+            *self.reg(REG_TOKEN_RDATA) += n << TOKEN_SHIFT;
+        }
+    }
+
+    impl SdioBus for MockBus {
+        fn cmd52_read(&mut self, func: u8, addr: u32) -> Result<u8, EspError> {
+            if func != 0 {
+                return Err(EspError::Comms);
+            }
+
+            if addr == CCCR_IO_READY && self.ready_after > 0 {
+                self.ready_after -= 1;
+                return Ok(0);
+            }
+            Ok(self.cccr[addr as usize])
+        }
+
+        fn cmd52_write(&mut self, func: u8, addr: u32, val: u8) -> Result<(), EspError> {
+            if func != 0 {
+                return Err(EspError::Comms);
+            }
+
+            self.cccr[addr as usize] = val;
+            // The slave reports ready once enabled.
+            if addr == CCCR_IO_ENABLE {
+                self.cccr[CCCR_IO_READY as usize] = val;
+            }
+            Ok(())
+        }
+
+        fn cmd53_read(
+            &mut self,
+            func: u8,
+            addr: u32,
+            buf: &mut [u8],
+            block_mode: bool,
+        ) -> Result<(), EspError> {
+            assert_eq!(func, FUNC_DATA);
+
+            if addr < 0x100 {
+                assert!(!block_mode);
+                buf.copy_from_slice(&self.reg(addr).to_le_bytes());
+                return Ok(());
+            }
+
+            self.log.push(Xfer {
+                addr,
+                block_mode,
+                data: Vec::new(),
+                len: buf.len(),
+            });
+
+            // Padding past the queued bytes reads as 0.
+            let n = buf.len().min(self.to_host.len());
+            buf[..n].copy_from_slice(&self.to_host[..n]);
+            buf[n..].fill(0);
+            self.to_host.drain(..n);
+            Ok(())
+        }
+
+        fn cmd53_write(
+            &mut self,
+            func: u8,
+            addr: u32,
+            buf: &[u8],
+            block_mode: bool,
+        ) -> Result<(), EspError> {
+            assert_eq!(func, FUNC_DATA);
+
+            if addr < 0x100 {
+                assert!(!block_mode);
+                *self.reg(addr) = u32::from_le_bytes(buf.try_into().unwrap());
+                return Ok(());
+            }
+
+            self.log.push(Xfer {
+                addr,
+                block_mode,
+                data: buf.to_vec(),
+                len: buf.len(),
+            });
+            Ok(())
+        }
+    }
+
+    /// `EspError` doesn't implement `Debug`, so can't be `unwrap`ped.
+    fn ok<T>(result: Result<T, EspError>) -> T {
+        match result {
+            Ok(v) => v,
+            Err(_) => panic!("unexpected error"),
+        }
+    }
+
+    fn slave() -> SdioTransport<MockBus> {
+        let mut t = SdioTransport::new(MockBus::default());
+        ok(t.init());
+        t
+    }
+
+    /// A frame of `len` bytes, with no zeros, so padding is distinguishable.
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 255) as u8 + 1).collect()
+    }
+
+    #[test]
+    fn split() {
+        for (len, expected) in [
+            (0, (0, 0, 0)),
+            (1, (0, 1, 4)),
+            (4, (0, 4, 4)),
+            (5, (0, 5, 8)),
+            (511, (0, 511, 512)),
+            (BLOCK_SIZE, (BLOCK_SIZE, 0, 0)),
+            (BLOCK_SIZE + 1, (BLOCK_SIZE, 1, 4)),
+            (2 * BLOCK_SIZE + 6, (2 * BLOCK_SIZE, 6, 8)),
+        ] {
+            assert_eq!(split_transfer(len), expected, "len: {len}");
+        }
+    }
+
+    #[test]
+    fn init() {
+        let mut bus = MockBus {
+            ready_after: 3,
+            ..Default::default()
+        };
+        *bus.reg(REG_PKT_LEN) = 100;
+        *bus.reg(REG_TOKEN_RDATA) = 7 << TOKEN_SHIFT;
+
+        let mut t = SdioTransport::new(bus);
+        ok(t.init());
+
+        assert_eq!(t.bus.cccr[CCCR_IO_ENABLE as usize], 1 << FUNC_DATA);
+        assert_eq!(
+            u16::from_le_bytes([
+                t.bus.cccr[FBR1_BLOCK_SIZE as usize],
+                t.bus.cccr[FBR1_BLOCK_SIZE as usize + 1]
+            ]),
+            BLOCK_SIZE as u16
+        );
+        // The counters start from the slave's.
+        assert_eq!(ok(t.rx_available()), 0);
+        assert_eq!(ok(t.tx_buffers_available()), 0);
+    }
+
+    #[test]
+    fn init_timeout() {
+        let mut t = SdioTransport::new(MockBus {
+            ready_after: POLL_LIMIT,
+            ..Default::default()
+        });
+        assert!(matches!(t.init(), Err(EspError::Timeout)));
+    }
+
+    #[test]
+    fn write_one_block() {
+        let mut t = slave();
+        t.bus.add_tokens(1);
+
+        let data = frame(BLOCK_SIZE);
+        ok(t.write(&data));
+
+        assert_eq!(
+            t.bus.log,
+            [Xfer {
+                addr: CMD53_END_ADDR - BLOCK_SIZE as u32,
+                block_mode: true,
+                data: data.clone(),
+                len: BLOCK_SIZE,
+            }]
+        );
+        assert_eq!(ok(t.tx_buffers_available()), 0);
+    }
+
+    #[test]
+    fn write_one_block_plus_one() {
+        let mut t = slave();
+        t.bus.add_tokens(1);
+
+        let data = frame(BLOCK_SIZE + 1);
+        ok(t.write(&data));
+
+        assert_eq!(
+            t.bus.log,
+            [
+                Xfer {
+                    addr: CMD53_END_ADDR - BLOCK_SIZE as u32 - 1,
+                    block_mode: true,
+                    data: data[..BLOCK_SIZE].to_vec(),
+                    len: BLOCK_SIZE,
+                },
+                // The remaining byte, padded to 4.
+                Xfer {
+                    addr: CMD53_END_ADDR - 1,
+                    block_mode: false,
+                    data: [data[BLOCK_SIZE], 0, 0, 0].to_vec(),
+                    len: BYTE_MODE_ALIGN,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn write_byte_mode_padding() {
+        let mut t = slave();
+        t.bus.add_tokens(2);
+
+        // Leave non-zero bytes in the buffer past the next frame's end.
+        ok(t.write(&frame(8)));
+        t.bus.log.clear();
+
+        let data = frame(6);
+        ok(t.write(&data));
+
+        assert_eq!(t.bus.log.len(), 1);
+        let xfer = &t.bus.log[0];
+        assert_eq!(xfer.addr, CMD53_END_ADDR - 6);
+        assert!(!xfer.block_mode);
+        assert_eq!(xfer.len, 8);
+        assert_eq!(xfer.data[..6], data[..]);
+        assert_eq!(xfer.data[6..], [0, 0]);
+    }
+
+    #[test]
+    fn write_waits_for_buffer() {
+        let mut t = slave();
+
+        assert!(matches!(t.write(&frame(16)), Err(EspError::Timeout)));
+        assert!(t.bus.log.is_empty());
+
+        assert!(matches!(
+            t.write(&frame(BUF_SIZE + 1)),
+            Err(EspError::Capacity)
+        ));
+    }
+
+    #[test]
+    fn read_one_block_plus_one() {
+        let mut t = slave();
+        let data = frame(BLOCK_SIZE + 1);
+        t.bus.queue(&data);
+
+        let mut buf = [0; BUF_SIZE];
+        assert_eq!(ok(t.read(&mut buf)), BLOCK_SIZE + 1);
+        assert_eq!(buf[..BLOCK_SIZE + 1], data[..]);
+
+        let xfers: Vec<(u32, bool, usize)> = t
+            .bus
+            .log
+            .iter()
+            .map(|x| (x.addr, x.block_mode, x.len))
+            .collect();
+        assert_eq!(
+            xfers,
+            [
+                (CMD53_END_ADDR - BLOCK_SIZE as u32 - 1, true, BLOCK_SIZE),
+                (CMD53_END_ADDR - 1, false, BYTE_MODE_ALIGN),
+            ]
+        );
+
+        assert_eq!(ok(t.rx_available()), 0);
+        assert_eq!(ok(t.read(&mut buf)), 0);
+    }
+
+    #[test]
+    fn read_limited_by_buf() {
+        let mut t = slave();
+        let data = frame(100);
+        t.bus.queue(&data);
+
+        let mut buf = [0; 10];
+        assert_eq!(ok(t.read(&mut buf)), 10);
+        assert_eq!(buf, data[..10]);
+        assert_eq!(t.bus.log[0].len, 12);
+        assert_eq!(ok(t.rx_available()), 90);
+    }
+}
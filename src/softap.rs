@@ -0,0 +1,140 @@
+//! Tracks the stations connected to our soft-AP. The table is updated from `EventApStaConnected` and
+//! `EventApStaDisconnected` events, and can be refreshed from the response to `wifi::ap_get_sta_list`,
+//! e.g. after a restart, or to update RSSI values.
+//!
+//! ```ignore
+//! static STATIONS: Mutex<RefCell<StationTable<8>>> = Mutex::new(RefCell::new(StationTable::new()));
+//!
+//! fn on_sta_event(event: &Event) {
+//!     critical_section::with(|cs| STATIONS.borrow_ref_mut(cs).on_event(event).ok());
+//! }
+//!
+//! dispatcher.subscribe_fn(RpcId::EventApStaConnected, on_sta_event)?;
+//! dispatcher.subscribe_fn(RpcId::EventApStaDisconnected, on_sta_event)?;
+//!
+//! critical_section::with(|cs| {
+//!     for sta in STATIONS.borrow_ref(cs).iter() {
+//!         println!("{:x}: {} dBm", sta.mac, sta.rssi);
+//!     }
+//! });
+//! ```
+//!
+//! With `EspHosted`, you can instead own the table, and pass it events from `EspHosted::next_event`.
+
+use heapless::Vec;
+
+use crate::{EspError, event::Event, proto_data::WifiStaList};
+
+/// A station connected to our soft-AP.
+#[derive(Clone, Copy, Default)]
+pub struct ConnectedSta {
+    pub mac: [u8; 6],
+    /// Association ID. 0 if unknown, e.g. for stations added by `update_from_list`.
+    pub aid: u8,
+    pub is_mesh_child: bool,
+    /// In dBm. 0 until updated by `update_from_list`.
+    pub rssi: i8,
+}
+
+/// A table of up to `N` connected stations.
+pub struct StationTable<const N: usize> {
+    stations: Vec<ConnectedSta, N>,
+}
+
+impl<const N: usize> Default for StationTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> StationTable<N> {
+    pub const fn new() -> Self {
+        Self {
+            stations: Vec::new(),
+        }
+    }
+
+    /// Update the table from an event. Returns `Ok(true)` if the table changed. Other events are ignored,
+    /// apart from `EspInit`, which clears the table, since the ESP has restarted. Returns `EspError::Capacity`
+    /// if the table is full.
+    pub fn on_event(&mut self, event: &Event) -> Result<bool, EspError> {
+        match event {
+            Event::ApStaConnected(ev) => {
+                let sta = ConnectedSta {
+                    mac: ev.mac,
+                    aid: ev.aid,
+                    is_mesh_child: ev.is_mesh_child,
+                    rssi: 0,
+                };
+
+                match self.stations.iter_mut().find(|s| s.mac == ev.mac) {
+                    // e.g. a reconnection we didn't see the disconnection for.
+                    Some(existing) => *existing = sta,
+                    None => self.stations.push(sta).map_err(|_| EspError::Capacity)?,
+                }
+                Ok(true)
+            }
+            Event::ApStaDisconnected(ev) => Ok(self.remove(&ev.mac)),
+            Event::EspInit => {
+                let changed = !self.stations.is_empty();
+                self.stations.clear();
+                Ok(changed)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Replace the table's contents with the stations in `list`, e.g. from `wifi::parse_sta_list`. Association
+    /// IDs are kept for stations already in the table. Returns `EspError::Capacity` if the list doesn't fit.
+    pub fn update_from_list(&mut self, list: &WifiStaList) -> Result<(), EspError> {
+        let mut updated = Vec::new();
+
+        for info in &list.sta {
+            let mut sta = self.get(&info.mac).copied().unwrap_or(ConnectedSta {
+                mac: info.mac,
+                ..Default::default()
+            });
+
+            sta.rssi = info.rssi;
+            // Bit 5 is `is_mesh_child`.
+            sta.is_mesh_child = info.bitmask & (1 << 5) != 0;
+
+            updated.push(sta).map_err(|_| EspError::Capacity)?;
+        }
+
+        self.stations = updated;
+        Ok(())
+    }
+
+    /// Remove a station. Returns `true` if it was in the table.
+    pub fn remove(&mut self, mac: &[u8; 6]) -> bool {
+        match self.stations.iter().position(|s| s.mac == *mac) {
+            Some(i) => {
+                self.stations.swap_remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, mac: &[u8; 6]) -> Option<&ConnectedSta> {
+        self.stations.iter().find(|s| s.mac == *mac)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConnectedSta> {
+        self.stations.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.stations.clear();
+    }
+
+    /// The number of connected stations.
+    pub fn len(&self) -> usize {
+        self.stations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stations.is_empty()
+    }
+}
@@ -0,0 +1,366 @@
+//! A full-duplex SPI master driver for ESP-Hosted-MCU, built on `embedded-hal`. This runs the exchange
+//! the slave expects: Wait for its Handshake line, then clock a fixed-size transaction; the ESP sends
+//! a frame (or a dummy one) while we send ours. If we have nothing to send, but the ESP's Data Ready line
+//! is high, we send a dummy frame so it can send its data.
+//!
+//! Every transaction is the full `ESP_TRANSPORT_SPI_MAX_BUF_SIZE`, with TX padded with zeros, even if
+//! neither frame needs it: the slave's DMA expects fixed-size transactions, and a short one desyncs it.
+//! The received frame's header, and payload, are parsed from the RX buffer afterwards.
+//!
+//! Received frames are queued, and returned by `Transport::read`. To get parsed messages, pair this with
+//! a `FrameDecoder`:
+//!
+//! ```ignore
+//! let mut spi = SpiTransport::new(spi_dev, handshake_pin, data_ready_pin);
+//! let mut decoder = FrameDecoder::<1_600>::new();
+//!
+//! wifi::start(&mut buf, &mut spi, 0)?;
+//!
+//! decoder.read_from(&mut spi)?;
+//! while let Some(msg) = decoder.next_frame() {
+//!     // ...
+//! }
+//! ```
+
+use embedded_hal::{digital::InputPin, spi::SpiDevice};
+
+use crate::{
+    EspError,
+    decoder::frame_len,
+    header::PL_HEADER_SIZE,
+    transport::{ESP_TRANSPORT_SPI_MAX_BUF_SIZE, Transport},
+};
+
+const BUF_SIZE: usize = ESP_TRANSPORT_SPI_MAX_BUF_SIZE as usize;
+
+/// The default number of times we poll the Handshake line before giving up on a transaction.
+pub const HANDSHAKE_POLL_LIMIT: u32 = 100_000;
+
+pub struct SpiTransport<SPI, HS, DR> {
+    spi: SPI,
+    /// Driven high by the ESP when it's ready for a transaction.
+    handshake: HS,
+    /// Driven high by the ESP when it has data queued for the host.
+    data_ready: DR,
+    /// Holds the TX frame, padded with zeros; it's replaced by the RX frame during the transaction.
+    xfer_buf: [u8; BUF_SIZE],
+    /// Frames received, but not yet returned by `read`. These are stored back-to-back.
+    rx_queue: [u8; BUF_SIZE],
+    rx_len: usize,
+    /// The number of times we poll the Handshake line before returning `EspError::Timeout`.
+    pub handshake_poll_limit: u32,
+    /// The number of received frames dropped due to a full RX queue. This can happen if several
+    /// frames are written without reading in between.
+    pub rx_dropped: u32,
+}
+
+impl<SPI, HS, DR> SpiTransport<SPI, HS, DR>
+where
+    SPI: SpiDevice,
+    HS: InputPin,
+    DR: InputPin,
+{
+    pub fn new(spi: SPI, handshake: HS, data_ready: DR) -> Self {
+        Self {
+            spi,
+            handshake,
+            data_ready,
+            xfer_buf: [0; BUF_SIZE],
+            rx_queue: [0; BUF_SIZE],
+            rx_len: 0,
+            handshake_poll_limit: HANDSHAKE_POLL_LIMIT,
+            rx_dropped: 0,
+        }
+    }
+
+    /// Release the SPI device and pins.
+    pub fn free(self) -> (SPI, HS, DR) {
+        (self.spi, self.handshake, self.data_ready)
+    }
+
+    fn wait_for_handshake(&mut self) -> Result<(), EspError> {
+        for _ in 0..self.handshake_poll_limit {
+            if self.handshake.is_high().map_err(|_| EspError::Comms)? {
+                return Ok(());
+            }
+        }
+
+        Err(EspError::Timeout)
+    }
+
+    /// Run a single transaction. `tx` is the frame to send; pass an empty slice to send a dummy frame.
+    /// The frame the ESP sends, if any, is added to the RX queue.
+    fn transact(&mut self, tx: &[u8]) -> Result<(), EspError> {
+        if tx.len() > BUF_SIZE {
+            return Err(EspError::Capacity);
+        }
+
+        self.xfer_buf[..tx.len()].copy_from_slice(tx);
+        self.xfer_buf[tx.len()..].fill(0);
+
+        self.wait_for_handshake()?;
+
+        self.spi
+            .transfer_in_place(&mut self.xfer_buf)
+            .map_err(|_| EspError::Comms)?;
+
+        // The ESP sends a zeroed, or otherwise invalid header when it has nothing for us.
+        let Some(frame_len) = frame_len(&self.xfer_buf[..PL_HEADER_SIZE], BUF_SIZE) else {
+            return Ok(());
+        };
+
+        if self.rx_len + frame_len > BUF_SIZE {
+            self.rx_dropped += 1;
+            return Ok(());
+        }
+
+        self.rx_queue[self.rx_len..self.rx_len + frame_len]
+            .copy_from_slice(&self.xfer_buf[..frame_len]);
+        self.rx_len += frame_len;
+
+        Ok(())
+    }
+}
+
+impl<SPI, HS, DR> Transport for SpiTransport<SPI, HS, DR>
+where
+    SPI: SpiDevice,
+    HS: InputPin,
+    DR: InputPin,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.transact(buf)
+    }
+
+    /// Returns queued frames. If none are queued, and the ESP has data ready, runs a transaction with
+    /// a dummy frame to receive it.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if self.rx_len == 0 && self.data_ready() {
+            self.transact(&[])?;
+        }
+
+        let n = self.rx_len.min(buf.len());
+        buf[..n].copy_from_slice(&self.rx_queue[..n]);
+
+        self.rx_queue.copy_within(n..self.rx_len, 0);
+        self.rx_len -= n;
+
+        Ok(n)
+    }
+
+    fn data_ready(&mut self) -> bool {
+        self.rx_len > 0 || self.data_ready.is_high().unwrap_or(false)
+    }
+
+    fn handshake_ready(&mut self) -> bool {
+        self.handshake.is_high().unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::{cell::Cell, convert::Infallible};
+    use std::{rc::Rc, vec::Vec};
+
+    use embedded_hal::{
+        digital::ErrorType as PinErrorType,
+        spi::{ErrorType, Operation},
+    };
+
+    use super::*;
+    use crate::{FrameDecoder, MsgParsed, ble::HciPkt, header::build_frame_ble};
+
+    /// An input pin that reads low `low_for` times, then high. If `low_for` is `None`, it stays low.
+    struct MockPin {
+        low_for: Option<u32>,
+        /// Shared, so tests can check it after the pin's moved into the transport.
+        reads: Rc<Cell<u32>>,
+    }
+
+    impl MockPin {
+        fn new(low_for: Option<u32>) -> Self {
+            Self {
+                low_for,
+                reads: Rc::new(Cell::new(0)),
+            }
+        }
+    }
+
+    impl PinErrorType for MockPin {
+        type Error = Infallible;
+    }
+
+    impl InputPin for MockPin {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            self.reads.set(self.reads.get() + 1);
+
+            Ok(match self.low_for {
+                Some(n) => self.reads.get() > n,
+                None => false,
+            })
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.is_high()?)
+        }
+    }
+
+    /// Records each transaction's TX bytes, and replies with queued RX frames, zero-padded.
+    #[derive(Default)]
+    struct MockSpi {
+        sent: Vec<Vec<u8>>,
+        replies: Vec<Vec<u8>>,
+    }
+
+    impl ErrorType for MockSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for MockSpi {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            for op in operations {
+                let Operation::TransferInPlace(buf) = op else {
+                    panic!("expected a single in-place transfer");
+                };
+
+                self.sent.push(buf.to_vec());
+
+                buf.fill(0);
+                if !self.replies.is_empty() {
+                    let reply = self.replies.remove(0);
+                    buf[..reply.len()].copy_from_slice(&reply);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    type Spi = SpiTransport<MockSpi, MockPin, MockPin>;
+
+    fn transport(handshake: MockPin, data_ready: MockPin) -> Spi {
+        SpiTransport::new(MockSpi::default(), handshake, data_ready)
+    }
+
+    fn hci_frame(payload: &[u8]) -> Vec<u8> {
+        let mut buf = [0; 256];
+        let len = build_frame_ble(&mut buf, HciPkt::Evt, payload);
+        buf[..len].to_vec()
+    }
+
+    /// `EspError` doesn't implement `Debug`, so can't be `unwrap`ped.
+    fn ok<T>(result: Result<T, EspError>) -> T {
+        match result {
+            Ok(v) => v,
+            Err(_) => panic!("unexpected error"),
+        }
+    }
+
+    #[test]
+    fn write_waits_for_handshake_and_pads() {
+        let handshake = MockPin::new(Some(3));
+        let reads = handshake.reads.clone();
+        let mut spi = transport(handshake, MockPin::new(None));
+
+        let frame = hci_frame(&[1, 2, 3]);
+        ok(spi.write(&frame));
+
+        // Three low reads, then the high one.
+        assert_eq!(reads.get(), 4);
+
+        let (spi_dev, _, _) = spi.free();
+        assert_eq!(spi_dev.sent.len(), 1);
+
+        let sent = &spi_dev.sent[0];
+        assert_eq!(sent.len(), BUF_SIZE);
+        assert_eq!(sent[..frame.len()], frame[..]);
+        assert!(sent[frame.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let mut spi = transport(MockPin::new(None), MockPin::new(None));
+        spi.handshake_poll_limit = 10;
+
+        assert!(matches!(spi.write(&[0; 4]), Err(EspError::Timeout)));
+
+        let (spi_dev, _, _) = spi.free();
+        assert!(spi_dev.sent.is_empty());
+    }
+
+    #[test]
+    fn oversized_write() {
+        let mut spi = transport(MockPin::new(Some(0)), MockPin::new(None));
+        assert!(matches!(
+            spi.write(&[0; BUF_SIZE + 1]),
+            Err(EspError::Capacity)
+        ));
+    }
+
+    #[test]
+    fn rx_during_write() {
+        let mut spi = transport(MockPin::new(Some(0)), MockPin::new(None));
+
+        let rx = hci_frame(&[0x0E, 0x01, 0x00]);
+        spi.spi.replies.push(rx.clone());
+
+        ok(spi.write(&hci_frame(&[1])));
+        assert!(spi.data_ready());
+
+        // The RX frame is parsed out of the full-length buffer; the padding isn't returned.
+        let mut decoder = FrameDecoder::<1_600>::new();
+        assert_eq!(ok(decoder.read_from(&mut spi)), rx.len());
+
+        match decoder.next_frame() {
+            Some(Ok(MsgParsed::Hci(msg))) => assert_eq!(msg.data, &rx[PL_HEADER_SIZE..]),
+            _ => panic!("expected an HCI frame"),
+        }
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn dummy_frame_when_data_ready() {
+        let mut spi = transport(MockPin::new(Some(0)), MockPin::new(Some(0)));
+
+        let rx = hci_frame(&[0x0E, 0x01, 0x00]);
+        spi.spi.replies.push(rx.clone());
+
+        let mut buf = [0; 64];
+        assert_eq!(ok(spi.read(&mut buf)), rx.len());
+        assert_eq!(buf[..rx.len()], rx[..]);
+
+        // We sent an all-zero, full-length dummy frame.
+        let sent = &spi.spi.sent[0];
+        assert_eq!(sent.len(), BUF_SIZE);
+        assert!(sent.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn no_rx_frame() {
+        let mut spi = transport(MockPin::new(Some(0)), MockPin::new(None));
+
+        // The ESP replies with a zeroed header when it has nothing for us.
+        ok(spi.write(&hci_frame(&[1])));
+        assert!(!spi.data_ready());
+        assert_eq!(ok(spi.read(&mut [0; 64])), 0);
+    }
+
+    #[test]
+    fn rx_queue_full() {
+        let mut spi = transport(MockPin::new(Some(0)), MockPin::new(None));
+
+        // Fill most of the queue with one frame, so the next doesn't fit.
+        let mut big = [0; BUF_SIZE];
+        let len = build_frame_ble(&mut big, HciPkt::Acl, &[0xAA; BUF_SIZE - 100]);
+        spi.spi.replies.push(big[..len].to_vec());
+        spi.spi.replies.push(hci_frame(&[0; 200]));
+
+        ok(spi.write(&[]));
+        ok(spi.write(&[]));
+
+        assert_eq!(spi.rx_dropped, 1);
+        assert_eq!(ok(spi.read(&mut [0; BUF_SIZE])), len);
+    }
+}
@@ -0,0 +1,536 @@
+//! Support for the SPI half-duplex (SPI-HD) transport. Unlike full-duplex SPI, each transaction has a command
+//! phase, an address phase, and optionally dummy cycles, then data in one direction only. Data may use 1, 2, or 4 lines,
+//! for higher throughput than full-duplex SPI.
+//!
+//! The host and slave coordinate using shared registers on the slave: The slave publishes how many bytes it
+//! has queued for us, and how many buffers it has available to receive. We keep running counts of our own, and compare.
+//!
+//! This is built on the `SpiHdBus` trait, vice a specific HAL's peripheral, since half-duplex SPI with separate command and
+//! address phases is exposed differently between HALs, e.g. as a QSPI or OCTOSPI peripheral.
+//!
+//! See `esp_hosted_transport_spi_hd.h`, and ESP-IDF's `essl_spi` for the host-side reference implementation.
+
+use defmt::Format;
+
+use crate::{
+    EspError,
+    transport::{ESP_TRANSPORT_SPI_HD_MAX_BUF_SIZE, Transport},
+};
+
+// Slave registers, read and written with the RDBUF and WRBUF commands. Each is 4 bytes.
+const REG_SLAVE_READY: u8 = 0x00;
+const REG_MAX_TX_BUF_LEN: u8 = 0x04;
+const REG_MAX_RX_BUF_LEN: u8 = 0x08;
+/// A running count of bytes the slave has queued for the host.
+const REG_TX_BUF_LEN: u8 = 0x0C;
+/// A running count of buffers the slave has made available to receive from the host.
+const REG_RX_BUF_LEN: u8 = 0x10;
+const REG_SLAVE_CTRL: u8 = 0x14;
+
+/// The value of `REG_SLAVE_READY` once the slave has initialized.
+const SLAVE_READY: u32 = 0xEE;
+/// Written to `REG_SLAVE_CTRL` to start data transfer.
+const CTRL_DATAPATH_ON: u32 = 1;
+
+/// Reads require 8 dummy cycles between the address, and data phases.
+const READ_DUMMY_CYCLES: u8 = 8;
+
+/// The number of times we poll the slave's registers during init, or waiting for an RX buffer, before
+/// returning `EspError::Timeout`.
+pub const POLL_LIMIT: u32 = 10_000;
+
+/// SPI-HD commands. The command byte on the wire also encodes the number of data lines; see `SpiHdCmd::val`.
+#[derive(Clone, Copy, PartialEq, Format)]
+#[repr(u8)]
+pub enum SpiHdCmd {
+    /// Write to a slave register.
+    WrBuf = 0x01,
+    /// Read from a slave register.
+    RdBuf = 0x02,
+    /// Write data to the slave's DMA buffer.
+    WrDma = 0x03,
+    /// Read data from the slave's DMA buffer.
+    RdDma = 0x04,
+    /// End of a DMA segment.
+    SegEnd = 0x05,
+    EnQpi = 0x06,
+    /// Marks the end of a DMA write.
+    WrEnd = 0x07,
+    /// Interrupts 0 - 2 on the slave. ESP-Hosted uses interrupt 0 to signal that a read is complete.
+    Int0 = 0x08,
+    Int1 = 0x09,
+    Int2 = 0x0A,
+}
+
+impl SpiHdCmd {
+    /// The command byte sent, for a given line mode. Commands that include address and data phases have a prefix indicating
+    /// the number of lines used for them.
+    pub fn val(&self, lines: DataLines) -> u8 {
+        let base = *self as u8;
+
+        match self {
+            Self::EnQpi | Self::WrEnd | Self::Int0 | Self::Int1 | Self::Int2 => base,
+            _ => match lines {
+                DataLines::Single => base,
+                DataLines::Dual => base | 0x50,
+                DataLines::Quad => base | 0xA0,
+            },
+        }
+    }
+}
+
+/// The number of lines used for the address, and data phases.
+#[derive(Clone, Copy, PartialEq, Default, Format)]
+pub enum DataLines {
+    #[default]
+    Single,
+    Dual,
+    Quad,
+}
+
+/// A half-duplex SPI bus, with command, address, and dummy phases. Implement this for your HAL's peripheral.
+/// The command and address phases are each 8 bits. Commands use a single line; the address and data phases use
+/// `lines`.
+pub trait SpiHdBus {
+    /// Run a transaction that reads `data.len()` bytes after the command, address and dummy phases.
+    fn read(
+        &mut self,
+        cmd: u8,
+        addr: u8,
+        dummy_cycles: u8,
+        lines: DataLines,
+        data: &mut [u8],
+    ) -> Result<(), EspError>;
+
+    /// Run a transaction that writes `data` after the command and address phases. `data` may be
+    /// empty, for commands that have no data phase.
+    fn write(&mut self, cmd: u8, addr: u8, lines: DataLines, data: &[u8]) -> Result<(), EspError>;
+}
+
+/// Host-side driver for the SPI-HD transport. This implements `Transport`, so can be passed
+/// to this library's functions, or to `FrameDecoder::read_from`.
+pub struct SpiHdTransport<B> {
+    bus: B,
+    lines: DataLines,
+    /// Running count of bytes we've read from the slave. Compared against `REG_TX_BUF_LEN`.
+    rx_bytes: u32,
+    /// Running count of buffers we've written to the slave. Compared against `REG_RX_BUF_LEN`.
+    tx_bufs: u32,
+    /// Reported by the slave during init.
+    max_tx_len: u32,
+    max_rx_len: u32,
+}
+
+impl<B: SpiHdBus> SpiHdTransport<B> {
+    /// Create the driver. Call `init` before use. Note that the slave must be configured for the
+    /// same number of data lines.
+    pub fn new(bus: B, lines: DataLines) -> Self {
+        Self {
+            bus,
+            lines,
+            rx_bytes: 0,
+            tx_bufs: 0,
+            max_tx_len: ESP_TRANSPORT_SPI_HD_MAX_BUF_SIZE as u32,
+            max_rx_len: ESP_TRANSPORT_SPI_HD_MAX_BUF_SIZE as u32,
+        }
+    }
+
+    pub fn free(self) -> B {
+        self.bus
+    }
+
+    /// Wait for the slave to be ready, read its buffer sizes, and enable its data path.
+    pub fn init(&mut self) -> Result<(), EspError> {
+        let mut ready = false;
+        for _ in 0..POLL_LIMIT {
+            if self.read_reg(REG_SLAVE_READY)? == SLAVE_READY {
+                ready = true;
+                break;
+            }
+        }
+
+        if !ready {
+            return Err(EspError::Timeout);
+        }
+
+        // Note: "TX" and "RX" here are from the slave's perspective.
+        self.max_rx_len = self.read_reg(REG_MAX_TX_BUF_LEN)?;
+        self.max_tx_len = self.read_reg(REG_MAX_RX_BUF_LEN)?;
+
+        // These counters are reset by the slave on init.
+        self.rx_bytes = 0;
+        self.tx_bufs = 0;
+
+        self.write_reg(REG_SLAVE_CTRL, CTRL_DATAPATH_ON)
+    }
+
+    pub fn read_reg(&mut self, reg: u8) -> Result<u32, EspError> {
+        let mut buf = [0; 4];
+        let cmd = SpiHdCmd::RdBuf.val(self.lines);
+
+        self.bus
+            .read(cmd, reg, READ_DUMMY_CYCLES, self.lines, &mut buf)?;
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn write_reg(&mut self, reg: u8, val: u32) -> Result<(), EspError> {
+        let cmd = SpiHdCmd::WrBuf.val(self.lines);
+        self.bus.write(cmd, reg, self.lines, &val.to_le_bytes())
+    }
+
+    /// The number of bytes the slave has queued for us.
+    pub fn rx_available(&mut self) -> Result<u32, EspError> {
+        Ok(self.read_reg(REG_TX_BUF_LEN)?.wrapping_sub(self.rx_bytes))
+    }
+
+    /// The number of buffers the slave has available to receive frames from us.
+    pub fn tx_buffers_available(&mut self) -> Result<u32, EspError> {
+        Ok(self.read_reg(REG_RX_BUF_LEN)?.wrapping_sub(self.tx_bufs))
+    }
+
+    fn send_cmd(&mut self, cmd: SpiHdCmd) -> Result<(), EspError> {
+        self.bus.write(cmd.val(self.lines), 0, self.lines, &[])
+    }
+}
+
+impl<B: SpiHdBus> Transport for SpiHdTransport<B> {
+    /// Waits for the slave to have a receive buffer available, then writes the frame using DMA.
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        if buf.len() > self.max_tx_len as usize {
+            return Err(EspError::Capacity);
+        }
+
+        let mut available = false;
+        for _ in 0..POLL_LIMIT {
+            if self.tx_buffers_available()? > 0 {
+                available = true;
+                break;
+            }
+        }
+
+        if !available {
+            return Err(EspError::Timeout);
+        }
+
+        let cmd = SpiHdCmd::WrDma.val(self.lines);
+        self.bus.write(cmd, 0, self.lines, buf)?;
+        self.send_cmd(SpiHdCmd::WrEnd)?;
+
+        self.tx_bufs = self.tx_bufs.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Reads the slave's pending buffer, up to its maximum buffer size, then signals the slave that
+    /// the read is complete. Returns `EspError::Capacity` if it doesn't fit in `buf`: Each read starts a new
+    /// buffer on the slave, so reading part of one would lose the rest.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        let available = self.rx_available()?;
+        if available == 0 {
+            return Ok(0);
+        }
+
+        let n = (available as usize).min(self.max_rx_len as usize);
+        if buf.len() < n {
+            return Err(EspError::Capacity);
+        }
+
+        let cmd = SpiHdCmd::RdDma.val(self.lines);
+        self.bus
+            .read(cmd, 0, READ_DUMMY_CYCLES, self.lines, &mut buf[..n])?;
+        self.send_cmd(SpiHdCmd::Int0)?;
+
+        self.rx_bytes = self.rx_bytes.wrapping_add(n as u32);
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        FrameDecoder, MsgParsed,
+        ble::HciPkt,
+        header::{PL_HEADER_SIZE, build_frame_ble},
+    };
+
+    /// A bus transaction, as recorded by `MockBus`: command byte, address, dummy cycles (reads only),
+    /// line mode, and the number of data bytes.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    struct Xfer {
+        cmd: u8,
+        addr: u8,
+        dummy: Option<u8>,
+        lines: DataLines,
+        len: usize,
+    }
+
+    impl core::fmt::Debug for DataLines {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let s = match self {
+                Self::Single => "Single",
+                Self::Dual => "Dual",
+                Self::Quad => "Quad",
+            };
+            f.write_str(s)
+        }
+    }
+
+    /// Emulates the slave's registers, and DMA buffers.
+    #[derive(Default)]
+    struct MockBus {
+        /// Registers 0x00 - 0x14, indexed by address / 4.
+        regs: [u32; 6],
+        /// Reads of `REG_SLAVE_READY` before it reports ready.
+        ready_after: u32,
+        /// Bytes the slave has queued for the host.
+        to_host: Vec<u8>,
+        /// Bytes written to the slave's DMA buffer.
+        from_host: Vec<u8>,
+        log: Vec<Xfer>,
+    }
+
+    impl MockBus {
+        fn reg(&mut self, addr: u8) -> &mut u32 {
+            &mut self.regs[addr as usize / 4]
+        }
+
+        /// Queue a frame for the host, and advance the slave's TX count.
+        fn queue(&mut self, frame: &[u8]) {
+            self.to_host.extend_from_slice(frame);
+            *self.reg(REG_TX_BUF_LEN) += frame.len() as u32;
+        }
+    }
+
+    /// Strip the line mode prefix from a command byte.
+    fn base(cmd: u8) -> u8 {
+        cmd & 0x0F
+    }
+
+    impl SpiHdBus for MockBus {
+        fn read(
+            &mut self,
+            cmd: u8,
+            addr: u8,
+            dummy_cycles: u8,
+            lines: DataLines,
+            data: &mut [u8],
+        ) -> Result<(), EspError> {
+            self.log.push(Xfer {
+                cmd,
+                addr,
+                dummy: Some(dummy_cycles),
+                lines,
+                len: data.len(),
+            });
+
+            match base(cmd) {
+                0x02 => {
+                    let val = if addr == REG_SLAVE_READY && self.ready_after > 0 {
+                        self.ready_after -= 1;
+                        0
+                    } else {
+                        *self.reg(addr)
+                    };
+                    data.copy_from_slice(&val.to_le_bytes());
+                }
+                0x04 => {
+                    data.copy_from_slice(&self.to_host[..data.len()]);
+                    self.to_host.drain(..data.len());
+                }
+                _ => return Err(EspError::Comms),
+            }
+
+            Ok(())
+        }
+
+        fn write(
+            &mut self,
+            cmd: u8,
+            addr: u8,
+            lines: DataLines,
+            data: &[u8],
+        ) -> Result<(), EspError> {
+            self.log.push(Xfer {
+                cmd,
+                addr,
+                dummy: None,
+                lines,
+                len: data.len(),
+            });
+
+            match base(cmd) {
+                0x01 => *self.reg(addr) = u32::from_le_bytes(data.try_into().unwrap()),
+                0x03 => self.from_host.extend_from_slice(data),
+                0x07 | 0x08 => (),
+                _ => return Err(EspError::Comms),
+            }
+
+            Ok(())
+        }
+    }
+
+    /// `EspError` doesn't implement `Debug`, so can't be `unwrap`ped.
+    fn ok<T>(result: Result<T, EspError>) -> T {
+        match result {
+            Ok(v) => v,
+            Err(_) => panic!("unexpected error"),
+        }
+    }
+
+    fn slave(lines: DataLines) -> SpiHdTransport<MockBus> {
+        let mut bus = MockBus::default();
+        *bus.reg(REG_SLAVE_READY) = SLAVE_READY;
+        *bus.reg(REG_MAX_TX_BUF_LEN) = 1_600;
+        *bus.reg(REG_MAX_RX_BUF_LEN) = 1_600;
+
+        SpiHdTransport::new(bus, lines)
+    }
+
+    #[test]
+    fn cmd_line_mode_prefixes() {
+        use SpiHdCmd::*;
+
+        for cmd in [WrBuf, RdBuf, WrDma, RdDma, SegEnd] {
+            let b = cmd as u8;
+            assert_eq!(cmd.val(DataLines::Single), b);
+            assert_eq!(cmd.val(DataLines::Dual), 0x50 | b);
+            assert_eq!(cmd.val(DataLines::Quad), 0xA0 | b);
+        }
+
+        // These have no address or data phase, so have no prefix.
+        for cmd in [EnQpi, WrEnd, Int0, Int1, Int2] {
+            for lines in [DataLines::Single, DataLines::Dual, DataLines::Quad] {
+                assert_eq!(cmd.val(lines), cmd as u8);
+            }
+        }
+
+        assert_eq!(RdBuf.val(DataLines::Quad), 0xA2);
+        assert_eq!(WrDma.val(DataLines::Dual), 0x53);
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut t = slave(DataLines::Dual);
+        t.bus.regs = [0x11, 0x2233, 0x44_5566, 0x7788_99AA, 0xBBCC_DDEE, 0xFF];
+
+        for (i, reg) in (0x00..=0x14).step_by(4).enumerate() {
+            assert_eq!(ok(t.read_reg(reg)), t.bus.regs[i]);
+
+            let xfer = *t.bus.log.last().unwrap();
+            assert_eq!(
+                xfer,
+                Xfer {
+                    cmd: 0x52,
+                    addr: reg,
+                    dummy: Some(READ_DUMMY_CYCLES),
+                    lines: DataLines::Dual,
+                    len: 4,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn write_register() {
+        let mut t = slave(DataLines::Single);
+        ok(t.write_reg(REG_SLAVE_CTRL, 0x0102_0304));
+
+        assert_eq!(t.bus.regs[5], 0x0102_0304);
+        assert_eq!(t.bus.log[0].cmd, 0x01);
+        assert_eq!(t.bus.log[0].len, 4);
+    }
+
+    #[test]
+    fn init() {
+        let mut t = slave(DataLines::Quad);
+        t.bus.ready_after = 3;
+        *t.bus.reg(REG_MAX_TX_BUF_LEN) = 1_024;
+        *t.bus.reg(REG_MAX_RX_BUF_LEN) = 512;
+
+        ok(t.init());
+
+        // "TX" and "RX" are swapped, since the registers are from the slave's perspective.
+        assert_eq!(t.max_rx_len, 1_024);
+        assert_eq!(t.max_tx_len, 512);
+        assert_eq!(t.bus.regs[5], CTRL_DATAPATH_ON);
+        // 4 polls of the ready register, 2 buffer size reads, and the control write.
+        assert_eq!(t.bus.log.len(), 7);
+    }
+
+    #[test]
+    fn init_timeout() {
+        let mut t = slave(DataLines::Single);
+        *t.bus.reg(REG_SLAVE_READY) = 0;
+
+        assert!(matches!(t.init(), Err(EspError::Timeout)));
+    }
+
+    #[test]
+    fn write_read_frame() {
+        let mut t = slave(DataLines::Quad);
+        ok(t.init());
+        t.bus.log.clear();
+
+        let mut frame = [0; 64];
+        let frame_len = build_frame_ble(&mut frame, HciPkt::Cmd, &[0x03, 0x0C, 0x00]);
+        let frame = &frame[..frame_len];
+
+        // No buffer available on the slave.
+        assert!(matches!(t.write(frame), Err(EspError::Timeout)));
+        t.bus.log.clear();
+
+        *t.bus.reg(REG_RX_BUF_LEN) = 1;
+        ok(t.write(frame));
+        assert_eq!(t.bus.from_host, frame);
+
+        let cmds: Vec<u8> = t.bus.log.iter().map(|x| x.cmd).collect();
+        assert_eq!(cmds, [0xA2, 0xA3, 0x07]);
+        assert_eq!(ok(t.tx_buffers_available()), 0);
+
+        // The slave echoes the frame back.
+        t.bus.queue(frame);
+        assert_eq!(ok(t.rx_available()), frame_len as u32);
+        t.bus.log.clear();
+
+        let mut decoder = FrameDecoder::<1_600>::new();
+        assert_eq!(ok(decoder.read_from(&mut t)), frame_len);
+
+        let cmds: Vec<u8> = t.bus.log.iter().map(|x| x.cmd).collect();
+        assert_eq!(cmds, [0xA2, 0xA4, 0x08]);
+        assert_eq!(ok(t.rx_available()), 0);
+        assert_eq!(ok(t.read(&mut [0; 16])), 0);
+
+        match decoder.next_frame() {
+            Some(Ok(MsgParsed::Hci(msg))) => assert_eq!(msg.data, &frame[PL_HEADER_SIZE..]),
+            _ => panic!("expected an HCI frame"),
+        }
+    }
+
+    #[test]
+    fn read_into_small_buf() {
+        let mut t = slave(DataLines::Single);
+        ok(t.init());
+
+        let mut frame = [0; 64];
+        let frame_len = build_frame_ble(&mut frame, HciPkt::Evt, &[0x0E, 0x01, 0x00]);
+        t.bus.queue(&frame[..frame_len]);
+        t.bus.log.clear();
+
+        // Reading part of the slave's buffer would lose the rest, so nothing is read.
+        let mut buf = [0; 8];
+        assert!(matches!(t.read(&mut buf), Err(EspError::Capacity)));
+        assert!(t.bus.log.iter().all(|x| x.cmd != 0x04 && x.cmd != 0x08));
+        assert_eq!(ok(t.rx_available()), frame_len as u32);
+
+        let mut buf = [0; 64];
+        assert_eq!(ok(t.read(&mut buf)), frame_len);
+        assert_eq!(buf[..frame_len], frame[..frame_len]);
+    }
+}
@@ -0,0 +1,672 @@
+//! A station supervisor: Tracks the link state, and reconnects with exponential backoff. It's driven by
+//! events (`EventStaConnected`, `EventStaDisconnected`, `EventEspInit`, and heartbeats), and `poll`, which
+//! sends connection attempts using the blocking call-style API in the `device` module.
+//!
+//! Several known networks may be added. After `attempts_per_network` consecutive failures, or an authentication
+//! failure, e.g. due to a wrong password, the supervisor moves on to the next. Use `rank_networks` to order them by RSSI, from a `ScanManager`'s results.
+//!
+//! ```ignore
+//! let mut sta = StaSupervisor::<4>::new();
+//! sta.on_state_change = Some(|state| println!("Link state: {}", state));
+//!
+//! sta.add_network(WifiStaConfig::new(b"home", b"password")?)?;
+//! sta.add_network(WifiStaConfig::new(b"backup", b"password")?)?;
+//! sta.rank_networks(&scanner);
+//! sta.start(dev.now_ms());
+//!
+//! loop {
+//!     // Pass each event received. `EspHosted` queues events that arrive during calls.
+//!     dev.poll()?;
+//!     while let Some(event) = dev.next_event() {
+//!         sta.on_event(&event, dev.now_ms());
+//!     }
+//!
+//!     sta.poll(&mut dev)?;
+//! }
+//! ```
+
+use core::cmp::Reverse;
+
+use defmt::Format;
+use heapless::Vec;
+
+use crate::{
+    EspError, EspHosted, ScanManager, device,
+    event::Event,
+    proto_data::{WifiConfig, WifiStaConfig},
+    transport::Transport,
+    wifi::DisconnectCategory,
+};
+
+/// The delay before the first reconnection attempt, in ms. This doubles with each consecutive failure.
+pub const DEFAULT_MIN_BACKOFF_MS: u32 = 1_000;
+pub const DEFAULT_MAX_BACKOFF_MS: u32 = 60_000;
+/// If no `EventStaConnected` or `EventStaDisconnected` arrives this long after connecting, in ms, the attempt
+/// is treated as a failure.
+pub const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 20_000;
+pub const DEFAULT_ATTEMPTS_PER_NETWORK: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum LinkState {
+    /// Not trying to connect. This is the state before `start`, after `stop`, and after the ESP restarts.
+    Idle,
+    /// Waiting for the result of a connection attempt.
+    Connecting,
+    Connected,
+    /// Waiting to retry, after a failed attempt or a lost connection.
+    Disconnected,
+}
+
+/// A network we may connect to.
+pub struct KnownNetwork {
+    pub cfg: WifiStaConfig,
+    /// The RSSI found in the scan passed to `rank_networks`, if found.
+    pub rssi: Option<i8>,
+}
+
+/// Supervises the station connection, to up to `N` known networks.
+pub struct StaSupervisor<const N: usize> {
+    networks: Vec<KnownNetwork, N>,
+    state: LinkState,
+    /// The index of the network we're connected to, or trying.
+    current: usize,
+    /// Whether `current`'s config has been sent to the ESP.
+    configured: bool,
+    /// Consecutive failures on the current network.
+    attempts: u8,
+    /// Consecutive failures on any network. Sets the backoff.
+    failures: u32,
+    /// When to next try connecting, or when the current attempt times out, in ms.
+    deadline_ms: u32,
+    last_heartbeat_ms: Option<u32>,
+    pub min_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    pub connect_timeout_ms: u32,
+    pub attempts_per_network: u8,
+    /// If set, and no heartbeat event arrives for this long, in ms, the connection is treated as lost. Enable
+    /// heartbeats on the ESP with `cfg_heartbeat`, using a shorter duration.
+    pub heartbeat_timeout_ms: Option<u32>,
+    /// Called with the new state, each time it changes.
+    pub on_state_change: Option<fn(LinkState)>,
+}
+
+impl<const N: usize> Default for StaSupervisor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> StaSupervisor<N> {
+    pub const fn new() -> Self {
+        Self {
+            networks: Vec::new(),
+            state: LinkState::Idle,
+            current: 0,
+            configured: false,
+            attempts: 0,
+            failures: 0,
+            deadline_ms: 0,
+            last_heartbeat_ms: None,
+            min_backoff_ms: DEFAULT_MIN_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+            connect_timeout_ms: DEFAULT_CONNECT_TIMEOUT_MS,
+            attempts_per_network: DEFAULT_ATTEMPTS_PER_NETWORK,
+            heartbeat_timeout_ms: None,
+            on_state_change: None,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Add a network to the end of the list. Returns `EspError::Capacity` if the list is full.
+    pub fn add_network(&mut self, cfg: WifiStaConfig) -> Result<(), EspError> {
+        self.networks
+            .push(KnownNetwork { cfg, rssi: None })
+            .map_err(|_| EspError::Capacity)
+    }
+
+    pub fn networks(&self) -> &[KnownNetwork] {
+        &self.networks
+    }
+
+    /// The network we're connected to, or trying.
+    pub fn current_network(&self) -> Option<&WifiStaConfig> {
+        self.networks.get(self.current).map(|n| &n.cfg)
+    }
+
+    /// Order the known networks by their strongest AP's RSSI in `scan`. Networks that weren't found go last. The
+    /// next connection attempt starts with the strongest; this doesn't affect an existing connection.
+    pub fn rank_networks<const M: usize>(&mut self, scan: &ScanManager<M>) {
+        for network in self.networks.iter_mut() {
+            network.rssi = scan.find_ssid(&network.cfg.ssid).map(|r| r.record.rssi);
+        }
+
+        let current_ssid = self.current_network().map(|c| c.ssid.clone());
+
+        // `None` sorts below any RSSI.
+        self.networks.sort_unstable_by_key(|n| Reverse(n.rssi));
+
+        if self.state == LinkState::Connected {
+            // Keep pointing at the network we're connected to.
+            self.current = self
+                .networks
+                .iter()
+                .position(|n| Some(&n.cfg.ssid) == current_ssid.as_ref())
+                .unwrap_or(0);
+        } else {
+            self.current = 0;
+            self.configured = false;
+            self.attempts = 0;
+        }
+    }
+
+    /// Start connecting, and reconnecting after failures. Wi-Fi must be initialized, and started in station mode.
+    pub fn start(&mut self, now_ms: u32) {
+        if self.state == LinkState::Idle {
+            self.failures = 0;
+            self.attempts = 0;
+            self.deadline_ms = now_ms;
+            self.set_state(LinkState::Disconnected);
+        }
+    }
+
+    /// Disconnect, and stop reconnecting.
+    pub fn stop<T: Transport>(&mut self, dev: &mut EspHosted<T>) -> Result<(), EspError> {
+        let prev = self.state;
+        self.set_state(LinkState::Idle);
+
+        if prev == LinkState::Connected || prev == LinkState::Connecting {
+            device::disconnect(dev)?;
+        }
+        Ok(())
+    }
+
+    /// Update the state from an event. Returns the new state, if it changed.
+    pub fn on_event(&mut self, event: &Event, now_ms: u32) -> Option<LinkState> {
+        let prev = self.state;
+
+        match event {
+            Event::Heartbeat(_) => self.last_heartbeat_ms = Some(now_ms),
+            // The ESP has restarted, and lost its configuration. Re-initialize Wi-Fi, then call `start`.
+            Event::EspInit => {
+                self.configured = false;
+                self.last_heartbeat_ms = None;
+                self.set_state(LinkState::Idle);
+            }
+            Event::StaConnected(_) if self.state != LinkState::Idle => {
+                self.attempts = 0;
+                self.failures = 0;
+                // Time the heartbeat timeout from connecting, vice a heartbeat from before.
+                if self.heartbeat_timeout_ms.is_some() {
+                    self.last_heartbeat_ms = Some(now_ms);
+                }
+                self.set_state(LinkState::Connected);
+            }
+            // Ignored while `Disconnected`, so a late event doesn't count as a second failure.
+            Event::StaDisconnected(ev)
+                if matches!(self.state, LinkState::Connecting | LinkState::Connected) =>
+            {
+                // Retrying with a wrong password won't help; move on to the next network.
+                if ev.reason.category() == DisconnectCategory::AuthFailure {
+                    self.attempts = self.attempts_per_network;
+                }
+                self.fail(now_ms)
+            }
+            _ => (),
+        }
+
+        (self.state != prev).then_some(self.state)
+    }
+
+    /// Check timeouts, and start a connection attempt if one is due. Call this periodically. Returns the
+    /// new state, if it changed. If sending the attempt fails, a retry is scheduled, and the error returned.
+    pub fn poll<T: Transport>(
+        &mut self,
+        dev: &mut EspHosted<T>,
+    ) -> Result<Option<LinkState>, EspError> {
+        let prev = self.state;
+        let now_ms = dev.now_ms();
+
+        // Only a live connection can be lost. During backoff, a stale heartbeat time would count as a second
+        // failure.
+        if let (Some(timeout), Some(last)) = (self.heartbeat_timeout_ms, self.last_heartbeat_ms)
+            && self.state == LinkState::Connected
+            && now_ms.wrapping_sub(last) >= timeout
+        {
+            self.fail(now_ms);
+        }
+
+        let due = reached(now_ms, self.deadline_ms);
+
+        match self.state {
+            LinkState::Connecting if due => self.fail(now_ms),
+            LinkState::Disconnected if due && !self.networks.is_empty() => {
+                if let Err(e) = self.connect(dev) {
+                    self.fail(now_ms);
+                    return Err(e);
+                }
+
+                self.deadline_ms = now_ms.wrapping_add(self.connect_timeout_ms);
+                self.set_state(LinkState::Connecting);
+            }
+            _ => (),
+        }
+
+        Ok((self.state != prev).then_some(self.state))
+    }
+
+    fn connect<T: Transport>(&mut self, dev: &mut EspHosted<T>) -> Result<(), EspError> {
+        if !self.configured {
+            let cfg = WifiConfig::Sta(self.networks[self.current].cfg.clone());
+            device::set_config(dev, &cfg)?;
+            self.configured = true;
+        }
+
+        device::connect(dev)
+    }
+
+    /// Record a failed attempt, or a lost connection, and schedule a retry.
+    fn fail(&mut self, now_ms: u32) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.failures = self.failures.saturating_add(1);
+
+        if self.attempts >= self.attempts_per_network {
+            self.select((self.current + 1) % self.networks.len().max(1));
+        }
+
+        self.deadline_ms = now_ms.wrapping_add(self.backoff_ms());
+        self.set_state(LinkState::Disconnected);
+    }
+
+    /// The delay before the next attempt: `min_backoff_ms`, doubled for each consecutive failure after the first.
+    fn backoff_ms(&self) -> u32 {
+        let shift = self.failures.saturating_sub(1).min(16);
+        self.min_backoff_ms
+            .saturating_mul(1 << shift)
+            .min(self.max_backoff_ms)
+    }
+
+    fn select(&mut self, i: usize) {
+        if i != self.current {
+            self.current = i;
+            self.configured = false;
+        }
+        self.attempts = 0;
+    }
+
+    fn set_state(&mut self, state: LinkState) {
+        if state == self.state {
+            return;
+        }
+
+        // The heartbeat timeout only applies while connected.
+        if self.state == LinkState::Connected {
+            self.last_heartbeat_ms = None;
+        }
+
+        self.state = state;
+        if let Some(f) = self.on_state_change {
+            f(state);
+        }
+    }
+}
+
+/// If `now_ms` is at or after `deadline_ms`, allowing for wrapping.
+fn reached(now_ms: u32, deadline_ms: u32) -> bool {
+    (now_ms.wrapping_sub(deadline_ms) as i32) >= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::tests::{MockEsp, now_ms, set_now_ms},
+        event::{StaConnected, StaDisconnected},
+        proto_data::{EventHeartbeat, RpcId},
+        wifi::{DisconnectReason, WifiApRecord},
+    };
+
+    use LinkState::*;
+
+    #[derive(Clone, Copy)]
+    enum Step {
+        /// Set the clock, in ms.
+        At(u32),
+        Poll,
+        StaConnected,
+        StaDisconnected(DisconnectReason),
+        Heartbeat,
+        EspInit,
+    }
+
+    use Step::*;
+
+    struct Case {
+        name: &'static str,
+        heartbeat_timeout_ms: Option<u32>,
+        start: bool,
+        /// Each step, and the state expected after it.
+        steps: &'static [(Step, LinkState)],
+    }
+
+    fn dev() -> EspHosted<MockEsp> {
+        set_now_ms(0);
+        EspHosted::new(MockEsp::default(), now_ms)
+    }
+
+    fn sta(ssids: &[&[u8]]) -> StaSupervisor<4> {
+        let mut sta = StaSupervisor::new();
+        for ssid in ssids {
+            sta.add_network(WifiStaConfig::new(ssid, b"password").unwrap_or_else(|_| panic!()))
+                .unwrap_or_else(|_| panic!("full"));
+        }
+        sta
+    }
+
+    fn step(sta: &mut StaSupervisor<4>, dev: &mut EspHosted<MockEsp>, step: Step) {
+        let event = match step {
+            At(ms) => return set_now_ms(ms),
+            Poll => {
+                assert!(sta.poll(dev).is_ok());
+                return;
+            }
+            StaConnected => Event::StaConnected(StaConnected::default()),
+            StaDisconnected(reason) => Event::StaDisconnected(StaDisconnected {
+                reason,
+                reason_code: reason as u16,
+                ..Default::default()
+            }),
+            Heartbeat => Event::Heartbeat(EventHeartbeat { number: 0 }),
+            EspInit => Event::EspInit,
+        };
+        sta.on_event(&event, now_ms());
+    }
+
+    fn ssid(sta: &StaSupervisor<4>) -> &[u8] {
+        &sta.current_network()
+            .unwrap_or_else(|| panic!("no network"))
+            .ssid
+    }
+
+    fn name(state: LinkState) -> &'static str {
+        match state {
+            Idle => "Idle",
+            Connecting => "Connecting",
+            Connected => "Connected",
+            Disconnected => "Disconnected",
+        }
+    }
+
+    const CASES: &[Case] = &[
+        Case {
+            name: "connect and reconnect",
+            heartbeat_timeout_ms: None,
+            start: true,
+            steps: &[
+                (Poll, Connecting),
+                (StaConnected, Connected),
+                (At(5_000), Connected),
+                (
+                    StaDisconnected(DisconnectReason::BeaconTimeout),
+                    Disconnected,
+                ),
+                // The backoff is `min_backoff_ms` after the first failure.
+                (At(5_999), Disconnected),
+                (Poll, Disconnected),
+                (At(6_000), Disconnected),
+                (Poll, Connecting),
+                (StaConnected, Connected),
+            ],
+        },
+        Case {
+            name: "idle until started",
+            heartbeat_timeout_ms: None,
+            start: false,
+            steps: &[
+                (Poll, Idle),
+                (StaConnected, Idle),
+                (StaDisconnected(DisconnectReason::BeaconTimeout), Idle),
+            ],
+        },
+        Case {
+            name: "connect timeout",
+            heartbeat_timeout_ms: None,
+            start: true,
+            steps: &[
+                (Poll, Connecting),
+                (At(DEFAULT_CONNECT_TIMEOUT_MS - 1), Connecting),
+                (Poll, Connecting),
+                (At(DEFAULT_CONNECT_TIMEOUT_MS), Connecting),
+                (Poll, Disconnected),
+            ],
+        },
+        Case {
+            name: "late disconnect doesn't count twice",
+            heartbeat_timeout_ms: None,
+            start: true,
+            steps: &[
+                (Poll, Connecting),
+                (StaDisconnected(DisconnectReason::NoApFound), Disconnected),
+                (StaDisconnected(DisconnectReason::NoApFound), Disconnected),
+                // A second failure would have doubled the backoff.
+                (At(DEFAULT_MIN_BACKOFF_MS), Disconnected),
+                (Poll, Connecting),
+            ],
+        },
+        Case {
+            name: "heartbeat timeout while connected",
+            heartbeat_timeout_ms: Some(5_000),
+            start: true,
+            steps: &[
+                (Poll, Connecting),
+                // No heartbeats while connecting; only the connect timeout applies.
+                (At(10_000), Connecting),
+                (Poll, Connecting),
+                (StaConnected, Connected),
+                (At(14_000), Connected),
+                (Heartbeat, Connected),
+                (At(18_999), Connected),
+                (Poll, Connected),
+                (At(19_000), Connected),
+                (Poll, Disconnected),
+                // The stale heartbeat doesn't fail the backoff again.
+                (At(19_500), Disconnected),
+                (Poll, Disconnected),
+                (At(20_000), Disconnected),
+                (Poll, Connecting),
+            ],
+        },
+        Case {
+            name: "ESP restart",
+            heartbeat_timeout_ms: Some(5_000),
+            start: true,
+            steps: &[
+                (Poll, Connecting),
+                (StaConnected, Connected),
+                (EspInit, Idle),
+                (At(60_000), Idle),
+                (Poll, Idle),
+            ],
+        },
+    ];
+
+    #[test]
+    fn state_transitions() {
+        for case in CASES {
+            let mut dev = dev();
+            let mut sta = sta(&[b"home"]);
+            sta.heartbeat_timeout_ms = case.heartbeat_timeout_ms;
+            if case.start {
+                sta.start(now_ms());
+            }
+
+            for (i, &(s, expected)) in case.steps.iter().enumerate() {
+                step(&mut sta, &mut dev, s);
+                assert!(
+                    sta.state() == expected,
+                    "{}: step {}: expected {}, got {}",
+                    case.name,
+                    i,
+                    name(expected),
+                    name(sta.state()),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let mut sta = sta(&[b"home"]);
+        sta.max_backoff_ms = 10_000;
+
+        for (failures, expected) in [
+            (1, 1_000),
+            (2, 2_000),
+            (3, 4_000),
+            (4, 8_000),
+            (5, 10_000),
+            (6, 10_000),
+            (u32::MAX, 10_000),
+        ] {
+            sta.failures = failures;
+            assert_eq!(sta.backoff_ms(), expected, "failures: {failures}");
+        }
+    }
+
+    #[test]
+    fn backoff_from_failed_attempts() {
+        let mut dev = dev();
+        let mut sta = sta(&[b"home"]);
+        sta.attempts_per_network = u8::MAX;
+        sta.start(0);
+
+        // Each attempt times out; the retry waits twice as long as the last.
+        let mut now = 0;
+        for backoff in [1_000, 2_000, 4_000, 8_000] {
+            step(&mut sta, &mut dev, Poll);
+            assert!(sta.state() == Connecting);
+
+            now += DEFAULT_CONNECT_TIMEOUT_MS;
+            set_now_ms(now);
+            step(&mut sta, &mut dev, Poll);
+            assert!(sta.state() == Disconnected);
+
+            set_now_ms(now + backoff - 1);
+            step(&mut sta, &mut dev, Poll);
+            assert!(sta.state() == Disconnected);
+
+            now += backoff;
+            set_now_ms(now);
+        }
+
+        // Connecting resets it.
+        step(&mut sta, &mut dev, Poll);
+        step(&mut sta, &mut dev, StaConnected);
+        step(
+            &mut sta,
+            &mut dev,
+            StaDisconnected(DisconnectReason::BeaconTimeout),
+        );
+        assert_eq!(sta.backoff_ms(), DEFAULT_MIN_BACKOFF_MS);
+    }
+
+    #[test]
+    fn rotate_after_attempts_per_network() {
+        let mut dev = dev();
+        let mut sta = sta(&[b"a", b"b"]);
+        sta.attempts_per_network = 2;
+        sta.min_backoff_ms = 0;
+        sta.start(0);
+
+        for expected in [b"a", b"a", b"b", b"b", b"a"] {
+            step(&mut sta, &mut dev, Poll);
+            assert!(sta.state() == Connecting);
+            assert_eq!(ssid(&sta), expected);
+            step(
+                &mut sta,
+                &mut dev,
+                StaDisconnected(DisconnectReason::NoApFound),
+            );
+        }
+
+        // The config is only sent when the network changes.
+        assert_eq!(dev.transport.count(RpcId::ReqWifiSetConfig), 3);
+        assert_eq!(dev.transport.count(RpcId::ReqWifiConnect), 5);
+    }
+
+    #[test]
+    fn auth_failure_skips_network() {
+        let mut dev = dev();
+        let mut sta = sta(&[b"a", b"b"]);
+        sta.min_backoff_ms = 0;
+        sta.start(0);
+
+        step(&mut sta, &mut dev, Poll);
+        assert_eq!(ssid(&sta), b"a");
+        step(
+            &mut sta,
+            &mut dev,
+            StaDisconnected(DisconnectReason::AuthFail),
+        );
+        assert_eq!(ssid(&sta), b"b");
+
+        // Other failures retry the same network.
+        step(&mut sta, &mut dev, Poll);
+        step(
+            &mut sta,
+            &mut dev,
+            StaDisconnected(DisconnectReason::BeaconTimeout),
+        );
+        assert_eq!(ssid(&sta), b"b");
+    }
+
+    fn scan(aps: &[(&[u8], i8)]) -> ScanManager<8> {
+        let mut scan = ScanManager::new();
+        for (i, &(ssid, rssi)) in aps.iter().enumerate() {
+            let record = WifiApRecord {
+                bssid: [i as u8; 6],
+                ssid: Vec::from_slice(ssid).unwrap_or_else(|_| panic!()),
+                rssi,
+                ..Default::default()
+            };
+            scan.insert(record, 0);
+        }
+        scan
+    }
+
+    #[test]
+    fn rank_networks_by_rssi() {
+        let mut sta = sta(&[b"a", b"b", b"c", b"d"]);
+        // Two APs for "b"; the strongest counts.
+        sta.rank_networks(&scan(&[(b"b", -80), (b"d", -40), (b"b", -60), (b"x", -30)]));
+
+        let ranked: Vec<(&[u8], Option<i8>), 4> = sta
+            .networks()
+            .iter()
+            .map(|n| (n.cfg.ssid.as_slice(), n.rssi))
+            .collect();
+        assert_eq!(ranked[0], (&b"d"[..], Some(-40)));
+        assert_eq!(ranked[1], (&b"b"[..], Some(-60)));
+        // Not found, so last.
+        assert_eq!(ranked[2].1, None);
+        assert_eq!(ranked[3].1, None);
+
+        assert_eq!(ssid(&sta), b"d");
+    }
+
+    #[test]
+    fn rank_networks_keeps_connection() {
+        let mut dev = dev();
+        let mut sta = sta(&[b"a", b"b"]);
+        sta.start(0);
+        step(&mut sta, &mut dev, Poll);
+        step(&mut sta, &mut dev, StaConnected);
+
+        sta.rank_networks(&scan(&[(b"a", -80), (b"b", -40)]));
+        assert_eq!(sta.networks()[0].cfg.ssid, b"b");
+        assert_eq!(ssid(&sta), b"a");
+        assert!(sta.state() == Connected);
+    }
+}
@@ -1,8 +1,10 @@
 //! From `esp_hosted_transport.h`
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use defmt::Format;
 
-use crate::{EspError, ble::HciPkt};
+use crate::{EspError, ble::HciPkt, parse_le};
 
 const PRIO_Q_SERIAL: u8 = 0;
 const PRIO_Q_BT: u8 = 1;
@@ -18,6 +20,8 @@ const SERIAL_IF_FILE: &str = "/dev/esps0";
 pub(crate) const RPC_EP_NAME_RSP: &str = "RPCRsp";
 pub(crate) const RPC_EP_NAME_EVT: &str = "RPCEvt";
 
+static VERIFY_CHECKSUMS: AtomicBool = AtomicBool::new(true);
+
 #[derive(Clone, Copy, PartialEq, Default, Format)]
 pub enum PacketType {
     #[default]
@@ -49,6 +53,45 @@ impl PacketType {
     }
 }
 
+/// The physical link to the ESP, e.g. UART, SPI, or SDIO. Implement this once for your hardware,
+/// then pass it to this library's functions. It handles both directions: Writing frames we've built,
+/// and reading bytes the ESP sends, e.g. into a `FrameDecoder`.
+pub trait Transport {
+    /// Write a complete frame to the ESP.
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError>;
+
+    /// Read bytes received from the ESP into `buf`. Returns the number of bytes read, which may be 0 if
+    /// no data is available. This may return a partial frame, or several frames.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError>;
+
+    /// For transports with a Data Ready line, e.g. SPI: Returns `true` if the ESP has data queued for the host.
+    /// Transports without one should leave this as the default, and return 0 from `read` if no data is available.
+    fn data_ready(&mut self) -> bool {
+        true
+    }
+
+    /// For transports with a Handshake line, e.g. SPI: Returns `true` if the ESP is ready for
+    /// a transaction.
+    fn handshake_ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// Allows passing a write-only closure as the transport. Reads are then left to the application, e.g. by
+/// parsing messages in a UART interrupt handler.
+impl<F> Transport for F
+where
+    F: FnMut(&[u8]) -> Result<(), EspError>,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self(buf)
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EspError> {
+        Ok(0)
+    }
+}
+
 #[repr(u8)]
 pub(crate) enum SLAVE_CONFIG_PRIV_TAG_TYPE {
     HOST_CAPABILITIES = 0x44,
@@ -71,13 +114,55 @@ pub(crate) struct esp_priv_event {
 
 /// `System_design_with_rps_as_focus.md`, section 3.3: Checksum Calculation
 pub(crate) fn compute_checksum(buf: &[u8]) -> u16 {
-    let mut checksum = 0;
+    let mut checksum: u16 = 0;
     let mut i = 0;
 
     while i < buf.len() {
-        checksum += buf[i] as u16;
+        checksum = checksum.wrapping_add(buf[i] as u16);
         i += 1;
     }
 
     checksum
 }
+
+/// Check a received frame's checksum against the one in its payload header. The checksum field itself
+/// is treated as 0 during the computation, as it is when the sender computes it.
+pub(crate) fn verify_checksum(frame: &[u8]) -> bool {
+    if frame.len() < 8 {
+        return false;
+    }
+
+    let expected = parse_le!(frame, u16, 6..8);
+    let computed = compute_checksum(frame)
+        .wrapping_sub(frame[6] as u16)
+        .wrapping_sub(frame[7] as u16);
+
+    computed == expected
+}
+
+/// For use when the header was received separately from the rest of the frame, e.g. over SPI. `header` is
+/// the payload header's bytes, as received.
+pub(crate) fn verify_checksum_split(header: &[u8], payload: &[u8]) -> bool {
+    if header.len() < 8 {
+        return false;
+    }
+
+    let expected = parse_le!(header, u16, 6..8);
+    let computed = compute_checksum(header)
+        .wrapping_sub(header[6] as u16)
+        .wrapping_sub(header[7] as u16)
+        .wrapping_add(compute_checksum(payload));
+
+    computed == expected
+}
+
+/// Enable or disable checksum verification of received frames. It's enabled by default. Disabling it
+/// may be appropriate for transports that have their own link-level CRC, e.g. SDIO, or SPI with hardware CRC.
+/// When enabled, a frame with a mismatched checksum is rejected with `EspError::CrcMismatch`.
+pub fn set_checksum_verification(enabled: bool) {
+    VERIFY_CHECKSUMS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn checksum_verification_enabled() -> bool {
+    VERIFY_CHECKSUMS.load(Ordering::Relaxed)
+}
@@ -1,19 +1,28 @@
 //! This module contains Wi-Fi and BLE-specific functionality.
 
-use defmt::{Format, println};
+use core::{fmt, str::FromStr};
+
+use defmt::Format;
 use heapless::Vec;
 use num_enum::TryFromPrimitive;
 
 use crate::{
     EspError,
     WireType::{Len, Varint},
-    proto_data::{RpcId, RpcReqWifiInit, RpcReqWifiScanStart},
-    rpc::{Rpc, WireType, decode_tag, decode_varint, setup_rpc, write_rpc},
+    proto_data::{RpcId, RpcReqWifiInit, RpcReqWifiScanStart, WifiConfig, WifiStaList},
+    regulatory,
+    rpc::{
+        FieldVal, Fields, Rpc, WireType, check_resp, decode_int32, setup_rpc_in_place, write_rpc,
+        write_rpc_bytes, write_rpc_int32, write_rpc_len,
+    },
+    transport::Transport,
     util::write_empty_msg,
 };
 // todo: Macros may help.
 
-const MAX_AP_RECORDS: usize = 30; // todo: A/R.
+/// The maximum number of records returned by `parse_ap_records`. Use `ApRecords` directly to
+/// process any number.
+pub const MAX_AP_RECORDS: usize = 30;
 
 // /// Information about one BLE advertisement
 // #[derive(Debug)]
@@ -23,35 +32,52 @@ const MAX_AP_RECORDS: usize = 30; // todo: A/R.
 //     pub rssi: i8,
 // }
 
-/// From a data buffer (e.g. as part of the Rpc struct), parse into Access Point records.
-/// The data buffer passed starts post the RPC "header"; the same data we include in the `Rpc` struct.
-pub fn parse_ap_records(data: &[u8]) -> Result<Vec<WifiApRecord, MAX_AP_RECORDS>, EspError> {
-    let mut result = Vec::new();
+/// An iterator over the AP records in the response to `scan_get_ap_records`, or `scan_get_ap_record`.
+/// Records are parsed as they're reached. A non-zero `resp` code is returned as `EspError::Esp`.
+pub struct ApRecords<'a> {
+    fields: Fields<'a>,
+}
 
-    let mut i = 1; // todo: Not robust!
-    if data.len() == 0 {
-        println!("Empty data on parsing AP records.");
-        return Err(EspError::InvalidData);
+impl<'a> ApRecords<'a> {
+    /// The data buffer passed starts post the RPC "header"; the same data we include in the `Rpc` struct.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            fields: Fields::new(data),
+        }
     }
+}
 
-    let (num_records, nr_len) = decode_varint(&data[i..])?;
-    i += nr_len;
-
-    for _ in 0..num_records {
-        i += 1; // todo: Skipping over the tag for the records struct.
+impl Iterator for ApRecords<'_> {
+    type Item = Result<WifiApRecord, EspError>;
 
-        if i >= data.len() {
-            return Err(EspError::InvalidData);
+    fn next(&mut self) -> Option<Self::Item> {
+        // `ScanGetApRecords` fields: resp = 1, number = 2, ap_records = 3 (repeated).
+        // `ScanGetApRecord` fields: resp = 1, ap_record = 2.
+        for field in self.fields.by_ref() {
+            match field {
+                Ok((1, FieldVal::Varint(v))) => {
+                    if let Err(e) = check_resp(v) {
+                        return Some(Err(e));
+                    }
+                }
+                Ok((2 | 3, FieldVal::Len(v))) => return Some(WifiApRecord::from_bytes(v)),
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
         }
 
-        let (record_len, record_len_len) = decode_varint(&data[i..])?;
-        i += record_len_len;
+        None
+    }
+}
 
-        // todo: This won't work; you need to get the varint size of each field etc!
-        let (record, _record_size) = WifiApRecord::from_bytes(&data[i..i + record_len as usize])?;
-        i += record_len as usize;
+/// From a data buffer (e.g. as part of the Rpc struct), parse into Access Point records.
+/// The data buffer passed starts post the RPC "header"; the same data we include in the `Rpc` struct.
+/// Returns `EspError::Capacity` if there are more than `MAX_AP_RECORDS`.
+pub fn parse_ap_records(data: &[u8]) -> Result<Vec<WifiApRecord, MAX_AP_RECORDS>, EspError> {
+    let mut result = Vec::new();
 
-        result.push(record).map_err(|_| EspError::Capacity)?;
+    for record in ApRecords::new(data) {
+        result.push(record?).map_err(|_| EspError::Capacity)?;
     }
 
     Ok(result)
@@ -79,6 +105,8 @@ pub struct InitConfig {
     pub beacon_max_len: i32,
     pub mgmt_sbuf_num: i32,
     pub feature_caps: u64,
+    /// If true, power save (`set_ps`) also applies while the station is disconnected. Otherwise, the
+    /// modem stays awake until connected.
     pub sta_disconnected_pm: bool,
     pub espnow_max_encrypt_num: i32,
     pub magic: i32,
@@ -151,26 +179,26 @@ impl InitConfig {
 
         let mut i = 0;
 
-        write_rpc(buf, 1, v, c.static_rx_buf_num as u64, &mut i);
-        write_rpc(buf, 2, v, c.dynamic_rx_buf_num as u64, &mut i);
-        write_rpc(buf, 3, v, c.tx_buf_type as u64, &mut i);
-        write_rpc(buf, 4, v, c.static_tx_buf_num as u64, &mut i);
-        write_rpc(buf, 5, v, c.dynamic_tx_buf_num as u64, &mut i);
-        write_rpc(buf, 6, v, c.cache_tx_buf_num as u64, &mut i);
-        write_rpc(buf, 7, v, c.csi_enable as u64, &mut i);
-        write_rpc(buf, 8, v, c.ampdu_rx_enable as u64, &mut i);
-        write_rpc(buf, 9, v, c.ampdu_tx_enable as u64, &mut i);
-        write_rpc(buf, 10, v, c.amsdu_tx_enable as u64, &mut i);
-        write_rpc(buf, 11, v, c.nvs_enable as u64, &mut i);
-        write_rpc(buf, 12, v, c.nano_enable as u64, &mut i);
-        write_rpc(buf, 13, v, c.rx_ba_win as u64, &mut i);
-        write_rpc(buf, 14, v, c.wifi_task_core_id as u64, &mut i);
-        write_rpc(buf, 15, v, c.beacon_max_len as u64, &mut i);
-        write_rpc(buf, 16, v, c.mgmt_sbuf_num as u64, &mut i);
+        write_rpc_int32(buf, 1, c.static_rx_buf_num, &mut i);
+        write_rpc_int32(buf, 2, c.dynamic_rx_buf_num, &mut i);
+        write_rpc_int32(buf, 3, c.tx_buf_type, &mut i);
+        write_rpc_int32(buf, 4, c.static_tx_buf_num, &mut i);
+        write_rpc_int32(buf, 5, c.dynamic_tx_buf_num, &mut i);
+        write_rpc_int32(buf, 6, c.cache_tx_buf_num, &mut i);
+        write_rpc_int32(buf, 7, c.csi_enable, &mut i);
+        write_rpc_int32(buf, 8, c.ampdu_rx_enable, &mut i);
+        write_rpc_int32(buf, 9, c.ampdu_tx_enable, &mut i);
+        write_rpc_int32(buf, 10, c.amsdu_tx_enable, &mut i);
+        write_rpc_int32(buf, 11, c.nvs_enable, &mut i);
+        write_rpc_int32(buf, 12, c.nano_enable, &mut i);
+        write_rpc_int32(buf, 13, c.rx_ba_win, &mut i);
+        write_rpc_int32(buf, 14, c.wifi_task_core_id, &mut i);
+        write_rpc_int32(buf, 15, c.beacon_max_len, &mut i);
+        write_rpc_int32(buf, 16, c.mgmt_sbuf_num, &mut i);
         write_rpc(buf, 17, v, c.feature_caps, &mut i);
         write_rpc(buf, 18, v, c.sta_disconnected_pm as u64, &mut i);
-        write_rpc(buf, 19, v, c.espnow_max_encrypt_num as u64, &mut i);
-        write_rpc(buf, 20, v, c.magic as u64, &mut i);
+        write_rpc_int32(buf, 19, c.espnow_max_encrypt_num, &mut i);
+        write_rpc_int32(buf, 20, c.magic, &mut i);
 
         i
     }
@@ -212,13 +240,7 @@ impl ScanTime {
     pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
         let mut i = 0;
 
-        // todo size?
-        let mut scan_time_buf = [0; 8]; // Measured at 5 with u16 values.
-        let active_size = self.active.to_bytes(&mut scan_time_buf);
-
-        write_rpc(buf, 1, Len, active_size as u64, &mut i);
-        buf[i..i + active_size].copy_from_slice(&scan_time_buf[..active_size]);
-        i += active_size;
+        write_rpc_len(buf, 1, &mut i, |b| self.active.to_bytes(b));
 
         write_rpc(buf, 2, Varint, self.passive as u64, &mut i);
 
@@ -237,7 +259,7 @@ pub enum ScanType {
 /// Parameters for an SSID scan.
 /// Note: If setting most of these values to 0 or empty Vecs, ESP will use its default settings.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv418wifi_scan_config_t)
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ScanConfig {
     /// Can limit to a specific SSID or MAC. Empty means no filter.
     pub ssid: Vec<u8, 33>,
@@ -268,13 +290,7 @@ impl ScanConfig {
         write_rpc(buf, 4, Varint, self.show_hidden as u64, &mut i);
         write_rpc(buf, 5, Varint, self.scan_type as u64, &mut i);
 
-        // todo size?
-        let mut scan_time_buf = [0; 14]; // Measured at 10 with all fields configured as u16.
-        let scan_time_size = self.scan_time.to_bytes(&mut scan_time_buf);
-
-        write_rpc(buf, 6, Len, scan_time_size as u64, &mut i);
-        buf[i..i + scan_time_size].copy_from_slice(&scan_time_buf[..scan_time_size]);
-        i += scan_time_size;
+        write_rpc_len(buf, 6, &mut i, |b| self.scan_time.to_bytes(b));
 
         write_rpc(buf, 7, Varint, self.home_chan_dwell_time as u64, &mut i);
 
@@ -282,6 +298,40 @@ impl ScanConfig {
     }
 }
 
+/// How the station scans for the AP to connect to.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv418wifi_scan_method_t)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ScanMethod {
+    /// Connect to the first matching AP found.
+    #[default]
+    Fast = 0,
+    /// Scan all channels, then connect to the best matching AP, per `SortMethod`.
+    AllChannel = 1,
+}
+
+/// How to pick between several APs matching the station config.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv423wifi_sort_method_t)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SortMethod {
+    #[default]
+    Rssi = 0,
+    Security = 1,
+}
+
+/// How WPA3-SAE derives its password element.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv420wifi_sae_pwe_method_t)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SaePweMethod {
+    #[default]
+    Unspecified = 0,
+    HuntAndPeck = 1,
+    HashToElement = 2,
+    Both = 3,
+}
+
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv418wifi_second_chan_t)
 #[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
 #[repr(u8)]
@@ -344,7 +394,7 @@ pub enum WifiAnt {
 
 /// Structure describing Wi-Fi country-based regional restrictions.
 /// [docs][https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv414wifi_country_t]
-#[derive(Default, Format)]
+#[derive(Clone, Default, Format)]
 pub struct WifiCountry {
     /// Country code string.
     pub cc: [u8; 3],
@@ -353,39 +403,88 @@ pub struct WifiCountry {
     /// Total channel number of the allowed 2.4GHz Wi-Fi channels
     pub nchan: u8,
     pub max_tx_power: i8,
-    /// Enum. Auto for 0, Manual for 1
+    /// Enum. Auto for 0, Manual for 1. See `regulatory::POLICY_AUTO` and `POLICY_MANUAL`.
     pub policy: u8,
 }
 
+impl WifiCountry {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Len(v)) => {
+                    // The third character is the environment, e.g. ' ', 'O'utdoor, or 'I'ndoor.
+                    let len = v.len().min(result.cc.len());
+                    result.cc[..len].copy_from_slice(&v[..len]);
+                }
+                (2, FieldVal::Varint(v)) => result.schan = v as u8,
+                (3, FieldVal::Varint(v)) => result.nchan = v as u8,
+                (4, FieldVal::Varint(v)) => result.max_tx_power = decode_int32(v) as i8,
+                (5, FieldVal::Varint(v)) => result.policy = v as u8,
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc_bytes(buf, 1, &self.cc, &mut i);
+        write_rpc(buf, 2, WireType::Varint, self.schan as u64, &mut i);
+        write_rpc(buf, 3, WireType::Varint, self.nchan as u64, &mut i);
+        write_rpc_int32(buf, 4, self.max_tx_power as i32, &mut i);
+        write_rpc(buf, 5, WireType::Varint, self.policy as u64, &mut i);
+
+        i
+    }
+}
+
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416wifi_bandwidth_t)
 #[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
 #[repr(u8)]
 pub enum WifiBandwidth {
-    #[default]
-    HT20 = 0,
     /// 20 Mhz
-    BW20 = 1,
-    BW_HT40 = 2,
+    #[default]
+    HT20 = 1,
     /// 40 Mhz
-    BW40 = 3,
-    BW80 = 4,
-    BW160 = 5,
+    HT40 = 2,
+    BW80 = 3,
+    BW160 = 4,
     /// 80 + 80 Mhz
-    BW80_BW80 = 6,
+    BW80_BW80 = 5,
 }
 
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv417wifi_he_ap_info_t)
-#[derive(Default, Format)]
+#[derive(Clone, Copy, Default, Format)]
 // todo: The protobuf here doesn't match teh normal docs version
 pub struct WifiHeApInfo {
     pub bitmask: u32,
     pub bssid_index: u32,
 }
 
+impl WifiHeApInfo {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Varint(v)) => result.bitmask = v as u32,
+                (2, FieldVal::Varint(v)) => result.bssid_index = v as u32,
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 ///Description of a Wi-Fi AP.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416wifi_ap_record_t)
 // #[derive(Format)]
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct WifiApRecord {
     pub bssid: [u8; 6],
     pub ssid: Vec<u8, 33>,
@@ -416,116 +515,295 @@ pub struct WifiApRecord {
 }
 
 impl WifiApRecord {
-    pub fn from_bytes(buf: &[u8]) -> Result<(Self, usize), EspError> {
-        let mut i = 0;
+    /// Parse a single `wifi_ap_record` message. Fields we don't recognize are skipped.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
         let mut result = Self::default();
 
-        loop {
-            if i >= buf.len() {
-                break;
-            }
-            let (tag, tag_len) = decode_varint(&buf[i..])?;
-            i += tag_len;
-
-            let (field, _wire_type) = decode_tag(tag as u16);
-
-            match field {
-                1 => {
-                    let (field_len, field_len_len) = decode_varint(&buf[i..])?;
-                    i += field_len_len;
-                    // println!("BSSID buf: {:?}", &buf[i..i + field_len as usize]);
-
-                    if i + field_len as usize >= buf.len() {
-                        return Err(EspError::Capacity);
-                    }
-
-                    result
-                        .bssid
-                        .copy_from_slice(&buf[i..i + field_len as usize]);
-                    i += field_len as usize;
-                }
-                2 => {
-                    let (field_len, field_len_len) = decode_varint(&buf[i..])?;
-                    i += field_len_len;
-                    // println!("SSID buf: {:?}", &buf[i..i + field_len as usize]);
-
-                    if i + field_len as usize >= buf.len() {
-                        return Err(EspError::Capacity);
-                    }
-
-                    result.ssid = Vec::<_, 33>::from_slice(&buf[i..i + field_len as usize])
-                        .map_err(|_| EspError::InvalidData)?;
-                    i += field_len as usize;
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Len(v)) => {
+                    result.bssid = v.try_into().map_err(|_| EspError::InvalidData)?
                 }
-                3 => {
-                    result.primary = buf[i];
-                    i += 1;
+                (2, FieldVal::Len(v)) => {
+                    // This may be sent as a null-padded C string.
+                    let len = v.iter().position(|&b| b == 0).unwrap_or(v.len());
+                    result.ssid = Vec::from_slice(&v[..len]).map_err(|_| EspError::InvalidData)?;
                 }
-                4 => {
-                    result.second = buf[i].try_into().unwrap_or_default();
-                    i += 1;
+                (3, FieldVal::Varint(v)) => result.primary = v as u8,
+                (4, FieldVal::Varint(v)) => {
+                    result.second = (v as u8).try_into().unwrap_or_default()
                 }
-                5 => {
-                    result.rssi = buf[i] as i8;
-                    i += 10; // Non-zigzag protobuf negative val encoding... yikes.
+                (5, FieldVal::Varint(v)) => result.rssi = decode_int32(v) as i8,
+                (6, FieldVal::Varint(v)) => {
+                    result.authmode = (v as u8).try_into().unwrap_or_default()
                 }
-                6 => {
-                    result.authmode = buf[i].try_into().unwrap_or_default();
-                    i += 1;
+                (7, FieldVal::Varint(v)) => {
+                    result.pairwise_cipher = (v as u8).try_into().unwrap_or_default()
                 }
-                7 => {
-                    result.pairwise_cipher = buf[i].try_into().unwrap_or_default();
-                    i += 1;
+                (8, FieldVal::Varint(v)) => {
+                    result.group_cipher = (v as u8).try_into().unwrap_or_default()
                 }
-                8 => {
-                    result.group_cipher = buf[i].try_into().unwrap_or_default();
-                    i += 1;
-                }
-                9 => {
-                    result.ant = buf[i].try_into().unwrap_or_default();
-                    i += 1;
-                }
-                10 => {
-                    let (val, len) = decode_varint(&buf[i..])?;
-                    result.bitmask = val as u32;
-                    i += len;
-                }
-                11 => {
-                    let (country_len, country_len_len) = decode_varint(&buf[i..])?;
-                    i += country_len_len;
-                    // todo: Parse here.
-                    i += country_len as usize;
-                }
-                12 => {
-                    let (he_ap_len, he_ap_len_len) = decode_varint(&buf[i..])?;
-                    i += he_ap_len_len;
-                    // todo: Parse here.
-                    i += he_ap_len as usize;
-                }
-                13 => {
-                    result.bandwidth = buf[i].try_into().unwrap_or_default();
-                    i += 1;
-                }
-                14 => {
-                    result.vht_ch_freq1 = buf[i];
-                    i += 1;
-                }
-                15 => {
-                    result.vht_ch_freq2 = buf[i];
-                    i += 1;
-                }
-                _ => {
-                    println!("Unparsed field: {:?}", field);
+                (9, FieldVal::Varint(v)) => result.ant = (v as u8).try_into().unwrap_or_default(),
+                (10, FieldVal::Varint(v)) => result.bitmask = v as u32,
+                (11, FieldVal::Len(v)) => result.country = WifiCountry::from_bytes(v)?,
+                (12, FieldVal::Len(v)) => result.he_ap = WifiHeApInfo::from_bytes(v)?,
+                (13, FieldVal::Varint(v)) => {
+                    result.bandwidth = (v as u8).try_into().unwrap_or_default()
                 }
+                (14, FieldVal::Varint(v)) => result.vht_ch_freq1 = v as u8,
+                (15, FieldVal::Varint(v)) => result.vht_ch_freq2 = v as u8,
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Why the station disconnected, or failed to connect. Included in `EventStaDisconnected`. Values below 200 are
+/// 802.11 reason codes; ones from 200 are specific to ESP-IDF.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/wifi.html#wi-fi-reason-code)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u16)]
+pub enum DisconnectReason {
+    /// A code we don't know of. 0 is reserved in 802.11, so isn't sent as a reason. Keep the raw code to tell
+    /// these apart; see e.g. `StaDisconnected::reason_code`.
+    #[default]
+    Unknown = 0,
+    Unspecified = 1,
+    AuthExpire = 2,
+    AuthLeave = 3,
+    DisassocDueToInactivity = 4,
+    AssocTooMany = 5,
+    Class2FrameFromNonauthSta = 6,
+    Class3FrameFromNonassocSta = 7,
+    /// We disconnected, e.g. with `disconnect`.
+    AssocLeave = 8,
+    AssocNotAuthed = 9,
+    DisassocPwrcapBad = 10,
+    DisassocSupchanBad = 11,
+    BssTransitionDisassoc = 12,
+    IeInvalid = 13,
+    MicFailure = 14,
+    /// Often caused by a wrong password.
+    FourWayHandshakeTimeout = 15,
+    GroupKeyUpdateTimeout = 16,
+    IeIn4wayDiffers = 17,
+    GroupCipherInvalid = 18,
+    PairwiseCipherInvalid = 19,
+    AkmpInvalid = 20,
+    UnsuppRsnIeVersion = 21,
+    InvalidRsnIeCap = 22,
+    Ieee802_1xAuthFailed = 23,
+    CipherSuiteRejected = 24,
+    TdlsPeerUnreachable = 25,
+    TdlsUnspecified = 26,
+    SspRequestedDisassoc = 27,
+    NoSspRoamingAgreement = 28,
+    BadCipherOrAkm = 29,
+    NotAuthorizedThisLocation = 30,
+    ServiceChangePrecludesTs = 31,
+    UnspecifiedQos = 32,
+    NotEnoughBandwidth = 33,
+    MissingAcks = 34,
+    ExceededTxop = 35,
+    StaLeaving = 36,
+    EndBa = 37,
+    UnknownBa = 38,
+    Timeout = 39,
+    PeerInitiated = 46,
+    ApInitiated = 47,
+    InvalidFtActionFrameCount = 48,
+    InvalidPmkid = 49,
+    InvalidMde = 50,
+    InvalidFte = 51,
+    TransmissionLinkEstablishFailed = 67,
+    AlternativeChannelOccupied = 68,
+    /// We stopped receiving the AP's beacons.
+    BeaconTimeout = 200,
+    NoApFound = 201,
+    AuthFail = 202,
+    AssocFail = 203,
+    HandshakeTimeout = 204,
+    ConnectionFail = 205,
+    ApTsfReset = 206,
+    Roaming = 207,
+    AssocComebackTimeTooLong = 208,
+    SaQueryTimeout = 209,
+    NoApFoundWCompatibleSecurity = 210,
+    NoApFoundInAuthmodeThreshold = 211,
+    NoApFoundInRssiThreshold = 212,
+}
+
+/// Broad categories of `DisconnectReason`, for deciding how to respond.
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum DisconnectCategory {
+    /// Authentication failed. This is usually a wrong password, and retrying is unlikely to help.
+    AuthFailure,
+    /// No AP matching the config was found, or none met the RSSI and auth mode thresholds.
+    ApNotFound,
+    /// An established link was lost, e.g. due to weak signal. Retrying is likely to help.
+    LinkLost,
+    /// The AP refused the connection, e.g. because it's full, or our security settings don't match.
+    Rejected,
+    /// We disconnected.
+    Local,
+    Roaming,
+    Other,
+    /// The reason code isn't one we know of.
+    Unknown,
+}
+
+impl DisconnectReason {
+    /// Convert a raw `wifi_err_reason_t` value. Codes we don't know of map to `Unknown`.
+    pub fn from_code(code: u16) -> Self {
+        code.try_into().unwrap_or(Self::Unknown)
+    }
+
+    pub fn category(&self) -> DisconnectCategory {
+        use DisconnectCategory::*;
+        use DisconnectReason as R;
+
+        match self {
+            R::MicFailure
+            | R::FourWayHandshakeTimeout
+            | R::Ieee802_1xAuthFailed
+            | R::InvalidPmkid
+            | R::AuthFail
+            | R::HandshakeTimeout => AuthFailure,
+            R::NoApFound
+            | R::NoApFoundWCompatibleSecurity
+            | R::NoApFoundInAuthmodeThreshold
+            | R::NoApFoundInRssiThreshold => ApNotFound,
+            R::AuthExpire
+            | R::DisassocDueToInactivity
+            | R::MissingAcks
+            | R::Timeout
+            | R::BeaconTimeout
+            | R::ApTsfReset
+            | R::SaQueryTimeout => LinkLost,
+            R::AssocTooMany
+            | R::Class2FrameFromNonauthSta
+            | R::Class3FrameFromNonassocSta
+            | R::AssocNotAuthed
+            | R::DisassocPwrcapBad
+            | R::DisassocSupchanBad
+            | R::IeInvalid
+            | R::IeIn4wayDiffers
+            | R::GroupCipherInvalid
+            | R::PairwiseCipherInvalid
+            | R::AkmpInvalid
+            | R::UnsuppRsnIeVersion
+            | R::InvalidRsnIeCap
+            | R::CipherSuiteRejected
+            | R::BadCipherOrAkm
+            | R::NotAuthorizedThisLocation
+            | R::NotEnoughBandwidth
+            | R::AssocFail
+            | R::ConnectionFail
+            | R::AssocComebackTimeTooLong => Rejected,
+            R::AssocLeave => Local,
+            R::BssTransitionDisassoc | R::Roaming => Roaming,
+            R::Unknown => Unknown,
+            _ => Other,
+        }
+    }
+}
+
+/// A MAC address, e.g. of our station or soft-AP interface. Displays as `aa:bb:cc:dd:ee:ff`, and can be parsed
+/// from the same format.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Multicast addresses have bit 0 of the first byte set. The ESP rejects these for its interfaces.
+    pub fn is_unicast(&self) -> bool {
+        self.0[0] & 0x01 == 0
+    }
+
+    /// Locally-administered addresses have bit 1 of the first byte set. Use these for addresses you assign,
+    /// unless you own the OUI.
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// A unicast, locally-administered address, from arbitrary bytes, e.g. a device serial number.
+    pub fn new_local(mut bytes: [u8; 6]) -> Self {
+        bytes[0] = (bytes[0] & !0x01) | 0x02;
+        Self(bytes)
+    }
+
+    /// Check that this can be assigned to an interface: It must be unicast, which excludes broadcast, and
+    /// locally-administered, so it can't clash with a vendor-assigned address. Use `new_local` to build one.
+    /// Universally-administered addresses, including the ESP's factory address, are rejected; that one is restored
+    /// when the ESP restarts.
+    pub fn validate(&self) -> Result<(), EspError> {
+        if !self.is_unicast() || !self.is_locally_administered() {
+            return Err(EspError::InvalidData);
+        }
+        Ok(())
+    }
+}
+
+impl From<[u8; 6]> for MacAddr {
+    fn from(bytes: [u8; 6]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+impl Format for MacAddr {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let m = &self.0;
+        defmt::write!(
+            f,
+            "{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}",
+            m[0],
+            m[1],
+            m[2],
+            m[3],
+            m[4],
+            m[5]
+        )
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = EspError;
+
+    /// Parses `aa:bb:cc:dd:ee:ff`, or `aa-bb-cc-dd-ee-ff`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = [0; 6];
+        let mut parts = s.split([':', '-']);
+
+        for byte in result.iter_mut() {
+            let part = parts.next().ok_or(EspError::InvalidData)?;
+            if part.len() != 2 {
+                return Err(EspError::InvalidData);
             }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| EspError::InvalidData)?;
+        }
+
+        if parts.next().is_some() {
+            return Err(EspError::InvalidData);
         }
 
-        Ok((result, i))
+        Ok(Self(result))
     }
 }
 
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416wifi_interface_t)
-#[derive(Clone, Copy, Format)]
+#[derive(Clone, Copy, PartialEq, Format, TryFromPrimitive)]
 #[repr(u8)]
 pub enum InterfaceType {
     Station = 0,
@@ -533,7 +811,7 @@ pub enum InterfaceType {
 }
 
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv411wifi_mode_t)
-#[derive(Clone, Copy, Format)]
+#[derive(Clone, Copy, PartialEq, Format, TryFromPrimitive)]
 #[repr(u8)]
 pub enum WifiMode {
     Null = 0,
@@ -545,93 +823,341 @@ pub enum WifiMode {
     ApStation = 3,
 }
 
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv414wifi_ps_type_t)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum WifiPsType {
+    /// No power save. This has the lowest latency.
+    None = 0,
+    /// Minimum modem sleep: The station wakes to receive every DTIM beacon. This is the ESP-IDF default.
+    #[default]
+    MinModem = 1,
+    /// Maximum modem sleep: The station wakes every `listen_interval` beacons. This uses the least power, but
+    /// broadcast data may be missed.
+    MaxModem = 2,
+}
+
 /// Start WiFi according to current configuration If mode is WIFI_MODE_STA, it creates station control block and starts station If mode is
 /// WIFI_MODE_AP, it creates soft-AP control block and starts soft-AP If mode is WIFI_MODE_APSTA, it creates soft-AP and station control
 /// block and starts soft-AP and station If mode is WIFI_MODE_NAN, it creates NAN control block and starts NAN.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv414esp_wifi_startv))
-pub fn start<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiStart)
+pub fn start<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiStart)
 }
 
 /// Stop WiFi If mode is WIFI_MODE_STA, it stops station and frees station control block If mode is WIFI_MODE_AP,
 /// it stops soft-AP and frees soft-AP control block If mode is WIFI_MODE_APSTA, it stops station/soft-AP and frees
 /// station/soft-AP control block If mode is WIFI_MODE_NAN, it stops NAN and frees NAN control block.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv413esp_wifi_stopv)
-pub fn stop<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiStop)
+pub fn stop<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiStop)
 }
 
 /// Get number of APs found in last scan.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv424esp_wifi_scan_get_ap_numP8uint16_t)
-pub fn scan_get_ap_num<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiScanGetApNum)
+pub fn scan_get_ap_num<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiScanGetApNum)
 }
 
 /// Get one AP record from the scanned AP list.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv428esp_wifi_scan_get_ap_recordsP8uint16_tP16wifi_ap_record_t)
-pub fn scan_get_ap_record<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiScanGetApRecord)
+pub fn scan_get_ap_record<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiScanGetApRecord)
 }
 
 /// Clear AP list found in last scan.
 /// This API will free all memory occupied by scanned AP list.
 /// When the obtained AP list fails, AP records must be cleared,otherwise it may cause memory leakage.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_clear_ap_listv)
-pub fn clear_ap_list<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiClearApList)
+pub fn clear_ap_list<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiClearApList)
 }
 
 /// Retrieve the list of APs found during the last scan. The returned AP list is sorted in descending order based on RSSI.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv428esp_wifi_scan_get_ap_recordsP8uint16_tP16wifi_ap_record_t)
-pub fn scan_get_ap_records<W>(
+pub fn scan_get_ap_records<T: Transport>(
     buf: &mut [u8],
-    mut write: W,
+    transport: &mut T,
     uid: u32,
     max_number: u8,
-) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqWifiScanGetApRecords, uid);
 
-    let mut data = [0; 2];
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, max_number as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the stations connected to our soft-AP. Parse the response with `parse_sta_list`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_ap_get_sta_listP15wifi_sta_list_t)
+pub fn ap_get_sta_list<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiApGetStaList)
+}
+
+/// Parse the response to `ap_get_sta_list`. `data` is the response's payload, e.g. `WifiMsg::data`.
+pub fn parse_sta_list(data: &[u8]) -> Result<WifiStaList, EspError> {
+    let mut result = WifiStaList::default();
+
+    // Fields: resp = 1, sta_list = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Len(v)) => result = WifiStaList::from_bytes(v)?,
+            _ => (),
+        }
+    }
+
+    Ok(result)
+}
+
+pub fn get_mode<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqGetWifiMode)
+}
+
+/// Get the MAC address of an interface. Parse the response with `parse_mac`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv415esp_wifi_get_mac16wifi_interface_tA6_7uint8_t)
+pub fn get_mac<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqGetMacAddress, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Parse the response to `get_mac`. `data` is the response's payload, e.g. `WifiMsg::data`.
+pub fn parse_mac(data: &[u8]) -> Result<MacAddr, EspError> {
+    let mut result = None;
+
+    // Fields: mac = 1, resp = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Len(v)) => {
+                result = Some(MacAddr(v.try_into().map_err(|_| EspError::InvalidData)?))
+            }
+            (2, FieldVal::Varint(v)) => check_resp(v)?,
+            _ => (),
+        }
+    }
+
+    result.ok_or(EspError::InvalidData)
+}
+
+/// Set the MAC address of an interface. Wi-Fi must be initialized, but not started. The station and soft-AP
+/// interfaces must have different addresses. Returns `EspError::InvalidData` without sending, if `mac` is
+/// multicast, or not locally-administered; see `MacAddr::validate`. The ESP doesn't persist this; set it after
+/// each restart.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv415esp_wifi_set_mac16wifi_interface_tA6_K7uint8_t)
+pub fn set_mac<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+    mac: &MacAddr,
+) -> Result<(), EspError> {
+    mac.validate()?;
+
+    let rpc = Rpc::new_req(RpcId::ReqSetMacAddress, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc_bytes(data, 1, &mac.0, &mut i);
+        write_rpc(data, 2, WireType::Varint, ifx as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Set the country info: The allowed channels, and maximum TX power. Build this from the `regulatory` table,
+/// e.g. `regulatory::find(b"JP")`, to match the checks in `check_channel` and `check_tx_power`. Returns
+/// `EspError::InvalidData` without sending, if the channel range is outside 1 - 14.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv420esp_wifi_set_countryPK14wifi_country_t)
+pub fn set_country<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    country: &WifiCountry,
+) -> Result<(), EspError> {
+    if !(1..=CHANNEL_MAX_2G).contains(&country.schan)
+        || country.nchan == 0
+        || country.nchan > CHANNEL_MAX_2G + 1 - country.schan
+    {
+        return Err(EspError::InvalidData);
+    }
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetCountry, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc_len(data, 1, &mut i, |b| country.to_bytes(b));
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the country info. Parse the response with `parse_country`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv420esp_wifi_get_countryP14wifi_country_t)
+pub fn get_country<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetCountry)
+}
+
+/// Parse the response to `get_country`.
+pub fn parse_country(data: &[u8]) -> Result<WifiCountry, EspError> {
+    let mut country = WifiCountry::default();
+
+    // Fields: resp = 1, country = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Len(v)) => country = WifiCountry::from_bytes(v)?,
+            _ => (),
+        }
+    }
+
+    Ok(country)
+}
+
+/// Set the country by its code, e.g. `b"US"`, using the ESP's built-in table. This covers more countries than
+/// the `regulatory` module. `cc` is 2 characters, or 3, with the environment last. If `ieee80211d_enabled`, the
+/// country info from the AP we're connected to is used instead, if available. Returns `EspError::InvalidData`
+/// without sending, if `cc` is the wrong length.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv425esp_wifi_set_country_codePKcb)
+pub fn set_country_code<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    cc: &[u8],
+    ieee80211d_enabled: bool,
+) -> Result<(), EspError> {
+    if !(2..=3).contains(&cc.len()) {
+        return Err(EspError::InvalidData);
+    }
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetCountryCode, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc_bytes(data, 1, cc, &mut i);
+        write_rpc(data, 2, WireType::Varint, ieee80211d_enabled as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// The lowest TX power the ESP accepts, in dBm.
+pub const TX_POWER_MIN_DBM: f32 = 2.;
+/// The highest TX power the ESP accepts, in dBm. The hardware may not reach this; see the ESP-IDF docs.
+pub const TX_POWER_MAX_DBM: f32 = 21.;
+
+/// Check a TX power against the ESP's range, and a country's limit, if set, e.g. from `regulatory::find`. Returns the power in the 0.25 dBm
+/// units used by the ESP, or `EspError::InvalidData` if it's out of range.
+pub fn check_tx_power(dbm: f32, country: Option<&WifiCountry>) -> Result<i8, EspError> {
+    if !(TX_POWER_MIN_DBM..=TX_POWER_MAX_DBM).contains(&dbm) {
+        return Err(EspError::InvalidData);
+    }
+
+    // A `max_tx_power` of 0 means the country has no limit set.
+    if let Some(c) = country
+        && c.max_tx_power > 0
+        && dbm > c.max_tx_power as f32
+    {
+        return Err(EspError::InvalidData);
+    }
 
-    let mut i = 0;
-    write_rpc(&mut data, 1, WireType::Varint, max_number as u64, &mut i);
+    // Round to the nearest step. This is always positive.
+    Ok((dbm * 4. + 0.5) as i8)
+}
+
+/// Set the maximum TX power, in dBm. This is 2 - 21 dBm, in 0.25 dBm steps; the ESP rounds it down to the
+/// nearest level its hardware supports. It's limited by the country's `max_tx_power`; pass the current country
+/// to check against it before sending. Returns `EspError::InvalidData` without sending, if out of range. Wi-Fi
+/// must be started.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv425esp_wifi_set_max_tx_power6int8_t)
+pub fn set_max_tx_power<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    dbm: f32,
+    country: Option<&WifiCountry>,
+) -> Result<(), EspError> {
+    let power = check_tx_power(dbm, country)?;
 
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetMaxTxPower, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc_int32(data, 1, power as i32, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
 
-pub fn ap_get_sta_list<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiApGetStaList)
+/// Get the maximum TX power. Parse the response with `parse_max_tx_power`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv425esp_wifi_get_max_tx_powerP6int8_t)
+pub fn get_max_tx_power<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetMaxTxPower)
 }
 
-pub fn get_mode<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqGetWifiMode)
+/// Parse the response to `get_max_tx_power`. Returns the power in dBm.
+pub fn parse_max_tx_power(data: &[u8]) -> Result<f32, EspError> {
+    let mut power = 0;
+
+    // Fields: power = 1, resp = 2. Power is in 0.25 dBm units.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => power = decode_int32(v),
+            (2, FieldVal::Varint(v)) => check_resp(v)?,
+            _ => (),
+        }
+    }
+
+    Ok(power as f32 / 4.)
 }
 
 /// Options:
@@ -640,19 +1166,20 @@ where
 /// 2: Soft AP; cannot scan.
 /// 3: Soft-AP and Sta (slower scan)
 /// 4: Wi-Fi aware. (Not relevant to normal scanning)
-pub fn set_mode<W>(buf: &mut [u8], mut write: W, uid: u32, mode: WifiMode) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+pub fn set_mode<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    mode: WifiMode,
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqSetWifiMode, uid);
 
-    let mut data = [0; 2];
-
-    let mut i = 0;
-    write_rpc(&mut data, 1, WireType::Varint, mode as u64, &mut i);
-
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, mode as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
@@ -660,37 +1187,70 @@ where
 /// Initialize WiFi Allocate resource for WiFi driver, such as WiFi control structure, RX/TX buffer,
 /// WiFi NVS structure etc. This WiFi also starts WiFi task.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv413esp_wifi_initPK18wifi_init_config_t)
-pub fn init<W>(buf: &mut [u8], mut write: W, uid: u32, cfg: &InitConfig) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+pub fn init<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    cfg: &InitConfig,
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqWifiInit, uid);
 
-    // todo: A/R.
-    let mut data = [0; 85]; // cfg size is ~50.
-
     let pl = RpcReqWifiInit {
         cfg: cfg.clone(), // todo: Don't clone
     };
 
-    let data_len = pl.to_bytes(&mut data);
-
-    let frame_len = setup_rpc(buf, &rpc, &data[..data_len]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| pl.to_bytes(data));
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
 
 /// Deinit WiFi Free all resource allocated in esp_wifi_init and stop WiFi task.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv415esp_wifi_deinitv))
-pub fn deinit<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiDeinit)
+pub fn deinit<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiDeinit)
 }
 
-/// Promiscuous frame type.
+/// Set the configuration of the station or soft-AP, depending on `cfg`'s variant. The station
+/// configuration takes effect on the next `connect`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv419esp_wifi_set_config16wifi_interface_tP13wifi_config_t)
+pub fn set_config<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    cfg: &WifiConfig,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetConfig, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, cfg.interface() as u64, &mut i);
+        write_rpc_len(data, 2, &mut i, |b| cfg.to_bytes(b));
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Connect to the AP set with `set_config`. Wi-Fi must be started in station mode. The response only
+/// indicates the attempt started; `EventStaConnected` or `EventStaDisconnected` follows.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416esp_wifi_connectv)
+pub fn connect<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiConnect)
+}
+
+/// Disconnect from the AP.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv419esp_wifi_disconnectv)
+pub fn disconnect<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiDisconnect)
+}
+
+/// Promiscuous frame type.
 ///
 /// Passed to promiscuous mode RX callback to indicate the type of parameter in the buffer.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv427wifi_promiscuous_pkt_type_t)
@@ -797,58 +1357,55 @@ impl PromiscuousCtrlFilter {
 
 /// Enable the promiscuous mode, and set its filter.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv424esp_wifi_set_promiscuousb)
-pub fn set_promiscuous<W>(
+pub fn set_promiscuous<T: Transport>(
     buf: &mut [u8],
-    mut write: W,
+    transport: &mut T,
     uid: u32,
     enabled: bool,
     filter: &PromiscuousFilter,
     ctrl_filter: Option<&PromiscuousCtrlFilter>,
-) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+) -> Result<(), EspError> {
     // todo: Where to handle setting the CB? Doesn't map neatly to RPC.
 
     // Enable or disable
     let rpc = Rpc::new_req(RpcId::ReqWifiSetPromiscuous, uid);
 
-    let mut data = [0; 6];
-
-    let mut i = 0;
-    write_rpc(&mut data, 1, WireType::Varint, enabled as u64, &mut i);
-
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, enabled as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     // Set its filter. This, and the ctrl filter, and single-field structs.
     let rpc = Rpc::new_req(RpcId::ReqWifiSetPromiscuousFilter, uid);
 
-    let mut i = 0;
-    // Same buf as enable/disable.
-
-    // todo: We assume 1 byte now for each filter. This is temp.
-    write_rpc(&mut data, 1, WireType::Len, 1, &mut i);
-    write_rpc(&mut data, 1, WireType::Varint, 1, &mut i); // Len of the struct.
-
-    write_rpc(&mut data, 1, WireType::Varint, filter.val() as u64, &mut i);
-
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    // The filter is a single-field struct, at field 1.
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc_len(data, 1, &mut i, |filter_buf| {
+            let mut j = 0;
+            write_rpc(filter_buf, 1, Varint, filter.val() as u64, &mut j);
+            j
+        });
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     // Set its ctrl-mode filter A/R
     if let Some(f) = ctrl_filter {
         let rpc = Rpc::new_req(RpcId::ReqWifiSetPromiscuousCtrlFilter, uid);
 
-        let mut i = 0;
-
-        write_rpc(&mut data, 1, WireType::Len, 1, &mut i);
-        write_rpc(&mut data, 1, WireType::Varint, 1, &mut i); // Len of the struct.
-
-        write_rpc(&mut data, 1, WireType::Varint, f.val() as u64, &mut i);
-
-        let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-        write(&buf[..frame_len])?;
+        let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+            let mut i = 0;
+            write_rpc_len(data, 1, &mut i, |filter_buf| {
+                let mut j = 0;
+                write_rpc(filter_buf, 1, Varint, f.val() as u64, &mut j);
+                j
+            });
+            i
+        });
+        transport.write(&buf[..frame_len])?;
     }
 
     Ok(())
@@ -856,63 +1413,63 @@ where
 
 /// Get the promiscuous mode.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv424esp_wifi_get_promiscuousPb)
-pub fn get_promiscuous<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiGetPromiscuous)
+pub fn get_promiscuous<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetPromiscuous)
 }
 
 /// Get the promiscuous filter.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv431esp_wifi_get_promiscuous_filterP25wifi_promiscuous_filter_t)
-pub fn get_promiscuous_filter<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiGetPromiscuousFilter)
+pub fn get_promiscuous_filter<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetPromiscuousFilter)
 }
 
 /// Get the subtype filter of the control packet in promiscuous mode.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv436esp_wifi_get_promiscuous_ctrl_filterP25wifi_promiscuous_filter_t)
-pub fn get_promiscuous_ctrl_filter<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiGetPromiscuousCtrlFilter)
+pub fn get_promiscuous_ctrl_filter<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetPromiscuousCtrlFilter)
 }
 
 /// Scan all available APs.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv419esp_wifi_scan_startPK18wifi_scan_config_tb)
-pub fn scan_start<W>(
+pub fn scan_start<T: Transport>(
     buf: &mut [u8],
-    mut write: W,
+    transport: &mut T,
     uid: u32,
     scan_start: &RpcReqWifiScanStart,
-) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqWifiScanStart, uid);
 
-    let mut data = [0; 100];
-    let data_size = scan_start.to_bytes(&mut data);
-
-    let frame_len = setup_rpc(buf, &rpc, &data[..data_size]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| scan_start.to_bytes(data));
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
 
 /// Stop the scan in process.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv418esp_wifi_scan_stopv)
-pub fn scan_stop<W>(buf: &mut [u8], write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    write_empty_msg(buf, write, uid, RpcId::ReqWifiScanStop)
+pub fn scan_stop<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiScanStop)
 }
 
-#[derive(Format)]
+/// A protocol bitmap, for one band.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv421esp_wifi_set_protocol16wifi_interface_t7uint8_t)
+#[derive(Clone, Copy, PartialEq, Format)]
 pub struct Protocols {
     /// 802.11b
     pub p_11b: bool,
@@ -924,15 +1481,14 @@ pub struct Protocols {
     pub p_lr: bool,
     /// 802.11ax
     pub p_11ax: bool,
-    /// 802.11ax
-    pub wps: bool,
-    /// 802.11a
+    /// 802.11a. 5 GHz only.
     pub p_11a: bool,
+    /// 802.11ac. 5 GHz only.
     pub p_11ac: bool,
 }
 
 impl Default for Protocols {
-    /// This matches the ESP default.
+    /// This matches the ESP default, for 2.4 GHz.
     fn default() -> Self {
         Self {
             p_11b: true,
@@ -940,20 +1496,32 @@ impl Default for Protocols {
             p_11n: true,
             p_lr: false,
             p_11ax: false,
-            wps: false,
             p_11a: false,
             p_11ac: false,
-            // todo: 802.1ac and ax A/R. 0x20 and 0x40 rep
         }
     }
 }
 
 impl Protocols {
+    /// The ESP default for 5 GHz: 802.11a, n, and ac.
+    pub fn default_5g() -> Self {
+        Self {
+            p_11b: false,
+            p_11g: false,
+            p_11n: true,
+            p_lr: false,
+            p_11ax: false,
+            p_11a: true,
+            p_11ac: true,
+        }
+    }
+
     pub fn to_byte(&self) -> u8 {
         (self.p_11b as u8)
             | ((self.p_11g as u8) << 1)
             | ((self.p_11n as u8) << 2)
             | ((self.p_lr as u8) << 3)
+            | ((self.p_11a as u8) << 4)
             | ((self.p_11ac as u8) << 5)
             | ((self.p_11ax as u8) << 6)
     }
@@ -964,13 +1532,9 @@ impl Protocols {
             p_11g: (b >> 1) & 1 != 0,
             p_11n: (b >> 2) & 1 != 0,
             p_lr: (b >> 3) & 1 != 0,
-            p_11ax: (b >> 4) & 1 != 0,
-            wps: (b >> 5) & 1 != 0,
-            p_11a: false,
-            p_11ac: false,
-            // todo?
-            // p_11a: (b >> 8) & 1 != 0,
-            // p_11ac: (b >> 9) & 1 != 0,
+            p_11a: (b >> 4) & 1 != 0,
+            p_11ac: (b >> 5) & 1 != 0,
+            p_11ax: (b >> 6) & 1 != 0,
         }
     }
 }
@@ -980,72 +1544,885 @@ impl Protocols {
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_set_protocols16wifi_interface_tP16wifi_protocols_t)
 /// interface (ifx) should be 0 for Station, and 1 for Ap.
 /// Bitmap: e.g 1 | 2 | 4; = 11B | 11G | 11N. Note that this is the default.
-pub fn set_protocol<W>(
+pub fn set_protocol<T: Transport>(
     buf: &mut [u8],
-    mut write: W,
+    transport: &mut T,
     uid: u32,
     ifx: InterfaceType,
     protocols: &Protocols,
-) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqWifiSetProtocol, uid);
 
-    let mut data = [0; 4];
-    let mut i = 0;
-
-    write_rpc(&mut data, 1, WireType::Varint, ifx as u64, &mut i);
-    write_rpc(
-        &mut data,
-        2,
-        WireType::Varint,
-        protocols.to_byte() as u64,
-        &mut i,
-    );
-
-    let frame_len = setup_rpc(buf, &rpc, &data[0..i]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        write_rpc(
+            data,
+            2,
+            WireType::Varint,
+            protocols.to_byte() as u64,
+            &mut i,
+        );
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
 
 /// Get the current protocol bitmap of the specified interface.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv421esp_wifi_get_protocol16wifi_interface_tP7uint8_t)
-pub fn get_protocol<W>(buf: &mut [u8], mut write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
+pub fn get_protocol<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
     let rpc = Rpc::new_req(RpcId::ReqWifiGetProtocol, uid);
 
-    let mut data = [0; 4];
-    let mut i = 0;
-
     let interface_num = 0; // todo?
-    write_rpc(&mut data, 1, WireType::Varint, interface_num, &mut i);
 
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, interface_num, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
 
-/// Set current WiFi power save type.
+/// Set the power save type. Modem sleep only takes effect in station mode, while connected; the station
+/// wakes every DTIM period with `MinModem`, or every `WifiStaConfig::listen_interval` beacons with `MaxModem`.
+/// To also use power save while disconnected, set `InitConfig::sta_disconnected_pm`.
 /// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv415esp_wifi_set_ps14wifi_ps_type_t)
-pub fn set_ps<W>(buf: &mut [u8], mut write: W, uid: u32) -> Result<(), EspError>
-where
-    W: FnMut(&[u8]) -> Result<(), EspError>,
-{
-    let rpc = Rpc::new_req(RpcId::ReqWifiGetProtocol, uid);
+pub fn set_ps<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ps_type: WifiPsType,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetPs, uid);
 
-    let mut data = [0; 4];
-    let mut i = 0;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ps_type as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
-    let interface_num = 0; // todo?
-    write_rpc(&mut data, 1, WireType::Varint, interface_num, &mut i);
+    Ok(())
+}
+
+/// Get the power save type. Parse the response with `parse_ps`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv415esp_wifi_get_psP14wifi_ps_type_t)
+pub fn get_ps<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetPs)
+}
+
+/// Parse the response to `get_ps`.
+pub fn parse_ps(data: &[u8]) -> Result<WifiPsType, EspError> {
+    let mut ps_type = 0;
+
+    // Fields: resp = 1, type = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Varint(v)) => ps_type = v as u8,
+            _ => (),
+        }
+    }
+
+    ps_type.try_into().map_err(|_| EspError::InvalidData)
+}
+
+/// The highest 2.4 GHz channel. For 5 GHz, see `CHANNELS_5G`.
+pub const CHANNEL_MAX_2G: u8 = 14;
+
+/// Channel 14 is only allowed in Japan, and only with 802.11b, so it can't be part of an HT40 pair.
+pub const CHANNEL_11B_ONLY: u8 = 14;
+
+/// Check that a primary channel, and secondary channel position are a valid combination. If `country` is passed,
+/// e.g. from `regulatory::find`, the channels must be in its range; otherwise, channels 1 - 13 are allowed.
+/// Channel 14 requires a country that allows it, e.g. `JP`, and no secondary channel. A secondary channel above
+/// the primary (HT40+) is 4 channels higher, and one below (HT40-) is 4 channels lower; both must be allowed. For
+/// example, HT40+ isn't allowed on channel 13 in most countries. Returns `EspError::InvalidData` if the
+/// combination isn't allowed.
+///
+/// 5 GHz channels are checked against the country's 5 GHz channels in the `regulatory` table. If the country isn't
+/// in the table, 5 GHz channels are rejected. If no country is passed, any channel in `CHANNELS_5G` is allowed. HT40
+/// channels are in fixed pairs, e.g. 36 and 40, so the secondary channel must be the other channel of the pair.
+pub fn check_channel(
+    primary: u8,
+    second: WifiSecondChan,
+    country: Option<&WifiCountry>,
+) -> Result<(), EspError> {
+    let ok = match channel_band(primary) {
+        Some(WifiBand::Ghz2) => check_channel_2g(primary, second, country),
+        Some(WifiBand::Ghz5) => check_channel_5g(primary, second, country),
+        None => false,
+    };
+
+    if !ok {
+        return Err(EspError::InvalidData);
+    }
+    Ok(())
+}
+
+fn check_channel_2g(primary: u8, second: WifiSecondChan, country: Option<&WifiCountry>) -> bool {
+    let (first, last) = match country {
+        Some(c) => (c.schan, c.schan.saturating_add(c.nchan).saturating_sub(1)),
+        None => (1, 13),
+    };
+
+    let allowed = |ch: u8| (first..=last).contains(&ch) && (1..=CHANNEL_MAX_2G).contains(&ch);
+    // 802.11b doesn't support HT40, so neither channel of a pair may be 14.
+    let allowed_ht40 = |ch: u8| allowed(ch) && ch != CHANNEL_11B_ONLY;
+
+    match second {
+        WifiSecondChan::None => allowed(primary),
+        WifiSecondChan::Above => allowed_ht40(primary) && allowed_ht40(primary.saturating_add(4)),
+        WifiSecondChan::Below => allowed_ht40(primary) && primary > 4 && allowed_ht40(primary - 4),
+    }
+}
+
+fn check_channel_5g(primary: u8, second: WifiSecondChan, country: Option<&WifiCountry>) -> bool {
+    // `WifiCountry` only describes 2.4 GHz channels, so we use the table's 5 GHz channels.
+    let ranges = match country {
+        Some(c) => match regulatory::find(&c.cc) {
+            Some(d) => d.channels_5g,
+            None => return false,
+        },
+        None => CHANNELS_5G,
+    };
+
+    let allowed = |ch: u8| in_channel_ranges(ch, ranges);
+
+    // HT40 pairs start at 36, and 149: (36, 40), (44, 48) ... (149, 153) etc.
+    let lower_of_pair = ((primary - if primary >= 149 { 149 } else { 36 }) / 4).is_multiple_of(2);
+
+    let secondary_ok = match second {
+        WifiSecondChan::None => true,
+        WifiSecondChan::Above => lower_of_pair && allowed(primary.saturating_add(4)),
+        WifiSecondChan::Below => !lower_of_pair && allowed(primary - 4),
+    };
+
+    allowed(primary) && secondary_ok
+}
+
+/// Set the primary, and secondary channel. Use this in soft-AP mode, in promiscuous (sniffer) mode, or in
+/// station mode while disconnected; when connected, the channel follows the AP. Wi-Fi must be started. The
+/// combination is checked with `check_channel` before sending; pass the current country to check against
+/// its channel range.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv420esp_wifi_set_channel7uint8_t18wifi_second_chan_t)
+pub fn set_channel<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    primary: u8,
+    second: WifiSecondChan,
+    country: Option<&WifiCountry>,
+) -> Result<(), EspError> {
+    check_channel(primary, second, country)?;
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetChannel, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, primary as u64, &mut i);
+        write_rpc(data, 2, WireType::Varint, second as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the primary, and secondary channel. Parse the response with `parse_channel`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv420esp_wifi_get_channelP7uint8_tP18wifi_second_chan_t)
+pub fn get_channel<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetChannel)
+}
+
+/// Parse the response to `get_channel`. Returns the primary channel, and the secondary channel's position.
+pub fn parse_channel(data: &[u8]) -> Result<(u8, WifiSecondChan), EspError> {
+    let mut primary = 0;
+    let mut second = 0;
+
+    // Fields: resp = 1, primary = 2, second = 3.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Varint(v)) => primary = v as u8,
+            (3, FieldVal::Varint(v)) => second = v as u8,
+            _ => (),
+        }
+    }
+
+    let second = second.try_into().map_err(|_| EspError::InvalidData)?;
+    Ok((primary, second))
+}
+
+/// Set the bandwidth of an interface. This is `HT20`, or `HT40`; others return `EspError::InvalidData`
+/// without sending. `HT40` requires a secondary channel; see `set_channel`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_set_bandwidth16wifi_interface_t16wifi_bandwidth_t)
+pub fn set_bandwidth<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+    bw: WifiBandwidth,
+) -> Result<(), EspError> {
+    if !matches!(bw, WifiBandwidth::HT20 | WifiBandwidth::HT40) {
+        return Err(EspError::InvalidData);
+    }
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetBandwidth, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        write_rpc(data, 2, WireType::Varint, bw as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the bandwidth of an interface. Parse the response with `parse_bandwidth`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_get_bandwidth16wifi_interface_tP16wifi_bandwidth_t)
+pub fn get_bandwidth<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiGetBandwidth, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Parse the response to `get_bandwidth`.
+pub fn parse_bandwidth(data: &[u8]) -> Result<WifiBandwidth, EspError> {
+    let mut bw = 0;
+
+    // Fields: resp = 1, bw = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Varint(v)) => bw = v as u8,
+            _ => (),
+        }
+    }
+
+    bw.try_into().map_err(|_| EspError::InvalidData)
+}
+
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv411wifi_band_t)
+#[derive(Clone, Copy, PartialEq, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum WifiBand {
+    /// 2.4 GHz
+    Ghz2 = 1,
+    /// 5 GHz
+    Ghz5 = 2,
+}
+
+/// Which bands the ESP may use. Only 5 GHz-capable chips, e.g. the ESP32-C5, support anything other than `Ghz2Only`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv416wifi_band_mode_t)
+#[derive(Clone, Copy, PartialEq, Default, Format, TryFromPrimitive)]
+#[repr(u8)]
+pub enum WifiBandMode {
+    #[default]
+    Ghz2Only = 1,
+    Ghz5Only = 2,
+    /// Use both bands. A station connects to the best AP on either; a soft-AP uses the band of its channel.
+    Auto = 3,
+}
+
+/// Protocols for each band, for `set_protocols`.
+#[derive(Clone, Copy, PartialEq, Format)]
+pub struct BandProtocols {
+    pub ghz_2g: Protocols,
+    pub ghz_5g: Protocols,
+}
+
+impl Default for BandProtocols {
+    /// This matches the ESP default.
+    fn default() -> Self {
+        Self {
+            ghz_2g: Protocols::default(),
+            ghz_5g: Protocols::default_5g(),
+        }
+    }
+}
+
+impl BandProtocols {
+    /// Returns `EspError::InvalidData` if a protocol is set for a band it doesn't support, e.g. 802.11b on 5 GHz.
+    pub fn validate(&self) -> Result<(), EspError> {
+        let p2 = &self.ghz_2g;
+        let p5 = &self.ghz_5g;
+
+        if p2.p_11a || p2.p_11ac || p5.p_11b || p5.p_11g || p5.p_lr {
+            return Err(EspError::InvalidData);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc(
+            buf,
+            1,
+            WireType::Varint,
+            self.ghz_2g.to_byte() as u64,
+            &mut i,
+        );
+        write_rpc(
+            buf,
+            2,
+            WireType::Varint,
+            self.ghz_5g.to_byte() as u64,
+            &mut i,
+        );
+
+        i
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Varint(v)) => result.ghz_2g = Protocols::from_byte(v as u8),
+                (2, FieldVal::Varint(v)) => result.ghz_5g = Protocols::from_byte(v as u8),
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Bandwidth for each band, for `set_bandwidths`. Each is `HT20`, or `HT40`.
+#[derive(Clone, Copy, PartialEq, Default, Format)]
+pub struct BandBandwidths {
+    pub ghz_2g: WifiBandwidth,
+    pub ghz_5g: WifiBandwidth,
+}
+
+impl BandBandwidths {
+    pub fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+
+        write_rpc(buf, 1, WireType::Varint, self.ghz_2g as u64, &mut i);
+        write_rpc(buf, 2, WireType::Varint, self.ghz_5g as u64, &mut i);
+
+        i
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EspError> {
+        let mut result = Self::default();
+
+        for field in Fields::new(buf) {
+            match field? {
+                (1, FieldVal::Varint(v)) => {
+                    result.ghz_2g = (v as u8).try_into().map_err(|_| EspError::InvalidData)?
+                }
+                (2, FieldVal::Varint(v)) => {
+                    result.ghz_5g = (v as u8).try_into().map_err(|_| EspError::InvalidData)?
+                }
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// 5 GHz channels, as (first, last) ranges, in steps of 4. Which of these are allowed depends on the country;
+/// see `regulatory`.
+pub const CHANNELS_5G: &[(u8, u8)] = &[(36, 64), (100, 144), (149, 177)];
+
+/// If a channel is in one of `ranges`, e.g. `CHANNELS_5G`.
+pub(crate) fn in_channel_ranges(ch: u8, ranges: &[(u8, u8)]) -> bool {
+    ranges
+        .iter()
+        .any(|&(first, last)| (first..=last).contains(&ch) && (ch - first).is_multiple_of(4))
+}
+
+/// The band a channel is in, or `None` if it's not a valid channel.
+pub fn channel_band(channel: u8) -> Option<WifiBand> {
+    if (1..=CHANNEL_MAX_2G).contains(&channel) {
+        Some(WifiBand::Ghz2)
+    } else if in_channel_ranges(channel, CHANNELS_5G) {
+        Some(WifiBand::Ghz5)
+    } else {
+        None
+    }
+}
+
+/// The center frequency of a channel, in MHz, or `None` if it's not a valid channel.
+pub fn channel_to_freq(channel: u8) -> Option<u16> {
+    match channel_band(channel)? {
+        WifiBand::Ghz2 if channel == 14 => Some(2_484),
+        WifiBand::Ghz2 => Some(2_407 + 5 * channel as u16),
+        WifiBand::Ghz5 => Some(5_000 + 5 * channel as u16),
+    }
+}
+
+/// The channel with a given center frequency, in MHz, or `None` if there isn't one.
+pub fn freq_to_channel(freq_mhz: u16) -> Option<u8> {
+    let channel = match freq_mhz {
+        2_484 => 14,
+        2_412..=2_472 if (freq_mhz - 2_407).is_multiple_of(5) => (freq_mhz - 2_407) / 5,
+        5_000..=6_000 if freq_mhz.is_multiple_of(5) => (freq_mhz - 5_000) / 5,
+        _ => return None,
+    } as u8;
+
+    channel_band(channel).map(|_| channel)
+}
+
+/// Set the band. Use this when the band mode is `Ghz2Only` or `Ghz5Only`; with `Auto`, the band follows the
+/// channel, or AP.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv417esp_wifi_set_band11wifi_band_t)
+pub fn set_band<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    band: WifiBand,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetBand, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, band as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the band. Parse the response with `parse_band`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv417esp_wifi_get_bandP11wifi_band_t)
+pub fn get_band<T: Transport>(buf: &mut [u8], transport: &mut T, uid: u32) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetBand)
+}
+
+/// Parse the response to `get_band`.
+pub fn parse_band(data: &[u8]) -> Result<WifiBand, EspError> {
+    let mut band = 0;
+
+    // Fields: resp = 1, band = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Varint(v)) => band = v as u8,
+            _ => (),
+        }
+    }
+
+    band.try_into().map_err(|_| EspError::InvalidData)
+}
+
+/// Set the band mode: 2.4 GHz only, 5 GHz only, or both.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_set_band_mode16wifi_band_mode_t)
+pub fn set_band_mode<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    mode: WifiBandMode,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetBandMode, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, mode as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the band mode. Parse the response with `parse_band_mode`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_get_band_modeP16wifi_band_mode_t)
+pub fn get_band_mode<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+) -> Result<(), EspError> {
+    write_empty_msg(buf, transport, uid, RpcId::ReqWifiGetBandMode)
+}
+
+/// Parse the response to `get_band_mode`.
+pub fn parse_band_mode(data: &[u8]) -> Result<WifiBandMode, EspError> {
+    let mut mode = 0;
+
+    // Fields: resp = 1, bandmode = 2.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (2, FieldVal::Varint(v)) => mode = v as u8,
+            _ => (),
+        }
+    }
+
+    mode.try_into().map_err(|_| EspError::InvalidData)
+}
+
+/// Set the protocols of an interface, for each band. Returns `EspError::InvalidData` without sending, if
+/// a protocol is set for a band that doesn't support it. On 2.4 GHz-only chips, use `set_protocol`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_set_protocols16wifi_interface_tP16wifi_protocols_t)
+pub fn set_protocols<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+    protocols: &BandProtocols,
+) -> Result<(), EspError> {
+    protocols.validate()?;
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetProtocols, uid);
 
-    let frame_len = setup_rpc(buf, &rpc, &data[..i]);
-    write(&buf[..frame_len])?;
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        write_rpc_len(data, 2, &mut i, |b| protocols.to_bytes(b));
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the protocols of an interface, for each band. Parse the response with `parse_protocols`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv422esp_wifi_get_protocols16wifi_interface_tP16wifi_protocols_t)
+pub fn get_protocols<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiGetProtocols, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
 
     Ok(())
 }
+
+/// Parse the response to `get_protocols`.
+pub fn parse_protocols(data: &[u8]) -> Result<BandProtocols, EspError> {
+    let mut protocols = BandProtocols::default();
+
+    // Fields: resp = 1, ifx = 2, protocols = 3.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (3, FieldVal::Len(v)) => protocols = BandProtocols::from_bytes(v)?,
+            _ => (),
+        }
+    }
+
+    Ok(protocols)
+}
+
+/// Set the bandwidth of an interface, for each band. Each is `HT20`, or `HT40`; others return
+/// `EspError::InvalidData` without sending. On 2.4 GHz-only chips, use `set_bandwidth`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv423esp_wifi_set_bandwidths16wifi_interface_tP17wifi_bandwidths_t)
+pub fn set_bandwidths<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+    bandwidths: &BandBandwidths,
+) -> Result<(), EspError> {
+    for bw in [bandwidths.ghz_2g, bandwidths.ghz_5g] {
+        if !matches!(bw, WifiBandwidth::HT20 | WifiBandwidth::HT40) {
+            return Err(EspError::InvalidData);
+        }
+    }
+
+    let rpc = Rpc::new_req(RpcId::ReqWifiSetBandwidths, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        write_rpc_len(data, 2, &mut i, |b| bandwidths.to_bytes(b));
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Get the bandwidth of an interface, for each band. Parse the response with `parse_bandwidths`.
+/// [docs](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/network/esp_wifi.html#_CPPv423esp_wifi_get_bandwidths16wifi_interface_tP17wifi_bandwidths_t)
+pub fn get_bandwidths<T: Transport>(
+    buf: &mut [u8],
+    transport: &mut T,
+    uid: u32,
+    ifx: InterfaceType,
+) -> Result<(), EspError> {
+    let rpc = Rpc::new_req(RpcId::ReqWifiGetBandwidths, uid);
+
+    let frame_len = setup_rpc_in_place(buf, &rpc, |data| {
+        let mut i = 0;
+        write_rpc(data, 1, WireType::Varint, ifx as u64, &mut i);
+        i
+    });
+    transport.write(&buf[..frame_len])?;
+
+    Ok(())
+}
+
+/// Parse the response to `get_bandwidths`.
+pub fn parse_bandwidths(data: &[u8]) -> Result<BandBandwidths, EspError> {
+    let mut bandwidths = BandBandwidths::default();
+
+    // Fields: resp = 1, ifx = 2, bandwidths = 3.
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldVal::Varint(v)) => check_resp(v)?,
+            (3, FieldVal::Len(v)) => bandwidths = BandBandwidths::from_bytes(v)?,
+            _ => (),
+        }
+    }
+
+    Ok(bandwidths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use WifiSecondChan as S;
+
+    fn country(cc: &[u8]) -> WifiCountry {
+        regulatory::find(cc)
+            .unwrap_or_else(|| panic!("unknown country"))
+            .country()
+    }
+
+    #[test]
+    fn tx_power_range() {
+        // In 0.25 dBm units.
+        assert!(matches!(check_tx_power(2., None), Ok(8)));
+        assert!(matches!(check_tx_power(2.25, None), Ok(9)));
+        assert!(matches!(check_tx_power(21., None), Ok(84)));
+        assert!(matches!(check_tx_power(20.75, None), Ok(83)));
+        // Rounded to the nearest step.
+        assert!(matches!(check_tx_power(20.1, None), Ok(80)));
+        assert!(matches!(check_tx_power(20.2, None), Ok(81)));
+
+        for dbm in [1.75, 1.99, 21.01, 21.25, 0., -2., f32::NAN] {
+            assert!(matches!(
+                check_tx_power(dbm, None),
+                Err(EspError::InvalidData)
+            ));
+        }
+    }
+
+    #[test]
+    fn tx_power_country_limit() {
+        // JP is limited to 20 dBm; US to 30 dBm, so the ESP's range applies.
+        let jp = country(b"JP");
+        assert!(matches!(check_tx_power(20., Some(&jp)), Ok(80)));
+        assert!(matches!(
+            check_tx_power(20.25, Some(&jp)),
+            Err(EspError::InvalidData)
+        ));
+
+        let us = country(b"US");
+        assert!(matches!(check_tx_power(21., Some(&us)), Ok(84)));
+        assert!(matches!(
+            check_tx_power(21.25, Some(&us)),
+            Err(EspError::InvalidData)
+        ));
+
+        // A `max_tx_power` of 0 means no limit.
+        let mut unset = jp.clone();
+        unset.max_tx_power = 0;
+        assert!(matches!(check_tx_power(21., Some(&unset)), Ok(84)));
+    }
+
+    fn channel_ok(primary: u8, second: WifiSecondChan, country: Option<&WifiCountry>) -> bool {
+        check_channel(primary, second, country).is_ok()
+    }
+
+    #[test]
+    fn channels_2g() {
+        // Without a country, 1 - 13.
+        assert!(channel_ok(1, S::None, None));
+        assert!(channel_ok(13, S::None, None));
+        for ch in [0, 14, 15] {
+            assert!(!channel_ok(ch, S::None, None));
+        }
+
+        // The US allows 1 - 11.
+        let us = country(b"US");
+        assert!(channel_ok(11, S::None, Some(&us)));
+        assert!(!channel_ok(12, S::None, Some(&us)));
+        assert!(!channel_ok(0, S::None, Some(&us)));
+
+        // HT40: The secondary channel must also be allowed.
+        assert!(channel_ok(7, S::Above, Some(&us)));
+        assert!(!channel_ok(8, S::Above, Some(&us)));
+        assert!(channel_ok(5, S::Below, Some(&us)));
+        assert!(!channel_ok(4, S::Below, Some(&us)));
+        assert!(!channel_ok(1, S::Below, None));
+    }
+
+    #[test]
+    fn channel_14_11b_only() {
+        let jp = country(b"JP");
+        assert!(channel_ok(14, S::None, Some(&jp)));
+        assert!(!channel_ok(15, S::None, Some(&jp)));
+
+        // 802.11b doesn't support HT40, so 14 can't be either channel of a pair.
+        assert!(!channel_ok(14, S::Below, Some(&jp)));
+        assert!(!channel_ok(14, S::Above, Some(&jp)));
+        assert!(!channel_ok(10, S::Above, Some(&jp)));
+        assert!(channel_ok(9, S::Above, Some(&jp)));
+        assert!(channel_ok(13, S::Below, Some(&jp)));
+
+        // Only allowed with a country that allows it.
+        assert!(!channel_ok(14, S::None, None));
+        assert!(!channel_ok(14, S::None, Some(&country(b"DE"))));
+    }
+
+    #[test]
+    fn channels_5g() {
+        assert!(channel_ok(36, S::None, None));
+        assert!(channel_ok(165, S::None, None));
+        assert!(!channel_ok(37, S::None, None));
+        assert!(!channel_ok(68, S::None, None));
+
+        // HT40 pairs are fixed: (36, 40), (44, 48), (149, 153) etc.
+        assert!(channel_ok(36, S::Above, None));
+        assert!(!channel_ok(36, S::Below, None));
+        assert!(channel_ok(40, S::Below, None));
+        assert!(!channel_ok(40, S::Above, None));
+        assert!(channel_ok(149, S::Above, None));
+        assert!(channel_ok(153, S::Below, None));
+
+        // Checked against the country's channels.
+        let de = country(b"DE");
+        assert!(channel_ok(140, S::None, Some(&de)));
+        assert!(!channel_ok(149, S::None, Some(&de)));
+        assert!(channel_ok(149, S::None, Some(&country(b"US"))));
+
+        // Countries missing from the regulatory table get no 5 GHz channels.
+        let mut unknown = de.clone();
+        unknown.cc = *b"ZZ ";
+        assert!(!channel_ok(36, S::None, Some(&unknown)));
+        assert!(channel_ok(1, S::None, Some(&unknown)));
+    }
+
+    #[test]
+    fn mac_validate() {
+        assert!(MacAddr([0x02, 0, 0, 0, 0, 1]).validate().is_ok());
+        assert!(
+            MacAddr([0xFE, 0x12, 0x34, 0x56, 0x78, 0x9A])
+                .validate()
+                .is_ok()
+        );
+
+        for mac in [
+            // Multicast, and broadcast.
+            [0x03, 0, 0, 0, 0, 1],
+            [0x01, 0x00, 0x5E, 0, 0, 1],
+            [0xFF; 6],
+            // Universally-administered, e.g. a factory address.
+            [0x00, 0x11, 0x22, 0x33, 0x44, 0x55],
+            [0x24, 0x0A, 0xC4, 0, 0, 1],
+        ] {
+            assert!(MacAddr(mac).validate().is_err());
+        }
+
+        // `new_local` sets the locally-administered bit, and clears the multicast one.
+        let mac = MacAddr::new_local([0x01, 1, 2, 3, 4, 5]);
+        assert_eq!(mac.0, [0x02, 1, 2, 3, 4, 5]);
+        assert!(mac.validate().is_ok());
+        assert!(MacAddr::new_local([0xFF; 6]).validate().is_ok());
+    }
+
+    #[test]
+    fn mac_parse() {
+        let mac: MacAddr = "02:ab:CD:00:11:ff"
+            .parse()
+            .unwrap_or_else(|_| panic!("failed to parse"));
+        assert_eq!(mac.0, [0x02, 0xAB, 0xCD, 0x00, 0x11, 0xFF]);
+        assert!(
+            "02-ab-cd-00-11-ff"
+                .parse::<MacAddr>()
+                .is_ok_and(|m| m == mac)
+        );
+
+        for s in [
+            "02:ab:cd:00:11",
+            "02:ab:cd:00:11:ff:00",
+            "2:ab:cd:00:11:ff",
+            "02:ab:cd:00:11:fg",
+            "",
+        ] {
+            assert!(s.parse::<MacAddr>().is_err());
+        }
+    }
+
+    #[test]
+    fn channel_freq() {
+        for (ch, freq) in [(1, 2_412), (6, 2_437), (13, 2_472), (14, 2_484)] {
+            assert_eq!(channel_to_freq(ch), Some(freq));
+            assert!(matches!(channel_band(ch), Some(WifiBand::Ghz2)));
+        }
+        for (ch, freq) in [
+            (36, 5_180),
+            (64, 5_320),
+            (100, 5_500),
+            (144, 5_720),
+            (165, 5_825),
+            (177, 5_885),
+        ] {
+            assert_eq!(channel_to_freq(ch), Some(freq));
+            assert!(matches!(channel_band(ch), Some(WifiBand::Ghz5)));
+        }
+
+        for ch in [0, 15, 35, 37, 68, 96, 148, 181] {
+            assert_eq!(channel_to_freq(ch), None);
+        }
+        for freq in [2_407, 2_411, 2_477, 2_483, 5_000, 5_181, 5_340, 6_000] {
+            assert_eq!(freq_to_channel(freq), None);
+        }
+    }
+
+    #[test]
+    fn channel_freq_round_trip() {
+        let channels_5g = CHANNELS_5G
+            .iter()
+            .flat_map(|&(first, last)| (first..=last).step_by(4));
+
+        for ch in (1..=CHANNEL_MAX_2G).chain(channels_5g) {
+            let freq = channel_to_freq(ch).unwrap_or_else(|| panic!("no freq for {ch}"));
+            assert_eq!(freq_to_channel(freq), Some(ch));
+        }
+    }
+}